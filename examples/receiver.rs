@@ -26,7 +26,7 @@ fn main() {
                 1.0 / 60.0,
             ))),
             LogPlugin::default(),
-            SerialPortPlugin,
+            SerialPortPlugin::default(),
         ))
         .insert_resource(args)
         .add_systems(Startup, setup)
@@ -44,6 +44,8 @@ fn setup(cmd_args: Res<Args>, mut serial_res: ResMut<SerialResource>, rt: Res<Se
 fn receive(mut serial_res: ResMut<SerialResource>, mut serial_ev: EventReader<SerialData>) {
     for message in serial_ev.read() {
         info!("receive {:?}", message);
-        serial_res.send_message(&message.port, message.data.clone());
+        if let Err(err) = serial_res.send_message(&message.port, message.data.clone()) {
+            error!("{}", err);
+        }
     }
 }
@@ -31,7 +31,7 @@ fn main() {
                 1.0 / 60.0,
             ))),
             LogPlugin::default(),
-            SerialPortPlugin,
+            SerialPortPlugin::default(),
         ))
         .insert_resource(args)
         .add_systems(Startup, setup)
@@ -48,6 +48,7 @@ fn setup(cmd_args: Res<Args>, mut serial_res: ResMut<SerialResource>, rt: Res<Se
         parity: Parity::None,
         stop_bits: StopBits::One,
         timeout: Default::default(),
+        ..default()
     };
     serial_res
         .open_with_setting(rt.clone(), serial_setting)
@@ -61,5 +62,7 @@ fn receive(mut serial_ev: EventReader<SerialData>) {
 }
 
 fn send_test_data(mut serial_res: ResMut<SerialResource>, cmd_args: Res<Args>) {
-    serial_res.send_message(&cmd_args.port, Bytes::from(&b"123457"[..]))
+    if let Err(err) = serial_res.send_message(&cmd_args.port, Bytes::from(&b"123457"[..])) {
+        error!("{}", err);
+    }
 }
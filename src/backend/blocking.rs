@@ -0,0 +1,234 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use bevy::{prelude::*, tasks::IoTaskPool};
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use super::{apply_control, Control, PortIo, PortTasks};
+use crate::{rs485::DirectionControl, PushError, SerialError, SerialPortSetting};
+
+/// How the blocking port handles are driven
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum RuntimeConfig {
+    /// Two dedicated threads per port
+    #[default]
+    Threads,
+    /// Tasks on bevy's [`IoTaskPool`], each occupying one of its threads while the port is open.
+    ///
+    /// Needs bevy's `multi_threaded` feature, without it the tasks would block the main thread.
+    IoTaskPool,
+}
+
+/// Drives the serial port io loops, see [`RuntimeConfig`]
+#[derive(Resource, Debug, Clone)]
+pub struct SerialPortRuntime {
+    config: RuntimeConfig,
+}
+
+impl SerialPortRuntime {
+    pub fn new(config: RuntimeConfig) -> io::Result<Self> {
+        Ok(Self { config })
+    }
+
+    /// Nothing to shut down, the io loops end when their ports are closed
+    pub fn shutdown(self, _timeout: Duration) {}
+
+    fn spawn(&self, name: String, f: impl FnOnce() + Send + 'static) -> io::Result<TaskHandle> {
+        Ok(match self.config {
+            RuntimeConfig::Threads => {
+                TaskHandle::Thread(thread::Builder::new().name(name).spawn(f)?)
+            }
+            RuntimeConfig::IoTaskPool => {
+                let done = Arc::new(AtomicBool::new(false));
+                let task_done = Done(done.clone());
+                IoTaskPool::get()
+                    .spawn(async move {
+                        let _done = task_done;
+                        f();
+                    })
+                    .detach();
+                TaskHandle::Task(done)
+            }
+        })
+    }
+}
+
+/// A blocking io loop, which can't be aborted and ends once its queue is closed
+pub(crate) enum TaskHandle {
+    Thread(JoinHandle<()>),
+    /// set once the task has ended
+    Task(Arc<AtomicBool>),
+}
+
+impl TaskHandle {
+    pub(crate) fn is_finished(&self) -> bool {
+        match self {
+            TaskHandle::Thread(thread) => thread.is_finished(),
+            TaskHandle::Task(done) => done.load(Ordering::Acquire),
+        }
+    }
+
+    pub(crate) fn abort(&self) {}
+}
+
+/// Marks a task ended when dropped, also if it panicked
+struct Done(Arc<AtomicBool>);
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// How long a read waits for data before checking whether the port was closed
+const READ_POLL: Duration = Duration::from_millis(100);
+
+/// Open the port with `serialport` and start its reader and writer loops
+pub(crate) fn spawn_port(
+    task_pool: &SerialPortRuntime,
+    setting: &SerialPortSetting,
+    io: PortIo,
+) -> Result<PortTasks, SerialError> {
+    let PortIo {
+        port_name,
+        send_queue,
+        recv_queue,
+        mut inbound,
+        mut outbound,
+        ..
+    } = io;
+
+    let timeout = if setting.timeout.is_zero() {
+        READ_POLL
+    } else {
+        setting.timeout
+    };
+    let mut writer = serialport::new(&setting.port_name, setting.baud_rate)
+        .data_bits(setting.data_bits)
+        .flow_control(setting.flow_control)
+        .parity(setting.parity)
+        .stop_bits(setting.stop_bits)
+        .timeout(timeout)
+        .open()?;
+    let mut reader = writer.try_clone()?;
+    let control: Control = {
+        let port = Mutex::new(writer.try_clone()?);
+        Arc::new(move |control| apply_control(&mut **port.lock(), control))
+    };
+    let mut direction = match &setting.rs485 {
+        Some(rs485) => Some(DirectionControl::new(rs485, control.clone())?),
+        None => None,
+    };
+
+    let writer_port = port_name.clone();
+    let writer_queue = send_queue.clone();
+    let writer = task_pool.spawn(format!("{port_name} writer"), move || {
+        while let Some(message) = writer_queue.pop_blocking() {
+            let written = outbound
+                .encode(message)
+                .and_then(|chunks| write_message(&mut writer, direction.as_mut(), chunks));
+            match written {
+                Ok(len) => outbound.written(len),
+                Err(err) => {
+                    outbound.write_failed();
+                    error!("write to {} error {:?}", writer_port, err);
+                }
+            }
+        }
+        // wait for the port to drain what was written
+        if let Err(err) = writer.flush() {
+            error!("flush {} error {:?}", writer_port, err);
+        }
+    })?;
+
+    let reader = task_pool.spawn(format!("{port_name} reader"), move || {
+        let mut buf = [0; 4096];
+        let mut full = false;
+        let mut wait = timeout;
+        while !recv_queue.is_closed() {
+            // wake up for delayed chunks even while nothing is read
+            let next_wait = inbound.next_due().map_or(timeout, |due| {
+                due.saturating_duration_since(Instant::now())
+                    .clamp(Duration::from_millis(1), timeout)
+            });
+            if next_wait != wait {
+                wait = next_wait;
+                if let Err(err) = reader.set_timeout(wait) {
+                    error!("read from {} error {:?}", port_name, err);
+                }
+            }
+            let frames = match reader.read(&mut buf) {
+                Ok(0) => inbound.poll(),
+                Ok(n) => inbound.feed(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => inbound.poll(),
+                Err(err) => Err(err),
+            };
+            let frames = match frames {
+                Ok(frames) => frames,
+                Err(err) => {
+                    inbound.read_failed();
+                    error!("read from {} error {:?}", port_name, err);
+                    break;
+                }
+            };
+            for frame in frames {
+                match recv_queue.push(frame) {
+                    Ok(()) => full = false,
+                    // the drops are counted, only log when the queue fills up
+                    Err(PushError::Full) if !full => {
+                        full = true;
+                        error!("receive queue of {} is full, dropping frames", port_name);
+                    }
+                    Err(PushError::Full) => {}
+                    Err(PushError::Closed) => return,
+                }
+            }
+        }
+    });
+    // opening fails without a reader, let the writer end and release the port
+    let reader = reader.inspect_err(|_| send_queue.close())?;
+
+    Ok(PortTasks {
+        writer,
+        reader,
+        control,
+    })
+}
+
+/// Write the chunks of a message, switching an RS-485 transceiver to transmit around them
+fn write_message(
+    writer: &mut impl Write,
+    direction: Option<&mut DirectionControl>,
+    chunks: Vec<(Instant, Bytes)>,
+) -> io::Result<usize> {
+    let Some(direction) = direction else {
+        return write_chunks(writer, chunks);
+    };
+    direction.set_transmitting(true)?;
+    thread::sleep(direction.pre_delay);
+    let written = write_chunks(writer, chunks);
+    // the transmitter may only be disabled once the last byte left the port
+    let drained = writer.flush();
+    thread::sleep(direction.post_delay);
+    direction.set_transmitting(false)?;
+    drained.and(written)
+}
+
+/// Write each chunk once it is due
+fn write_chunks(writer: &mut impl Write, chunks: Vec<(Instant, Bytes)>) -> io::Result<usize> {
+    let mut len = 0;
+    for (due, bytes) in chunks {
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        writer.write_all(&bytes)?;
+        len += bytes.len();
+    }
+    Ok(len)
+}
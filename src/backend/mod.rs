@@ -0,0 +1,111 @@
+//! Drivers moving bytes between a port and its queues.
+//!
+//! With the default `tokio` feature ports are driven by `tokio-serial` on a tokio runtime,
+//! without it by blocking `serialport` handles on dedicated threads or bevy's `IoTaskPool`.
+
+#[cfg(not(feature = "tokio"))]
+mod blocking;
+#[cfg(feature = "tokio")]
+mod tokio_rt;
+
+#[cfg(not(feature = "tokio"))]
+pub(crate) use blocking::{spawn_port, TaskHandle};
+#[cfg(not(feature = "tokio"))]
+pub use blocking::{RuntimeConfig, SerialPortRuntime};
+#[cfg(feature = "tokio")]
+pub(crate) use tokio_rt::{spawn_port, TaskHandle};
+#[cfg(feature = "tokio")]
+pub use tokio_rt::{RuntimeConfig, SerialPortRuntime};
+
+use std::{
+    io,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serialport::SerialPort;
+
+use crate::{
+    clock::Clock,
+    pipeline::{Inbound, Outbound},
+    PortControl, RecvQueue, SendQueue, SerialError,
+};
+
+/// What the driver of a port shares with its [`SerialPortWrap`](crate::SerialPortWrap)
+pub(crate) struct PortIo {
+    pub port_name: String,
+    pub send_queue: SendQueue,
+    pub recv_queue: RecvQueue,
+    pub inbound: Inbound,
+    pub outbound: Outbound,
+    /// what the pipeline's timestamps and delays are measured in
+    pub clock: Clock,
+}
+
+/// What moves the bytes of a port
+pub(crate) enum PortDriver {
+    /// Reader and writer tasks of a real port
+    Tasks(PortTasks),
+    /// A port driven from the app itself
+    Virtual(Arc<dyn VirtualDriver>),
+}
+
+/// A port without io tasks, whose sends are handled when the app flushes it
+pub(crate) trait VirtualDriver: Send + Sync {
+    /// Called after the send queue was released by [`SerialPortWrap::flush`](crate::SerialPortWrap::flush)
+    fn flush(&self);
+
+    /// Called before the frames read from the port are taken, to deliver delayed data
+    fn poll(&self) {}
+
+    fn control(&self, _control: PortControl) {}
+}
+
+/// Changes the modem lines and settings of an open port
+pub(crate) type Control = Arc<dyn Fn(PortControl) -> io::Result<()> + Send + Sync>;
+
+/// The reader and writer of a port
+pub(crate) struct PortTasks {
+    pub writer: TaskHandle,
+    pub reader: TaskHandle,
+    pub control: Control,
+}
+
+impl PortTasks {
+    pub(crate) fn control(&self, control: PortControl) -> Result<(), SerialError> {
+        Ok((self.control)(control)?)
+    }
+
+    /// Wait for the writer to drain the closed send queue, `false` if it missed the deadline
+    pub(crate) fn join_writer(&self, deadline: Instant) -> bool {
+        wait_until(&self.writer, deadline)
+    }
+
+    /// Stop both tasks and wait for them to end, `false` if they missed the deadline
+    pub(crate) fn stop(&self, deadline: Instant) -> bool {
+        self.writer.abort();
+        self.reader.abort();
+        wait_until(&self.writer, deadline) & wait_until(&self.reader, deadline)
+    }
+}
+
+/// Apply a change of the modem lines or settings to a handle of the port
+pub(crate) fn apply_control(port: &mut dyn SerialPort, control: PortControl) -> io::Result<()> {
+    match control {
+        PortControl::Dtr(level) => port.write_data_terminal_ready(level)?,
+        PortControl::Rts(level) => port.write_request_to_send(level)?,
+        PortControl::BaudRate(baud_rate) => port.set_baud_rate(baud_rate)?,
+    }
+    Ok(())
+}
+
+fn wait_until(task: &TaskHandle, deadline: Instant) -> bool {
+    while !task.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
@@ -0,0 +1,349 @@
+use std::{
+    io,
+    ops::Deref,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    runtime::{Builder, Handle, Runtime},
+    sync::oneshot,
+};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use super::{apply_control, Control, PortIo, PortTasks};
+use crate::{rs485::DirectionControl, PushError, SerialError, SerialPortSetting};
+
+/// How [`SerialPortPlugin`](crate::SerialPortPlugin) gets the tokio runtime driving its ports
+#[derive(Debug, Clone)]
+pub enum RuntimeConfig {
+    /// A new multi-thread runtime owned by the plugin
+    MultiThread {
+        /// Number of worker threads, tokio defaults to one per core
+        worker_threads: Option<usize>,
+        /// Name of the worker threads
+        thread_name: Option<String>,
+    },
+    /// A new current-thread runtime, driven by a single background thread
+    CurrentThread {
+        /// Name of the background thread
+        thread_name: Option<String>,
+    },
+    /// A runtime the app already runs, e.g. for networking
+    Handle(Handle),
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self::MultiThread {
+            worker_threads: None,
+            thread_name: None,
+        }
+    }
+}
+
+/// The tokio runtime the serial port io tasks are spawned on
+#[derive(Resource, Clone)]
+pub struct SerialPortRuntime {
+    handle: Handle,
+    /// keeps an owned runtime alive, `None` when using a handle supplied by the app
+    runtime: Option<Arc<Runtime>>,
+    /// stops the thread driving a current-thread runtime once the last clone is dropped
+    stop: Option<Arc<oneshot::Sender<()>>>,
+    /// the thread driving a current-thread runtime
+    driver: Option<Arc<Mutex<Option<JoinHandle<()>>>>>,
+}
+
+impl SerialPortRuntime {
+    pub fn new(config: RuntimeConfig) -> io::Result<Self> {
+        match config {
+            RuntimeConfig::MultiThread {
+                worker_threads,
+                thread_name,
+            } => {
+                let mut builder = Builder::new_multi_thread();
+                builder.enable_all();
+                if let Some(worker_threads) = worker_threads {
+                    builder.worker_threads(worker_threads);
+                }
+                if let Some(thread_name) = thread_name {
+                    builder.thread_name(thread_name);
+                }
+                let runtime = builder.build()?;
+                Ok(Self {
+                    handle: runtime.handle().clone(),
+                    runtime: Some(Arc::new(runtime)),
+                    stop: None,
+                    driver: None,
+                })
+            }
+            RuntimeConfig::CurrentThread { thread_name } => {
+                let runtime = Arc::new(Builder::new_current_thread().enable_all().build()?);
+                let (stop, stopped) = oneshot::channel::<()>();
+                let driver = runtime.clone();
+                let driver = thread::Builder::new()
+                    .name(thread_name.unwrap_or_else(|| "serialport-runtime".to_string()))
+                    .spawn(move || {
+                        let _ = driver.block_on(stopped);
+                    })?;
+                Ok(Self {
+                    handle: runtime.handle().clone(),
+                    runtime: Some(runtime),
+                    stop: Some(Arc::new(stop)),
+                    driver: Some(Arc::new(Mutex::new(Some(driver)))),
+                })
+            }
+            RuntimeConfig::Handle(handle) => Ok(Self::from_handle(handle)),
+        }
+    }
+
+    /// Use a runtime owned by the app
+    pub fn from_handle(handle: Handle) -> Self {
+        Self {
+            handle,
+            runtime: None,
+            stop: None,
+            driver: None,
+        }
+    }
+
+    /// Shut down a runtime created by the plugin, waiting at most `timeout` for its tasks.
+    ///
+    /// Does nothing to a runtime supplied by the app. A runtime still shared with other clones
+    /// of this resource is left running until the last of them is dropped.
+    pub fn shutdown(self, timeout: Duration) {
+        let Self {
+            runtime,
+            stop,
+            driver,
+            ..
+        } = self;
+        if let Some(stop) = stop.and_then(|stop| Arc::try_unwrap(stop).ok()) {
+            let _ = stop.send(());
+            if let Some(driver) = driver.and_then(|driver| driver.lock().take()) {
+                let _ = driver.join();
+            }
+        }
+        if let Some(runtime) = runtime.and_then(|runtime| Arc::try_unwrap(runtime).ok()) {
+            runtime.shutdown_timeout(timeout);
+        }
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// The runtime created by the plugin, `None` when it was supplied by the app
+    pub fn runtime(&self) -> Option<&Arc<Runtime>> {
+        self.runtime.as_ref()
+    }
+}
+
+impl Deref for SerialPortRuntime {
+    type Target = Handle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+pub(crate) struct TaskHandle(tokio::task::JoinHandle<()>);
+
+impl TaskHandle {
+    pub(crate) fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+
+    pub(crate) fn abort(&self) {
+        self.0.abort();
+    }
+}
+
+/// Open the port with `tokio-serial` and spawn its reader and writer tasks
+pub(crate) fn spawn_port(
+    task_pool: &SerialPortRuntime,
+    setting: &SerialPortSetting,
+    io: PortIo,
+) -> Result<PortTasks, SerialError> {
+    let PortIo {
+        port_name,
+        send_queue,
+        recv_queue,
+        mut inbound,
+        mut outbound,
+        ..
+    } = io;
+
+    let port = {
+        // registering the port with the reactor needs the runtime context
+        let _guard = task_pool.enter();
+        SharedStream(Arc::new(Mutex::new(open(setting)?)))
+    };
+    let control: Control = {
+        let port = port.clone();
+        Arc::new(move |control| apply_control(&mut *port.0.lock(), control))
+    };
+    let mut direction = match &setting.rs485 {
+        Some(rs485) => Some(DirectionControl::new(rs485, control.clone())?),
+        None => None,
+    };
+    let mut reader = port.clone();
+    let mut writer = port;
+
+    let writer_port = port_name.clone();
+    let writer = task_pool.spawn(async move {
+        while let Some(message) = send_queue.pop().await {
+            let written = match outbound.encode(message) {
+                Ok(chunks) => write_message(&mut writer, direction.as_mut(), chunks).await,
+                Err(err) => Err(err),
+            };
+            match written {
+                Ok(len) => outbound.written(len),
+                Err(err) => {
+                    outbound.write_failed();
+                    error!("write to {} error {:?}", writer_port, err);
+                }
+            }
+        }
+        // wait for the port to drain what was written
+        if let Err(err) = writer.flush().await {
+            error!("flush {} error {:?}", writer_port, err);
+        }
+    });
+
+    let reader = task_pool.spawn(async move {
+        let mut buf = [0; 4096];
+        let mut full = false;
+        loop {
+            // wake up for delayed chunks even while nothing is read
+            let read = match inbound.next_due() {
+                Some(due) => tokio::time::timeout_at(due.into(), reader.read(&mut buf))
+                    .await
+                    .ok(),
+                None => Some(reader.read(&mut buf).await),
+            };
+            let frames = match read {
+                None => inbound.poll(),
+                Some(Ok(0)) => break,
+                Some(Ok(n)) => inbound.feed(&buf[..n]),
+                Some(Err(err)) => Err(err),
+            };
+            let frames = match frames {
+                Ok(frames) => frames,
+                Err(err) => {
+                    inbound.read_failed();
+                    error!("read from {} error {:?}", port_name, err);
+                    break;
+                }
+            };
+            for frame in frames {
+                match recv_queue.push_async(frame).await {
+                    Ok(()) => full = false,
+                    // the drops are counted, only log when the queue fills up
+                    Err(PushError::Full) if !full => {
+                        full = true;
+                        error!("receive queue of {} is full, dropping frames", port_name);
+                    }
+                    Err(PushError::Full) => {}
+                    Err(PushError::Closed) => return,
+                }
+            }
+        }
+    });
+
+    Ok(PortTasks {
+        writer: TaskHandle(writer),
+        reader: TaskHandle(reader),
+        control,
+    })
+}
+
+fn open(setting: &SerialPortSetting) -> Result<SerialStream, SerialError> {
+    Ok(tokio_serial::new(&setting.port_name, setting.baud_rate)
+        .data_bits(setting.data_bits)
+        .flow_control(setting.flow_control)
+        .parity(setting.parity)
+        .stop_bits(setting.stop_bits)
+        .open_native_async()?)
+}
+
+/// The port shared by its reader and writer tasks and its control handle.
+///
+/// Async ports can't be cloned on every platform, so instead of splitting it each user locks
+/// it for the duration of a poll or of a change to its modem lines and settings.
+#[derive(Clone)]
+struct SharedStream(Arc<Mutex<SerialStream>>);
+
+impl AsyncRead for SharedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock()).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SharedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock()).poll_shutdown(cx)
+    }
+}
+
+/// Write the chunks of a message, switching an RS-485 transceiver to transmit around them
+async fn write_message(
+    writer: &mut (impl AsyncWrite + Unpin),
+    direction: Option<&mut DirectionControl>,
+    chunks: Vec<(Instant, Bytes)>,
+) -> io::Result<usize> {
+    let Some(direction) = direction else {
+        return write_chunks(writer, chunks).await;
+    };
+    direction.set_transmitting(true)?;
+    if !direction.pre_delay.is_zero() {
+        tokio::time::sleep(direction.pre_delay).await;
+    }
+    let written = write_chunks(writer, chunks).await;
+    // the transmitter may only be disabled once the last byte left the port
+    let drained = writer.flush().await;
+    if !direction.post_delay.is_zero() {
+        tokio::time::sleep(direction.post_delay).await;
+    }
+    direction.set_transmitting(false)?;
+    drained.and(written)
+}
+
+/// Write each chunk once it is due
+async fn write_chunks(
+    writer: &mut (impl AsyncWrite + Unpin),
+    chunks: Vec<(Instant, Bytes)>,
+) -> io::Result<usize> {
+    let mut len = 0;
+    for (due, bytes) in chunks {
+        if due > Instant::now() {
+            tokio::time::sleep_until(due.into()).await;
+        }
+        writer.write_all(&bytes).await?;
+        len += bytes.len();
+    }
+    Ok(len)
+}
@@ -0,0 +1,220 @@
+//! Recording the traffic of ports, to reproduce field bugs with
+//! [`SerialResource::open_replay`](crate::SerialResource::open_replay).
+//!
+//! The native format is plain text, one chunk per line:
+//! `<unix seconds>.<micros>\t<rx|tx>\t<port>\t<hex bytes>`.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+/// Which way a chunk went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Read from the port
+    Rx,
+    /// Written to the port
+    Tx,
+}
+
+/// A chunk of raw bytes read from or written to a port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub port: String,
+    pub data: Bytes,
+}
+
+/// Receives every chunk of the ports it is set on in
+/// [`SerialPortSetting::captures`](crate::SerialPortSetting::captures).
+///
+/// Reads are recorded as they come from the port, before their faults are injected, writes
+/// once they are encoded, stamped with the time they are due to be written.
+pub trait CaptureSink: fmt::Debug + Send + Sync {
+    fn record(&self, record: &CaptureRecord);
+}
+
+/// The sinks of one port
+pub(crate) struct Captures {
+    port: String,
+    sinks: Vec<Arc<dyn CaptureSink>>,
+}
+
+impl Captures {
+    pub(crate) fn new(port: String, sinks: Vec<Arc<dyn CaptureSink>>) -> Self {
+        Self { port, sinks }
+    }
+
+    pub(crate) fn record(&self, direction: Direction, timestamp: SystemTime, data: &[u8]) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let record = CaptureRecord {
+            timestamp,
+            direction,
+            port: self.port.clone(),
+            data: Bytes::copy_from_slice(data),
+        };
+        for sink in &self.sinks {
+            sink.record(&record);
+        }
+    }
+}
+
+/// Writes records in the native capture format
+pub struct CaptureWriter {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl CaptureWriter {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl fmt::Debug for CaptureWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureWriter").finish_non_exhaustive()
+    }
+}
+
+impl CaptureSink for CaptureWriter {
+    fn record(&self, record: &CaptureRecord) {
+        let since_epoch = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let direction = match record.direction {
+            Direction::Rx => "rx",
+            Direction::Tx => "tx",
+        };
+        let mut line = format!(
+            "{}.{:06}\t{direction}\t{}\t",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros(),
+            record.port
+        );
+        for byte in &record.data {
+            line.push_str(&format!("{byte:02x}"));
+        }
+        let mut out = self.out.lock();
+        // flushed right away so a crash keeps everything up to it
+        if let Err(err) = writeln!(out, "{line}").and_then(|_| out.flush()) {
+            bevy::log::error!("writing capture error {:?}", err);
+        }
+    }
+}
+
+/// Read a capture written by [`CaptureWriter`]
+pub fn read_capture(path: impl AsRef<Path>) -> io::Result<Vec<CaptureRecord>> {
+    parse_capture(BufReader::new(File::open(path)?))
+}
+
+/// Parse a capture in the native format
+pub fn parse_capture(input: impl BufRead) -> io::Result<Vec<CaptureRecord>> {
+    let mut records = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record = parse_record(&line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid capture record on line {}", i + 1),
+            )
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn parse_record(line: &str) -> Option<CaptureRecord> {
+    let mut fields = line.split('\t');
+    let (secs, micros) = fields.next()?.split_once('.')?;
+    let timestamp = UNIX_EPOCH
+        + Duration::from_secs(secs.parse().ok()?)
+        + Duration::from_micros(micros.parse().ok()?);
+    let direction = match fields.next()? {
+        "rx" => Direction::Rx,
+        "tx" => Direction::Tx,
+        _ => return None,
+    };
+    let port = fields.next()?.to_string();
+    let hex = fields.next()?;
+    if fields.next().is_some() || hex.len() % 2 != 0 {
+        return None;
+    }
+    let data = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(CaptureRecord {
+        timestamp,
+        direction,
+        port,
+        data: data.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_and_parse() {
+        let out = Shared::default();
+        let writer = CaptureWriter::new(out.clone());
+        let records = vec![
+            CaptureRecord {
+                timestamp: UNIX_EPOCH + Duration::from_micros(1_700_000_000_000_001),
+                direction: Direction::Rx,
+                port: "/dev/ttyUSB0".to_string(),
+                data: Bytes::from_static(b"\x00hi\xff"),
+            },
+            CaptureRecord {
+                timestamp: UNIX_EPOCH + Duration::from_secs(1_700_000_001),
+                direction: Direction::Tx,
+                port: "COM 3".to_string(),
+                data: Bytes::new(),
+            },
+        ];
+        for record in &records {
+            writer.record(record);
+        }
+
+        let text = out.0.lock().clone();
+        assert!(text.starts_with(b"1700000000.000001\trx\t/dev/ttyUSB0\t006869ff\n"));
+        assert_eq!(parse_capture(&text[..]).unwrap(), records);
+        assert!(parse_capture(&b"1.0\trx\tCOM1\t0g\n"[..]).is_err());
+    }
+}
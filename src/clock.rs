@@ -0,0 +1,37 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// The time a port's timeouts and delays are measured in
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Clock {
+    /// the time of a mock port, which only moves with [`MockPort::advance`](crate::MockPort::advance),
+    /// `None` for the system clock
+    manual: Option<Arc<Mutex<Instant>>>,
+}
+
+impl Clock {
+    /// A clock standing still until it is advanced
+    pub(crate) fn manual() -> Self {
+        Self {
+            manual: Some(Arc::new(Mutex::new(Instant::now()))),
+        }
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        match &self.manual {
+            Some(now) => *now.lock(),
+            None => Instant::now(),
+        }
+    }
+
+    /// Move a manual clock forward, the system clock moves by itself
+    pub(crate) fn advance(&self, by: Duration) {
+        if let Some(now) = &self.manual {
+            *now.lock() += by;
+        }
+    }
+}
@@ -1,8 +1,63 @@
 use std::io;
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bevy::log::warn;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
+/// How the bytes of a port are split into frames, see
+/// [`SerialPortSetting::framing`](crate::SerialPortSetting::framing)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// Whatever was read at once is a frame, messages are written as they are
+    #[default]
+    Raw,
+    /// Frames are COBS encoded and end with a zero byte
+    Cobs,
+    /// Frames start with their length
+    LengthPrefixed(LengthPrefix),
+    /// Frames are lines ending with `\n` or `\r\n`, the line ending is not part of them
+    Lines,
+}
+
+/// Length field of [`Framing::LengthPrefixed`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthPrefix {
+    /// Size of the field
+    pub width: LengthWidth,
+    pub big_endian: bool,
+    /// Longer frames are a sign of lost sync, they are skipped
+    pub max_len: usize,
+}
+
+/// Size of the length field of [`Framing::LengthPrefixed`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl LengthWidth {
+    /// Size of the field in bytes
+    pub fn bytes(self) -> usize {
+        match self {
+            LengthWidth::U8 => 1,
+            LengthWidth::U16 => 2,
+            LengthWidth::U32 => 4,
+        }
+    }
+}
+
+impl Default for LengthPrefix {
+    fn default() -> Self {
+        Self {
+            width: LengthWidth::U16,
+            big_endian: false,
+            max_len: u16::MAX as usize,
+        }
+    }
+}
+
 pub struct RawCodec;
 
 impl Decoder for RawCodec {
@@ -30,3 +85,344 @@ impl Encoder<Bytes> for RawCodec {
         Ok(())
     }
 }
+
+/// Consistent Overhead Byte Stuffing, frames never contain a zero byte except as terminator.
+///
+/// Frames that don't decode are skipped, the next zero byte resynchronises.
+pub struct CobsCodec;
+
+impl Decoder for CobsCodec {
+    type Error = io::Error;
+    type Item = Bytes;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(end) = src.iter().position(|&b| b == 0) {
+            let frame = src.split_to(end + 1);
+            if end == 0 {
+                continue;
+            }
+            match cobs_decode(&frame[..end]) {
+                Some(data) => return Ok(Some(data.into())),
+                None => warn!("skipping invalid COBS frame of {} bytes", end),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<Bytes> for CobsCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(item.len() + item.len() / 254 + 2);
+        let mut code_at = dst.len();
+        dst.put_u8(0);
+        let mut code = 1u8;
+        for &byte in &item {
+            if byte != 0 {
+                dst.put_u8(byte);
+                code += 1;
+            }
+            if byte == 0 || code == 0xFF {
+                dst[code_at] = code;
+                code_at = dst.len();
+                dst.put_u8(0);
+                code = 1;
+            }
+        }
+        dst[code_at] = code;
+        dst.put_u8(0);
+        Ok(())
+    }
+}
+
+fn cobs_decode(src: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(src.len());
+    let mut i = 0;
+    while i < src.len() {
+        let code = src[i] as usize;
+        let block = src.get(i + 1..i + code)?;
+        out.extend_from_slice(block);
+        i += code;
+        if code < 0xFF && i < src.len() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+/// Frames preceded by their length, see [`LengthPrefix`]
+pub struct LengthPrefixedCodec {
+    prefix: LengthPrefix,
+}
+
+impl LengthPrefixedCodec {
+    pub fn new(prefix: LengthPrefix) -> Self {
+        Self { prefix }
+    }
+}
+
+impl Decoder for LengthPrefixedCodec {
+    type Error = io::Error;
+    type Item = Bytes;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let LengthPrefix {
+            width,
+            big_endian,
+            max_len,
+        } = self.prefix;
+        let width = width.bytes();
+        loop {
+            if src.len() < width {
+                return Ok(None);
+            }
+            let len = if big_endian {
+                (&src[..width]).get_uint(width)
+            } else {
+                (&src[..width]).get_uint_le(width)
+            } as usize;
+            if len > max_len {
+                warn!("skipping a byte of a frame claiming {} bytes", len);
+                src.advance(1);
+                continue;
+            }
+            if src.len() < width + len {
+                src.reserve(width + len - src.len());
+                return Ok(None);
+            }
+            src.advance(width);
+            return Ok(Some(src.split_to(len).freeze()));
+        }
+    }
+}
+
+impl Encoder<Bytes> for LengthPrefixedCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let LengthPrefix {
+            width,
+            big_endian,
+            max_len,
+        } = self.prefix;
+        let width = width.bytes();
+        if item.len() > max_len || (item.len() as u64) >> (width * 8) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "frame of {} bytes is too long for its length prefix",
+                    item.len()
+                ),
+            ));
+        }
+        if big_endian {
+            dst.put_uint(item.len() as u64, width);
+        } else {
+            dst.put_uint_le(item.len() as u64, width);
+        }
+        dst.put_slice(&item);
+        Ok(())
+    }
+}
+
+/// Lines longer than this are a sign of a binary stream or lost line endings
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Newline terminated lines, empty lines are skipped.
+///
+/// Lines longer than 64 KiB are dropped.
+pub struct LinesCodec {
+    /// the start of the buffer is the tail of an overlong line
+    discarding: bool,
+}
+
+impl LinesCodec {
+    pub fn new() -> Self {
+        Self { discarding: false }
+    }
+}
+
+impl Default for LinesCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for LinesCodec {
+    type Error = io::Error;
+    type Item = Bytes;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(end) = src.iter().position(|&b| b == b'\n') {
+            let mut line = src.split_to(end + 1);
+            line.truncate(end);
+            if line.last() == Some(&b'\r') {
+                line.truncate(end - 1);
+            }
+            if std::mem::take(&mut self.discarding) || line.is_empty() {
+                continue;
+            }
+            return Ok(Some(line.freeze()));
+        }
+        if src.len() > MAX_LINE_LEN {
+            warn!("dropping a line longer than {} bytes", MAX_LINE_LEN);
+            src.clear();
+            self.discarding = true;
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<Bytes> for LinesCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        if item.contains(&b'\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a line must not contain a line ending",
+            ));
+        }
+        dst.reserve(item.len() + 1);
+        dst.put_slice(&item);
+        dst.put_u8(b'\n');
+        Ok(())
+    }
+}
+
+/// The codec selected by a [`Framing`]
+pub(crate) enum FramingCodec {
+    Raw(RawCodec),
+    Cobs(CobsCodec),
+    LengthPrefixed(LengthPrefixedCodec),
+    Lines(LinesCodec),
+}
+
+impl From<Framing> for FramingCodec {
+    fn from(framing: Framing) -> Self {
+        match framing {
+            Framing::Raw => FramingCodec::Raw(RawCodec),
+            Framing::Cobs => FramingCodec::Cobs(CobsCodec),
+            Framing::LengthPrefixed(prefix) => {
+                FramingCodec::LengthPrefixed(LengthPrefixedCodec::new(prefix))
+            }
+            Framing::Lines => FramingCodec::Lines(LinesCodec::new()),
+        }
+    }
+}
+
+impl Decoder for FramingCodec {
+    type Error = io::Error;
+    type Item = Bytes;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self {
+            FramingCodec::Raw(codec) => codec.decode(src),
+            FramingCodec::Cobs(codec) => codec.decode(src),
+            FramingCodec::LengthPrefixed(codec) => codec.decode(src),
+            FramingCodec::Lines(codec) => codec.decode(src),
+        }
+    }
+}
+
+impl Encoder<Bytes> for FramingCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match self {
+            FramingCodec::Raw(codec) => codec.encode(item, dst),
+            FramingCodec::Cobs(codec) => codec.encode(item, dst),
+            FramingCodec::LengthPrefixed(codec) => codec.encode(item, dst),
+            FramingCodec::Lines(codec) => codec.encode(item, dst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(codec: &mut (impl Decoder<Item = Bytes> + Encoder<Bytes>), frames: &[&[u8]]) {
+        let mut buf = BytesMut::new();
+        for frame in frames {
+            assert!(codec
+                .encode(Bytes::copy_from_slice(frame), &mut buf)
+                .is_ok());
+        }
+        // fed one byte at a time, as a slow port would
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for &byte in buf.iter() {
+            src.put_u8(byte);
+            while let Ok(Some(frame)) = codec.decode(&mut src) {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn cobs() {
+        let long: Vec<u8> = (1..=255u8).chain(1..=20).collect();
+        round_trip(&mut CobsCodec, &[b"\x11\x00\x00\x22", b"", b"abc", &long]);
+
+        let mut buf = BytesMut::new();
+        CobsCodec
+            .encode(Bytes::from_static(b"\x11\x22\x00\x33"), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], b"\x03\x11\x22\x02\x33\x00");
+
+        let mut garbage = BytesMut::from(&b"\x05\x01\x00\x02\x01\x00"[..]);
+        assert_eq!(
+            CobsCodec.decode(&mut garbage).unwrap().unwrap(),
+            &b"\x01"[..]
+        );
+    }
+
+    #[test]
+    fn length_prefixed() {
+        let mut codec = LengthPrefixedCodec::new(LengthPrefix::default());
+        round_trip(&mut codec, &[b"hello", b"", b"\x00\x01"]);
+
+        let mut codec = LengthPrefixedCodec::new(LengthPrefix {
+            width: LengthWidth::U8,
+            big_endian: true,
+            max_len: 4,
+        });
+        let mut buf = BytesMut::new();
+        assert!(codec
+            .encode(Bytes::from_static(b"12345"), &mut buf)
+            .is_err());
+        let mut src = BytesMut::from(&b"\x09\x02ok"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), &b"ok"[..]);
+        let mut codec = LengthPrefixedCodec::new(LengthPrefix {
+            width: LengthWidth::U32,
+            big_endian: true,
+            max_len: 1 << 20,
+        });
+        round_trip(&mut codec, &[b"hello", &[0x55; 300]]);
+        let mut src = BytesMut::from(&b"\x00\x00\x00\x02ok"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), &b"ok"[..]);
+    }
+
+    #[test]
+    fn lines() {
+        let mut codec = LinesCodec::new();
+        round_trip(&mut codec, &[b"{\"a\": 1}", b"ok"]);
+
+        let mut src = BytesMut::from(&b"one\r\n\r\ntwo\nthr"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), &b"one"[..]);
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), &b"two"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(&src[..], b"thr");
+
+        let mut src = BytesMut::from(&vec![b'x'; MAX_LINE_LEN + 1][..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.put_slice(b"xx\nnext\n");
+        assert_eq!(codec.decode(&mut src).unwrap().unwrap(), &b"next"[..]);
+
+        let mut buf = BytesMut::new();
+        assert!(codec.encode(Bytes::from_static(b"a\nb"), &mut buf).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::{collections::HashMap, time::Instant};
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticMeasurement, DiagnosticPath, DiagnosticsStore},
+    prelude::*,
+};
+
+use crate::{SerialPortStats, SerialResource};
+
+/// Publishes the traffic statistics of every open port to the [`DiagnosticsStore`].
+///
+/// Diagnostics are named `serial/<port>/<metric>`, where the port name is split on `/` like
+/// any other diagnostic path, e.g. `serial/dev/ttyUSB0/rx_bytes`. Byte and frame counts are
+/// published as rates per second.
+///
+/// # See also
+///
+/// [`LogDiagnosticsPlugin`](bevy::diagnostic::LogDiagnosticsPlugin) to output diagnostics to
+/// the console.
+#[derive(Default)]
+pub struct SerialDiagnosticsPlugin;
+
+impl Plugin for SerialDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DiagnosticsStore>()
+            .add_systems(Update, Self::diagnostic_system);
+    }
+}
+
+impl SerialDiagnosticsPlugin {
+    pub const RX_BYTES: &'static str = "rx_bytes";
+    pub const TX_BYTES: &'static str = "tx_bytes";
+    pub const RX_FRAMES: &'static str = "rx_frames";
+    pub const TX_FRAMES: &'static str = "tx_frames";
+    pub const READ_ERRORS: &'static str = "read_errors";
+    pub const WRITE_ERRORS: &'static str = "write_errors";
+    pub const DROPPED_RX: &'static str = "dropped_rx";
+    pub const DROPPED_TX: &'static str = "dropped_tx";
+    pub const SEND_QUEUE: &'static str = "send_queue";
+    pub const RECV_QUEUE: &'static str = "recv_queue";
+    pub const IDLE: &'static str = "idle";
+
+    const METRICS: [(&'static str, &'static str); 11] = [
+        (Self::RX_BYTES, "B/s"),
+        (Self::TX_BYTES, "B/s"),
+        (Self::RX_FRAMES, "/s"),
+        (Self::TX_FRAMES, "/s"),
+        (Self::READ_ERRORS, ""),
+        (Self::WRITE_ERRORS, ""),
+        (Self::DROPPED_RX, ""),
+        (Self::DROPPED_TX, ""),
+        (Self::SEND_QUEUE, ""),
+        (Self::RECV_QUEUE, ""),
+        (Self::IDLE, "s"),
+    ];
+
+    /// Diagnostic path of `metric` for `port`
+    pub fn path(port: &str, metric: &str) -> DiagnosticPath {
+        let port = port.split('/').filter(|c| !c.is_empty());
+        DiagnosticPath::from_components(std::iter::once("serial").chain(port).chain([metric]))
+    }
+
+    pub fn diagnostic_system(
+        serial_res: Res<SerialResource>,
+        mut store: ResMut<DiagnosticsStore>,
+        mut last: Local<HashMap<String, (Instant, SerialPortStats)>>,
+    ) {
+        for (port, wrap) in serial_res.ports.iter() {
+            let now = wrap.now();
+            let stats = wrap.stats();
+            let Some((then, prev)) = last.insert(port.clone(), (now, stats)) else {
+                for (metric, suffix) in Self::METRICS {
+                    store.add(Diagnostic::new(Self::path(port, metric)).with_suffix(suffix));
+                }
+                continue;
+            };
+            let secs = now.duration_since(then).as_secs_f64();
+            if secs == 0.0 {
+                continue;
+            }
+            let rate = |current: u64, previous: u64| current.saturating_sub(previous) as f64 / secs;
+
+            let values = [
+                (Self::RX_BYTES, rate(stats.bytes_read, prev.bytes_read)),
+                (
+                    Self::TX_BYTES,
+                    rate(stats.bytes_written, prev.bytes_written),
+                ),
+                (Self::RX_FRAMES, rate(stats.frames_read, prev.frames_read)),
+                (
+                    Self::TX_FRAMES,
+                    rate(stats.frames_written, prev.frames_written),
+                ),
+                (Self::READ_ERRORS, stats.read_errors as f64),
+                (Self::WRITE_ERRORS, stats.write_errors as f64),
+                (Self::DROPPED_RX, stats.dropped_received as f64),
+                (Self::DROPPED_TX, stats.dropped_sent as f64),
+                (Self::SEND_QUEUE, stats.send_queue_len as f64),
+                (Self::RECV_QUEUE, stats.recv_queue_len as f64),
+                (
+                    Self::IDLE,
+                    stats
+                        .last_activity
+                        .map_or(0.0, |t| now.duration_since(t).as_secs_f64()),
+                ),
+            ];
+            for (metric, value) in values {
+                if let Some(diagnostic) = store
+                    .get_mut(&Self::path(port, metric))
+                    .filter(|d| d.is_enabled)
+                {
+                    diagnostic.add_measurement(DiagnosticMeasurement { time: now, value });
+                }
+            }
+        }
+        last.retain(|port, _| serial_res.ports.contains_key(port));
+    }
+}
@@ -4,6 +4,41 @@ use thiserror::Error;
 pub enum SerialError {
     #[error("serial port error")]
     SerialPortError(#[from] serialport::Error),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "tokio")]
     #[error("tokio join error")]
     JoinError(#[from] tokio::task::JoinError),
+    #[error("serial port {0} is not open")]
+    PortNotFound(String),
+    #[error("serial port {0} is closed")]
+    PortClosed(String),
+    #[error("send queue of serial port {0} is full")]
+    QueueFull(String),
+    #[error("serial port {0} did not finish writing before closing")]
+    CloseTimeout(String),
+    #[error("no reply from serial port {0} in time")]
+    TransactionTimeout(String),
+    #[error("serial port {0} has no message format")]
+    NoMessageFormat(String),
+    #[error("message for serial port {0} could not be serialized: {1}")]
+    Serialize(String, String),
+    #[error("serial port {0} can't open a channel named {1:?}")]
+    InvalidChannel(String, String),
+    #[error("serial port {0} is busy with a file transfer or bootloader")]
+    PortBusy(String),
+    #[error("file transfer on serial port {0} failed: {1}")]
+    TransferFailed(String, String),
+    #[error("file transfer on serial port {0} was cancelled")]
+    TransferCancelled(String),
+    #[error("bootloader on serial port {0} failed: {1}")]
+    BootloaderFailed(String, String),
+    #[error("bootloader session on serial port {0} was cancelled")]
+    BootloaderCancelled(String),
+    #[error("serial port {0} can't replay the capture: {1}")]
+    InvalidReplay(String, String),
+    #[error("serial port {0} has no Firmata board attached")]
+    NoFirmataBoard(String),
+    #[error("Firmata message for serial port {0} is invalid: {1}")]
+    InvalidFirmata(String, String),
 }
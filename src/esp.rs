@@ -0,0 +1,1066 @@
+//! The Espressif ROM serial bootloader of ESP32 and ESP8266 chips, as spoken by esptool.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use bytes::Bytes;
+
+use crate::{
+    transfer::{BootloaderProgress, Sessions, Transfer, TransferIo},
+    PortControl, SerialError, SerialResource,
+};
+
+/// SLIP frame delimiter and escapes
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+const FLASH_BEGIN: u8 = 0x02;
+const FLASH_DATA: u8 = 0x03;
+const FLASH_END: u8 = 0x04;
+const SYNC: u8 = 0x08;
+const READ_REG: u8 = 0x0A;
+const SPI_SET_PARAMS: u8 = 0x0B;
+const SPI_ATTACH: u8 = 0x0D;
+const CHANGE_BAUDRATE: u8 = 0x0F;
+const SPI_FLASH_MD5: u8 = 0x13;
+
+/// Holds a value telling the chips apart
+const CHIP_DETECT_MAGIC_REG: u32 = 0x4000_1000;
+const ESP8266_MAGIC: u32 = 0xFFF0_C101;
+const ESP32_MAGIC: u32 = 0x00F0_1D83;
+
+/// Bytes written by one flash data command
+const BLOCK: usize = 0x400;
+const SECTOR: u32 = 0x1000;
+/// Seed of the checksum over flash data
+const CHECKSUM_SEED: u8 = 0xEF;
+const SYNC_ATTEMPTS: u32 = 7;
+/// The ROM answers a sync at once, or missed it while measuring the baud rate
+const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
+const ERASE_TIMEOUT_PER_MB: Duration = Duration::from_secs(30);
+const MD5_TIMEOUT_PER_MB: Duration = Duration::from_secs(8);
+/// Time for the ROM to switch to the new baud rate
+const BAUD_SETTLE: Duration = Duration::from_millis(50);
+
+/// A command run by [`EspBootloader::run`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EspCommand {
+    ReadRegister(u32),
+    /// Erase what `data` covers and write it, padding the last block with `0xFF`
+    WriteFlash {
+        address: u32,
+        data: Bytes,
+    },
+    /// Have the ROM hash flash and compare it with the MD5 of `data`. ESP8266 ROMs lack it
+    VerifyFlash {
+        address: u32,
+        data: Bytes,
+    },
+    /// Switch the ROM and the port to another baud rate
+    ChangeBaudRate(u32),
+    /// Finish flashing, and with `reboot` start the application, nothing can follow then
+    FlashEnd {
+        reboot: bool,
+    },
+}
+
+/// What a command returned, one per command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EspReply {
+    Register(u32),
+    /// Write, verify, baud change or flash end succeeded
+    Done,
+}
+
+/// How to get into the bootloader and back out, and how long to wait for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EspOptions {
+    /// Modem line changes, each followed by a wait, starting the bootloader
+    pub enter: Vec<(PortControl, Duration)>,
+    /// Modem line changes, each followed by a wait, starting the application once the commands
+    /// are done, failed or were cancelled, unless they ended with [`EspCommand::FlashEnd`]
+    /// rebooting
+    pub exit: Vec<(PortControl, Duration)>,
+    /// Longest wait for a reply. Erasing and hashing wait longer for large regions
+    pub timeout: Duration,
+    /// Size of the flash chip of an ESP32, which its ROM needs to know before writing
+    pub flash_size: u32,
+}
+
+impl Default for EspOptions {
+    fn default() -> Self {
+        Self {
+            enter: Vec::new(),
+            exit: Vec::new(),
+            timeout: Duration::from_secs(3),
+            flash_size: 4 << 20,
+        }
+    }
+}
+
+impl EspOptions {
+    /// Reset into the bootloader with `reset` wired to EN and `boot` to GPIO0, asserting a line
+    /// holding the chip in reset or pulling GPIO0 low. Development boards wire them as
+    /// `with_reset_lines(PortControl::Rts, PortControl::Dtr)`. Reset again once done
+    pub fn with_reset_lines(
+        mut self,
+        reset: fn(bool) -> PortControl,
+        boot: fn(bool) -> PortControl,
+    ) -> Self {
+        let pulse = Duration::from_millis(100);
+        self.enter = vec![
+            (boot(false), Duration::ZERO),
+            (reset(true), pulse),
+            (boot(true), Duration::ZERO),
+            (reset(false), Duration::from_millis(50)),
+            (boot(false), Duration::ZERO),
+        ];
+        self.exit = vec![(reset(true), pulse), (reset(false), Duration::ZERO)];
+        self
+    }
+}
+
+/// Outcome of a session started with [`EspBootloader::run`]
+#[derive(Debug, Event)]
+pub struct EspResult {
+    pub port: String,
+    /// The ID the session was started with
+    pub id: u64,
+    /// A reply for each command
+    pub result: Result<Vec<EspReply>, SerialError>,
+}
+
+/// Sessions with ESP32 and ESP8266 ROM bootloaders, one per port. Each ends with an
+/// [`EspResult`] event and reports [`BootloaderProgress`] on the way.
+///
+/// The ROM detects the baud rate while syncing, 115200 is the usual one to start at.
+#[derive(Resource)]
+pub struct EspBootloader {
+    sessions: Sessions<Vec<EspReply>>,
+}
+
+impl Default for EspBootloader {
+    fn default() -> Self {
+        Self {
+            sessions: Sessions::new(
+                SerialError::BootloaderFailed,
+                SerialError::BootloaderCancelled,
+            ),
+        }
+    }
+}
+
+impl EspBootloader {
+    /// Start the bootloader of the chip on `port`, run `commands` and start the application,
+    /// once [`SerialSet::Receive`](crate::SerialSet) next runs
+    pub fn run(
+        &mut self,
+        port: impl ToString,
+        id: u64,
+        options: EspOptions,
+        commands: Vec<EspCommand>,
+    ) {
+        self.sessions.start(port.to_string(), id, move || {
+            Ok(Box::new(EspSession::new(options, commands)))
+        });
+    }
+
+    /// Abort the session on `port`, ending it with [`SerialError::BootloaderCancelled`] once
+    /// the exit sequence ran. `false` if there was none
+    pub fn cancel(&mut self, port: &str) -> bool {
+        self.sessions.cancel(port)
+    }
+
+    /// Whether a session runs or is about to start on `port`
+    pub fn is_active(&self, port: &str) -> bool {
+        self.sessions.is_active(port)
+    }
+}
+
+pub(crate) fn drive_esp(
+    mut bootloader: ResMut<EspBootloader>,
+    mut serial_res: ResMut<SerialResource>,
+    mut progress_ev: EventWriter<BootloaderProgress>,
+    mut result_ev: EventWriter<EspResult>,
+) {
+    bootloader.sessions.drive(
+        &mut serial_res,
+        |port, id, (stage, done, total)| {
+            progress_ev.send(BootloaderProgress {
+                port: port.to_string(),
+                id,
+                stage,
+                done,
+                total,
+            });
+        },
+        |ended| {
+            result_ev.send(EspResult {
+                port: ended.port,
+                id: ended.id,
+                result: ended.result,
+            });
+        },
+    );
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chip {
+    Esp8266,
+    Esp32,
+}
+
+/// What to do with a reply
+enum Then {
+    Nothing,
+    Detect,
+    Register,
+    Verify {
+        address: u32,
+        expected: String,
+        len: usize,
+    },
+    /// report progress, and the command as done once `done` reaches `total`
+    Progress {
+        stage: &'static str,
+        done: usize,
+        total: usize,
+    },
+    Reply(EspReply),
+}
+
+/// A command packet and the reply expected
+struct Exchange {
+    what: &'static str,
+    op: u8,
+    send: Vec<u8>,
+    /// bytes in the reply before the status
+    payload: usize,
+    timeout: Duration,
+    then: Then,
+}
+
+enum Step {
+    Control(PortControl),
+    Wait(Duration),
+    Exchange(Exchange),
+}
+
+/// A SLIP frame around `packet`
+fn slip(packet: &[u8]) -> Vec<u8> {
+    let mut frame = vec![END];
+    for &byte in packet {
+        match byte {
+            END => frame.extend_from_slice(&[ESC, ESC_END]),
+            ESC => frame.extend_from_slice(&[ESC, ESC_ESC]),
+            _ => frame.push(byte),
+        }
+    }
+    frame.push(END);
+    frame
+}
+
+/// A framed command packet
+fn packet(op: u8, data: &[u8], checksum: u32) -> Vec<u8> {
+    let mut packet = vec![0x00, op];
+    packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+    packet.extend_from_slice(&checksum.to_le_bytes());
+    packet.extend_from_slice(data);
+    slip(&packet)
+}
+
+fn words(words: &[u32]) -> Vec<u8> {
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+fn scaled(per_mb: Duration, size: usize, min: Duration) -> Duration {
+    min.max(per_mb.mul_f64(size as f64 / (1 << 20) as f64))
+}
+
+/// Bytes the ESP8266 ROM is told to erase, which it erases about twice of
+fn esp8266_erase_size(offset: u32, size: u32) -> u32 {
+    const SECTORS_PER_BLOCK: u32 = 16;
+    let sectors = size.div_ceil(SECTOR);
+    let head = (SECTORS_PER_BLOCK - offset / SECTOR % SECTORS_PER_BLOCK).min(sectors);
+    if sectors < 2 * head {
+        sectors.div_ceil(2) * SECTOR
+    } else {
+        (sectors - head) * SECTOR
+    }
+}
+
+fn describe(error: u8) -> String {
+    let text = match error {
+        0x05 => "invalid message",
+        0x06 => "failed to act on the message",
+        0x07 => "bad checksum",
+        0x08 => "flash write error",
+        0x09 => "flash read error",
+        0x0A => "flash read length error",
+        0x0B => "deflate error",
+        _ => return format!("error {error:#04x}"),
+    };
+    text.to_string()
+}
+
+pub(crate) struct EspSession {
+    options: EspOptions,
+    commands: VecDeque<EspCommand>,
+    steps: VecDeque<Step>,
+    /// the exchange awaiting its reply and its deadline
+    current: Option<(Exchange, Instant)>,
+    /// the frame being received, `None` between frames
+    frame: Option<Vec<u8>>,
+    escaped: bool,
+    waiting: Option<Instant>,
+    sync_attempts: u32,
+    chip: Option<Chip>,
+    replies: Vec<EspReply>,
+    /// the exit sequence was queued, or the application started with a flash end
+    exited: bool,
+    /// why the session ends once the exit sequence ran
+    failure: Option<String>,
+}
+
+impl EspSession {
+    pub(crate) fn new(options: EspOptions, commands: Vec<EspCommand>) -> Self {
+        let mut session = Self {
+            options,
+            commands: commands.into(),
+            steps: VecDeque::new(),
+            current: None,
+            frame: None,
+            escaped: false,
+            waiting: None,
+            sync_attempts: 0,
+            chip: None,
+            replies: Vec::new(),
+            exited: false,
+            failure: None,
+        };
+        session.lines(session.options.enter.clone());
+        let mut sync = vec![0x07, 0x07, 0x12, 0x20];
+        sync.extend_from_slice(&[0x55; 32]);
+        session.steps.push_back(Step::Exchange(Exchange {
+            what: "sync",
+            op: SYNC,
+            send: packet(SYNC, &sync, 0),
+            payload: 0,
+            timeout: SYNC_TIMEOUT,
+            then: Then::Nothing,
+        }));
+        session.command(
+            "read register",
+            READ_REG,
+            &words(&[CHIP_DETECT_MAGIC_REG]),
+            Then::Detect,
+        );
+        session
+    }
+
+    fn lines(&mut self, sequence: Vec<(PortControl, Duration)>) {
+        for (control, wait) in sequence {
+            self.steps.push_back(Step::Control(control));
+            self.steps.push_back(Step::Wait(wait));
+        }
+    }
+
+    fn command(&mut self, what: &'static str, op: u8, data: &[u8], then: Then) {
+        self.steps.push_back(Step::Exchange(Exchange {
+            what,
+            op,
+            send: packet(op, data, 0),
+            payload: 0,
+            timeout: self.options.timeout,
+            then,
+        }));
+    }
+
+    /// Queue the exchanges of a command
+    fn expand(&mut self, command: EspCommand) {
+        match command {
+            EspCommand::ReadRegister(address) => self.command(
+                "read register",
+                READ_REG,
+                &words(&[address]),
+                Then::Register,
+            ),
+            EspCommand::WriteFlash { address, data } => self.write(address, data),
+            EspCommand::VerifyFlash { address, data } => {
+                self.steps.push_back(Step::Exchange(Exchange {
+                    what: "MD5",
+                    op: SPI_FLASH_MD5,
+                    send: packet(
+                        SPI_FLASH_MD5,
+                        &words(&[address, data.len() as u32, 0, 0]),
+                        0,
+                    ),
+                    // as hex digits
+                    payload: 32,
+                    timeout: scaled(MD5_TIMEOUT_PER_MB, data.len(), self.options.timeout),
+                    then: Then::Verify {
+                        address,
+                        expected: format!("{:x}", md5::compute(&data)),
+                        len: data.len(),
+                    },
+                }));
+            }
+            EspCommand::ChangeBaudRate(baud) => {
+                // 0 for the rate the ROM runs at
+                self.command(
+                    "baud rate change",
+                    CHANGE_BAUDRATE,
+                    &words(&[baud, 0]),
+                    Then::Reply(EspReply::Done),
+                );
+                self.lines(vec![(PortControl::BaudRate(baud), BAUD_SETTLE)]);
+            }
+            EspCommand::FlashEnd { reboot } => {
+                // the ROM stays in the bootloader with 1
+                self.command(
+                    "flash end",
+                    FLASH_END,
+                    &words(&[!reboot as u32]),
+                    Then::Reply(EspReply::Done),
+                );
+                self.exited |= reboot;
+            }
+        }
+    }
+
+    fn write(&mut self, address: u32, data: Bytes) {
+        if data.is_empty() {
+            self.replies.push(EspReply::Done);
+            return;
+        }
+        let size = data.len() as u32;
+        let erase_size = match self.chip {
+            Some(Chip::Esp8266) => esp8266_erase_size(address, size),
+            _ => size,
+        };
+        let blocks = data.len().div_ceil(BLOCK) as u32;
+        self.steps.push_back(Step::Exchange(Exchange {
+            what: "flash begin",
+            op: FLASH_BEGIN,
+            send: packet(
+                FLASH_BEGIN,
+                &words(&[erase_size, blocks, BLOCK as u32, address]),
+                0,
+            ),
+            payload: 0,
+            timeout: scaled(ERASE_TIMEOUT_PER_MB, data.len(), self.options.timeout),
+            then: Then::Nothing,
+        }));
+        for (seq, chunk) in data.chunks(BLOCK).enumerate() {
+            let mut block = chunk.to_vec();
+            block.resize(BLOCK, 0xFF);
+            let checksum = block.iter().fold(CHECKSUM_SEED, |sum, &b| sum ^ b);
+            let mut body = words(&[BLOCK as u32, seq as u32, 0, 0]);
+            body.extend_from_slice(&block);
+            self.steps.push_back(Step::Exchange(Exchange {
+                what: "flash data",
+                op: FLASH_DATA,
+                send: packet(FLASH_DATA, &body, checksum as u32),
+                payload: 0,
+                timeout: self.options.timeout,
+                then: Then::Progress {
+                    stage: "write",
+                    done: seq * BLOCK + chunk.len(),
+                    total: data.len(),
+                },
+            }));
+        }
+    }
+
+    /// Run steps until one needs a reply or time to pass
+    fn advance(&mut self, now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        while self.current.is_none() && io.outcome.is_none() {
+            if let Some(until) = self.waiting {
+                if now < until {
+                    return;
+                }
+                self.waiting = None;
+            }
+            let Some(step) = self.steps.pop_front() else {
+                if let Some(command) = self.commands.pop_front() {
+                    self.expand(command);
+                } else if !self.exited {
+                    self.exited = true;
+                    self.lines(self.options.exit.clone());
+                } else if let Some(reason) = self.failure.take() {
+                    io.fail(reason);
+                } else {
+                    io.finish(std::mem::take(&mut self.replies));
+                }
+                continue;
+            };
+            match step {
+                Step::Control(control) => io.control.push(control),
+                Step::Wait(wait) => {
+                    if !wait.is_zero() {
+                        self.waiting = Some(now + wait);
+                    }
+                }
+                Step::Exchange(exchange) => {
+                    io.send(exchange.send.clone());
+                    let deadline = now + exchange.timeout;
+                    self.current = Some((exchange, deadline));
+                }
+            }
+        }
+    }
+
+    /// Handle a whole frame from the ROM
+    fn reply(&mut self, frame: &[u8], now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        let Some((exchange, _)) = &self.current else {
+            return;
+        };
+        // the ROM answers a sync several times, and frames may start in its boot log
+        if frame.len() < 8 || frame[0] != 0x01 || frame[1] != exchange.op {
+            return;
+        }
+        let value = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+        let data = &frame[8..];
+        // errors come without the payload
+        let at = if data.len() > exchange.payload {
+            exchange.payload
+        } else {
+            0
+        };
+        let Some(&status) = data.get(at) else {
+            io.fail(format!("short reply to {}", exchange.what));
+            return;
+        };
+        if status != 0 {
+            let error = data.get(at + 1).copied().unwrap_or(0);
+            io.fail(format!(
+                "the bootloader refused {}: {}",
+                exchange.what,
+                describe(error)
+            ));
+            return;
+        }
+        if at < exchange.payload {
+            io.fail(format!("short reply to {}", exchange.what));
+            return;
+        }
+        let (exchange, _) = self.current.take().expect("checked above");
+        self.complete(exchange.then, value, &data[..exchange.payload], io);
+        self.advance(now, io);
+    }
+
+    fn complete(
+        &mut self,
+        then: Then,
+        value: u32,
+        payload: &[u8],
+        io: &mut TransferIo<Vec<EspReply>>,
+    ) {
+        match then {
+            Then::Nothing => {}
+            Then::Detect => match value {
+                ESP8266_MAGIC => self.chip = Some(Chip::Esp8266),
+                ESP32_MAGIC => {
+                    self.chip = Some(Chip::Esp32);
+                    // the ESP32 ROM reaches flash only once told where and how large it is
+                    self.command("SPI attach", SPI_ATTACH, &[0; 8], Then::Nothing);
+                    let params = [0, self.options.flash_size, 64 << 10, SECTOR, 256, 0xFFFF];
+                    self.command(
+                        "SPI parameters",
+                        SPI_SET_PARAMS,
+                        &words(&params),
+                        Then::Nothing,
+                    );
+                }
+                _ => io.fail(format!("unsupported chip, magic value {value:#010x}")),
+            },
+            Then::Register => self.replies.push(EspReply::Register(value)),
+            Then::Verify {
+                address,
+                expected,
+                len,
+            } => {
+                let digest = String::from_utf8_lossy(payload);
+                if !digest.eq_ignore_ascii_case(&expected) {
+                    io.fail(format!(
+                        "verify failed at {address:#010x}: MD5 {digest}, expected {expected}"
+                    ));
+                    return;
+                }
+                self.progress("verify", len, len, io);
+            }
+            Then::Progress { stage, done, total } => self.progress(stage, done, total, io),
+            Then::Reply(reply) => self.replies.push(reply),
+        }
+    }
+
+    fn progress(
+        &mut self,
+        stage: &str,
+        done: usize,
+        total: usize,
+        io: &mut TransferIo<Vec<EspReply>>,
+    ) {
+        io.progress(stage, done as u64, Some(total as u64));
+        if done == total {
+            self.replies.push(EspReply::Done);
+        }
+    }
+}
+
+impl EspSession {
+    /// Feed what was read to the exchange awaiting its reply
+    fn read(&mut self, data: &[u8], now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        for &byte in data {
+            let Some(frame) = &mut self.frame else {
+                if byte == END {
+                    self.frame = Some(Vec::new());
+                    self.escaped = false;
+                }
+                continue;
+            };
+            match byte {
+                // back to back delimiters, or an end taken for a start
+                END if frame.is_empty() => {}
+                END => {
+                    let frame = self.frame.take().expect("matched above");
+                    self.reply(&frame, now, io);
+                    if io.outcome.is_some() {
+                        return;
+                    }
+                }
+                _ if self.escaped => {
+                    self.escaped = false;
+                    frame.push(match byte {
+                        ESC_END => END,
+                        ESC_ESC => ESC,
+                        _ => byte,
+                    });
+                }
+                ESC => self.escaped = true,
+                _ => frame.push(byte),
+            }
+        }
+    }
+
+    /// Retry the sync or give up on an exchange past its deadline
+    fn timeout(&mut self, now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        let Some((exchange, deadline)) = &mut self.current else {
+            self.advance(now, io);
+            return;
+        };
+        if now < *deadline {
+            return;
+        }
+        if exchange.op == SYNC && self.sync_attempts < SYNC_ATTEMPTS {
+            self.sync_attempts += 1;
+            *deadline = now + exchange.timeout;
+            io.send(exchange.send.clone());
+            return;
+        }
+        io.fail(format!("no reply to {}", exchange.what));
+    }
+
+    /// Drop what is left to do and run the exit sequence, before failing with `reason`
+    fn abort(&mut self, reason: String, now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        self.commands.clear();
+        self.current = None;
+        if !self.exited {
+            self.exited = true;
+            self.steps.clear();
+            self.waiting = None;
+            self.lines(self.options.exit.clone());
+        }
+        self.failure = Some(reason);
+        self.advance(now, io);
+    }
+
+    /// Take the device out of the bootloader before reporting a failure
+    fn exit_on_failure(&mut self, now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        if self.exited {
+            return;
+        }
+        if let Some(Err(reason)) = &mut io.outcome {
+            let reason = std::mem::take(reason);
+            io.outcome = None;
+            self.abort(reason, now, io);
+        }
+    }
+}
+
+impl Transfer<Vec<EspReply>> for EspSession {
+    fn start(&mut self, now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        self.advance(now, io);
+        self.exit_on_failure(now, io);
+    }
+
+    fn receive(&mut self, data: &[u8], now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        self.read(data, now, io);
+        self.exit_on_failure(now, io);
+    }
+
+    fn update(&mut self, now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        self.timeout(now, io);
+        self.exit_on_failure(now, io);
+    }
+
+    /// The bootloader has nothing to abort, it waits for the next command
+    fn cancel(&mut self, now: Instant, io: &mut TransferIo<Vec<EspReply>>) {
+        self.abort("cancelled".to_string(), now, io);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EFUSE: u32 = 0x3FF5_A004;
+
+    /// A ROM bootloader with 128 KiB of flash, answering whole packets
+    struct Device {
+        magic: u32,
+        /// syncs missed before answering
+        deaf: u32,
+        synced: bool,
+        silent: bool,
+        attached: bool,
+        flash_size: Option<u32>,
+        /// address and size of the last erase
+        erased: Option<(u32, u32)>,
+        writing: u32,
+        flash: Vec<u8>,
+        /// bit flipped in the byte written at this address
+        faulty: Option<usize>,
+        baud: Option<u32>,
+        rebooted: Option<bool>,
+    }
+
+    impl Device {
+        fn new(magic: u32) -> Self {
+            Self {
+                magic,
+                deaf: 0,
+                synced: false,
+                silent: false,
+                attached: false,
+                flash_size: None,
+                erased: None,
+                writing: 0,
+                flash: vec![0; 128 << 10],
+                faulty: None,
+                baud: None,
+                rebooted: None,
+            }
+        }
+
+        fn frame(&self, op: u8, value: u32, payload: &[u8], error: Option<u8>) -> Vec<u8> {
+            let status = match (error, self.magic) {
+                (None, ESP8266_MAGIC) => vec![0, 0],
+                (Some(error), ESP8266_MAGIC) => vec![1, error],
+                (None, _) => vec![0; 4],
+                (Some(error), _) => vec![1, error, 0, 0],
+            };
+            // errors come without the payload
+            let payload = if error.is_some() { &[][..] } else { payload };
+            let mut packet = vec![0x01, op];
+            packet.extend_from_slice(&((payload.len() + status.len()) as u16).to_le_bytes());
+            packet.extend_from_slice(&value.to_le_bytes());
+            packet.extend_from_slice(payload);
+            packet.extend_from_slice(&status);
+            slip(&packet)
+        }
+
+        fn handle(&mut self, msg: &[u8]) -> Vec<u8> {
+            if self.silent {
+                return Vec::new();
+            }
+            let mut packet = Vec::new();
+            let mut escaped = false;
+            for &byte in &msg[1..msg.len() - 1] {
+                match byte {
+                    _ if escaped => {
+                        escaped = false;
+                        packet.push(if byte == ESC_END { END } else { ESC });
+                    }
+                    ESC => escaped = true,
+                    _ => packet.push(byte),
+                }
+            }
+            let op = packet[1];
+            let checksum = u32::from_le_bytes([packet[4], packet[5], packet[6], packet[7]]);
+            let data = &packet[8..];
+            let word = |i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+            let ok = |device: &Self, value| device.frame(op, value, &[], None);
+            match op {
+                SYNC => {
+                    if self.deaf > 0 {
+                        self.deaf -= 1;
+                        return Vec::new();
+                    }
+                    let mut reply = Vec::new();
+                    if !self.synced {
+                        reply.extend_from_slice(b"rst:0x1 (POWERON_RESET),boot:0x3\r\n");
+                    }
+                    self.synced = true;
+                    for _ in 0..8 {
+                        reply.extend(ok(self, 0));
+                    }
+                    reply
+                }
+                _ if !self.synced => Vec::new(),
+                READ_REG if word(0) == CHIP_DETECT_MAGIC_REG => ok(self, self.magic),
+                READ_REG => ok(self, word(0).rotate_left(8)),
+                SPI_ATTACH => {
+                    self.attached = true;
+                    ok(self, 0)
+                }
+                SPI_SET_PARAMS => {
+                    self.flash_size = Some(word(1));
+                    ok(self, 0)
+                }
+                FLASH_BEGIN => {
+                    if self.magic == ESP32_MAGIC && self.flash_size.is_none() {
+                        return self.frame(op, 0, &[], Some(0x06));
+                    }
+                    let (size, address) = (word(0), word(3));
+                    self.flash[address as usize..(address + size) as usize].fill(0xFF);
+                    self.erased = Some((address, size));
+                    self.writing = address;
+                    ok(self, 0)
+                }
+                FLASH_DATA => {
+                    let block = &data[16..16 + word(0) as usize];
+                    let sum = block.iter().fold(CHECKSUM_SEED, |sum, &b| sum ^ b);
+                    if sum as u32 != checksum {
+                        return self.frame(op, 0, &[], Some(0x07));
+                    }
+                    let start = self.writing as usize + word(1) as usize * BLOCK;
+                    self.flash[start..start + block.len()].copy_from_slice(block);
+                    if let Some(i) = self.faulty.filter(|i| (start..start + BLOCK).contains(i)) {
+                        self.flash[i] ^= 1;
+                    }
+                    ok(self, 0)
+                }
+                FLASH_END => {
+                    self.rebooted = Some(word(0) == 0);
+                    ok(self, 0)
+                }
+                SPI_FLASH_MD5 if self.magic != ESP8266_MAGIC => {
+                    let (address, size) = (word(0) as usize, word(1) as usize);
+                    let digest =
+                        format!("{:x}", md5::compute(&self.flash[address..address + size]));
+                    self.frame(op, 0, digest.as_bytes(), None)
+                }
+                CHANGE_BAUDRATE => {
+                    self.baud = Some(word(0));
+                    ok(self, 0)
+                }
+                _ => self.frame(op, 0, &[], Some(0x05)),
+            }
+        }
+    }
+
+    type Outcome = (Result<Vec<EspReply>, String>, Vec<PortControl>, Vec<String>);
+
+    fn run(device: &mut Device, options: EspOptions, commands: Vec<EspCommand>) -> Outcome {
+        let mut now = Instant::now();
+        let mut session = EspSession::new(options, commands);
+        let mut io = TransferIo::default();
+        let mut controls = Vec::new();
+        let mut stages = Vec::new();
+        session.start(now, &mut io);
+        for _ in 0..10_000 {
+            controls.append(&mut io.control);
+            if let Some(outcome) = io.outcome.take() {
+                return (outcome, controls, stages);
+            }
+            for (stage, _, _) in io.progress.drain(..) {
+                if stages.last() != Some(&stage) {
+                    stages.push(stage);
+                }
+            }
+            let out = std::mem::take(&mut io.out);
+            if out.is_empty() {
+                now += Duration::from_millis(50);
+                session.update(now, &mut io);
+            }
+            for msg in out {
+                let reply = device.handle(&msg);
+                session.receive(&reply, now, &mut io);
+            }
+        }
+        panic!("the session did not end");
+    }
+
+    #[test]
+    fn flash_esp32() {
+        // SLIP escapes in the data
+        let firmware: Bytes = (0..3000u32).map(|i| (i % 7) as u8 ^ 0xC0).collect();
+        let mut device = Device::new(ESP32_MAGIC);
+        device.deaf = 2;
+        let options = EspOptions::default().with_reset_lines(PortControl::Rts, PortControl::Dtr);
+        let commands = vec![
+            EspCommand::ReadRegister(EFUSE),
+            EspCommand::ChangeBaudRate(921_600),
+            EspCommand::WriteFlash {
+                address: 0x1_0000,
+                data: firmware.clone(),
+            },
+            EspCommand::VerifyFlash {
+                address: 0x1_0000,
+                data: firmware.clone(),
+            },
+            EspCommand::FlashEnd { reboot: true },
+        ];
+        let (replies, controls, stages) = run(&mut device, options, commands);
+        assert_eq!(
+            replies.unwrap(),
+            [
+                EspReply::Register(EFUSE.rotate_left(8)),
+                EspReply::Done,
+                EspReply::Done,
+                EspReply::Done,
+                EspReply::Done
+            ]
+        );
+        assert!(device.attached);
+        assert_eq!(device.flash_size, Some(4 << 20));
+        assert_eq!(device.erased, Some((0x1_0000, 3000)));
+        assert_eq!(&device.flash[0x1_0000..0x1_0000 + 3000], &firmware[..]);
+        // the last block is padded
+        assert_eq!(device.flash[0x1_0000 + 3000], 0xFF);
+        assert_eq!(device.baud, Some(921_600));
+        assert_eq!(device.rebooted, Some(true));
+        // reset into the bootloader, not out of it after rebooting
+        assert_eq!(
+            controls,
+            [
+                PortControl::Dtr(false),
+                PortControl::Rts(true),
+                PortControl::Dtr(true),
+                PortControl::Rts(false),
+                PortControl::Dtr(false),
+                PortControl::BaudRate(921_600)
+            ]
+        );
+        assert_eq!(stages, ["write", "verify"]);
+    }
+
+    #[test]
+    fn flash_esp8266() {
+        assert_eq!(esp8266_erase_size(0, 3000), SECTOR);
+        assert_eq!(esp8266_erase_size(0, 256 * SECTOR), 240 * SECTOR);
+        assert_eq!(esp8266_erase_size(14 * SECTOR, 4 * SECTOR), 2 * SECTOR);
+
+        let firmware = Bytes::from(vec![0x42; 5000]);
+        let mut device = Device::new(ESP8266_MAGIC);
+        let options = EspOptions::default().with_reset_lines(PortControl::Rts, PortControl::Dtr);
+        let commands = vec![
+            EspCommand::WriteFlash {
+                address: 0,
+                data: firmware.clone(),
+            },
+            EspCommand::FlashEnd { reboot: false },
+        ];
+        let (replies, controls, _) = run(&mut device, options, commands);
+        assert_eq!(replies.unwrap(), [EspReply::Done, EspReply::Done]);
+        assert!(!device.attached);
+        assert_eq!(device.erased, Some((0, SECTOR)));
+        assert_eq!(&device.flash[..5000], &firmware[..]);
+        assert_eq!(device.rebooted, Some(false));
+        // reset out of the bootloader
+        assert_eq!(
+            controls[controls.len() - 2..],
+            [PortControl::Rts(true), PortControl::Rts(false)]
+        );
+
+        let commands = vec![EspCommand::VerifyFlash {
+            address: 0,
+            data: firmware,
+        }];
+        let (result, _, _) = run(&mut device, EspOptions::default(), commands);
+        assert_eq!(
+            result,
+            Err("the bootloader refused MD5: invalid message".to_string())
+        );
+    }
+
+    #[test]
+    fn failures() {
+        let data = Bytes::from(vec![0x5A; 2048]);
+        let mut device = Device::new(ESP32_MAGIC);
+        device.faulty = Some(0x1_0123);
+        let commands = vec![
+            EspCommand::WriteFlash {
+                address: 0x1_0000,
+                data: data.clone(),
+            },
+            EspCommand::VerifyFlash {
+                address: 0x1_0000,
+                data: data.clone(),
+            },
+        ];
+        let (result, _, _) = run(&mut device, EspOptions::default(), commands);
+        let reason = result.unwrap_err();
+        assert!(
+            reason.starts_with("verify failed at 0x00010000: MD5 "),
+            "{reason}"
+        );
+        assert!(reason.ends_with(&format!("expected {:x}", md5::compute(&data))));
+
+        let (result, _, _) = run(&mut Device::new(0x0000_07C6), EspOptions::default(), vec![]);
+        assert_eq!(
+            result,
+            Err("unsupported chip, magic value 0x000007c6".to_string())
+        );
+
+        let mut device = Device::new(ESP32_MAGIC);
+        device.silent = true;
+        let (result, _, _) = run(&mut device, EspOptions::default(), Vec::new());
+        assert_eq!(result, Err("no reply to sync".to_string()));
+    }
+
+    #[test]
+    fn exit_after_failure_or_cancel() {
+        let options = EspOptions::default().with_reset_lines(PortControl::Rts, PortControl::Dtr);
+        let enter = [
+            PortControl::Dtr(false),
+            PortControl::Rts(true),
+            PortControl::Dtr(true),
+            PortControl::Rts(false),
+            PortControl::Dtr(false),
+        ];
+        let exit = [PortControl::Rts(true), PortControl::Rts(false)];
+
+        let commands = vec![EspCommand::ReadRegister(0x3FF0_0050)];
+        let mut device = Device::new(0x0000_07C6);
+        let (result, controls, _) = run(&mut device, options.clone(), commands.clone());
+        assert_eq!(
+            result,
+            Err("unsupported chip, magic value 0x000007c6".to_string())
+        );
+        assert_eq!(controls, [&enter[..], &exit[..]].concat());
+
+        let mut now = Instant::now();
+        let mut session = EspSession::new(options, commands);
+        let mut io = TransferIo::default();
+        session.start(now, &mut io);
+        while io.out.is_empty() {
+            now += Duration::from_millis(50);
+            session.update(now, &mut io);
+        }
+        session.cancel(now, &mut io);
+        while io.outcome.is_none() {
+            now += Duration::from_millis(50);
+            session.update(now, &mut io);
+        }
+        assert_eq!(io.outcome, Some(Err("cancelled".to_string())));
+        assert_eq!(io.control, [&enter[..], &exit[..]].concat());
+        assert_eq!(io.out.len(), 1);
+    }
+}
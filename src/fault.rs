@@ -0,0 +1,190 @@
+//! Fault injection between a port and its codec, to test protocol handling against bad links.
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+/// Faults injected into one direction of a port, see [`SerialPortSetting::faults`](crate::SerialPortSetting::faults).
+///
+/// Probabilities range from `0.0` (never) to `1.0` (always). Faults are drawn from a generator
+/// seeded with [`FaultConfig::seed`], so the same seed and traffic reproduce the same faults.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FaultConfig {
+    pub seed: u64,
+    /// Delay added to every chunk
+    pub latency: Duration,
+    /// Random extra delay of up to this much per chunk, chunks are never reordered
+    pub jitter: Duration,
+    /// Probability of each byte being lost
+    pub drop_byte: f64,
+    /// Probability of each byte getting one random bit flipped
+    pub flip_bit: f64,
+    /// Probability of a chunk arriving twice
+    pub duplicate_chunk: f64,
+    /// Deliver every chunk one byte at a time
+    pub fragment: bool,
+    /// Probability of the link breaking at each chunk
+    pub disconnect: f64,
+    /// Break the link once this many bytes went through
+    pub disconnect_after_bytes: Option<u64>,
+}
+
+/// Faults injected into both directions of a port
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FaultInjection {
+    /// Faults in the bytes read from the port, before they are decoded
+    pub inbound: Option<FaultConfig>,
+    /// Faults in the bytes written to the port, after they are encoded
+    pub outbound: Option<FaultConfig>,
+}
+
+/// Applies a [`FaultConfig`] to a stream of chunks
+pub(crate) struct FaultInjector {
+    config: FaultConfig,
+    rng: SplitMix64,
+    bytes: u64,
+    last_due: Option<Instant>,
+    disconnected: bool,
+}
+
+impl FaultInjector {
+    pub(crate) fn new(config: FaultConfig) -> Self {
+        Self {
+            rng: SplitMix64(config.seed),
+            config,
+            bytes: 0,
+            last_due: None,
+            disconnected: false,
+        }
+    }
+
+    /// The chunks to deliver in place of `chunk` and when to deliver them, or an error once
+    /// the link is broken
+    pub(crate) fn apply(
+        &mut self,
+        chunk: &[u8],
+        now: Instant,
+    ) -> io::Result<Vec<(Instant, Bytes)>> {
+        self.bytes += chunk.len() as u64;
+        let broken = self
+            .config
+            .disconnect_after_bytes
+            .is_some_and(|limit| self.bytes > limit)
+            || self.rng.chance(self.config.disconnect);
+        if self.disconnected || broken {
+            self.disconnected = true;
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "link broken by fault injection",
+            ));
+        }
+
+        let mut data = Vec::with_capacity(chunk.len());
+        for &byte in chunk {
+            if self.rng.chance(self.config.drop_byte) {
+                continue;
+            }
+            if self.rng.chance(self.config.flip_bit) {
+                data.push(byte ^ (1 << self.rng.below(8)));
+            } else {
+                data.push(byte);
+            }
+        }
+        let data = Bytes::from(data);
+        let copies = if self.rng.chance(self.config.duplicate_chunk) {
+            2
+        } else {
+            1
+        };
+
+        let jitter = self.config.jitter.mul_f64(self.rng.unit());
+        let due = (now + self.config.latency + jitter).max(self.last_due.unwrap_or(now));
+        self.last_due = Some(due);
+
+        let mut chunks = Vec::new();
+        for _ in 0..copies {
+            if self.config.fragment {
+                chunks.extend((0..data.len()).map(|i| (due, data.slice(i..i + 1))));
+            } else if !data.is_empty() {
+                chunks.push((due, data.clone()));
+            }
+        }
+        Ok(chunks)
+    }
+}
+
+/// Small seeded generator, stable across versions so recorded seeds stay reproducible
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.unit() < probability
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_faults() {
+        let config = FaultConfig {
+            seed: 42,
+            drop_byte: 0.1,
+            flip_bit: 0.1,
+            duplicate_chunk: 0.2,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let run = || {
+            let mut faults = FaultInjector::new(config.clone());
+            (0..20)
+                .map(|_| faults.apply(b"0123456789", now).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn fragments_delays_and_disconnects() {
+        let mut faults = FaultInjector::new(FaultConfig {
+            latency: Duration::from_millis(10),
+            fragment: true,
+            disconnect_after_bytes: Some(4),
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let chunks = faults.apply(b"abc", now).unwrap();
+        let due = now + Duration::from_millis(10);
+        assert_eq!(
+            chunks,
+            vec![
+                (due, Bytes::from_static(b"a")),
+                (due, Bytes::from_static(b"b")),
+                (due, Bytes::from_static(b"c")),
+            ]
+        );
+        assert!(faults.apply(b"de", now).is_err());
+        assert!(faults.apply(b"f", now).is_err());
+    }
+}
@@ -0,0 +1,895 @@
+//! A Firmata client for Arduino-class boards running StandardFirmata or a derivative.
+
+use std::{collections::HashMap, time::Duration};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use crate::{SerialData, SerialError, SerialResource};
+
+const DIGITAL_MESSAGE: u8 = 0x90;
+const REPORT_ANALOG: u8 = 0xC0;
+const REPORT_DIGITAL: u8 = 0xD0;
+const ANALOG_MESSAGE: u8 = 0xE0;
+const START_SYSEX: u8 = 0xF0;
+const SET_PIN_MODE: u8 = 0xF4;
+const END_SYSEX: u8 = 0xF7;
+const REPORT_VERSION: u8 = 0xF9;
+
+const ANALOG_MAPPING_QUERY: u8 = 0x69;
+const ANALOG_MAPPING_RESPONSE: u8 = 0x6A;
+const CAPABILITY_QUERY: u8 = 0x6B;
+const CAPABILITY_RESPONSE: u8 = 0x6C;
+const PIN_STATE_QUERY: u8 = 0x6D;
+const PIN_STATE_RESPONSE: u8 = 0x6E;
+const EXTENDED_ANALOG: u8 = 0x6F;
+const STRING_DATA: u8 = 0x71;
+const I2C_REQUEST: u8 = 0x76;
+const I2C_REPLY: u8 = 0x77;
+const I2C_CONFIG: u8 = 0x78;
+const REPORT_FIRMWARE: u8 = 0x79;
+const SAMPLING_INTERVAL: u8 = 0x7A;
+
+/// Ends the modes of a pin in a capability response, and marks pins without an analog channel
+const NONE: u8 = 0x7F;
+/// Longest SysEx message kept, longer ones are dropped
+const MAX_SYSEX: usize = 4096;
+
+/// Mode of a pin, as in the Firmata protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PinMode {
+    Input,
+    Output,
+    Analog,
+    Pwm,
+    Servo,
+    Shift,
+    I2c,
+    OneWire,
+    Stepper,
+    Encoder,
+    Serial,
+    InputPullup,
+    Spi,
+    Sonar,
+    Tone,
+    Dht,
+    /// A mode this crate has no name for
+    Other(u8),
+}
+
+impl PinMode {
+    fn code(self) -> u8 {
+        match self {
+            PinMode::Input => 0x00,
+            PinMode::Output => 0x01,
+            PinMode::Analog => 0x02,
+            PinMode::Pwm => 0x03,
+            PinMode::Servo => 0x04,
+            PinMode::Shift => 0x05,
+            PinMode::I2c => 0x06,
+            PinMode::OneWire => 0x07,
+            PinMode::Stepper => 0x08,
+            PinMode::Encoder => 0x09,
+            PinMode::Serial => 0x0A,
+            PinMode::InputPullup => 0x0B,
+            PinMode::Spi => 0x0C,
+            PinMode::Sonar => 0x0D,
+            PinMode::Tone => 0x0E,
+            PinMode::Dht => 0x0F,
+            PinMode::Other(code) => code,
+        }
+    }
+
+    fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => PinMode::Input,
+            0x01 => PinMode::Output,
+            0x02 => PinMode::Analog,
+            0x03 => PinMode::Pwm,
+            0x04 => PinMode::Servo,
+            0x05 => PinMode::Shift,
+            0x06 => PinMode::I2c,
+            0x07 => PinMode::OneWire,
+            0x08 => PinMode::Stepper,
+            0x09 => PinMode::Encoder,
+            0x0A => PinMode::Serial,
+            0x0B => PinMode::InputPullup,
+            0x0C => PinMode::Spi,
+            0x0D => PinMode::Sonar,
+            0x0E => PinMode::Tone,
+            0x0F => PinMode::Dht,
+            code => PinMode::Other(code),
+        }
+    }
+
+    fn is_input(self) -> bool {
+        matches!(self, PinMode::Input | PinMode::InputPullup)
+    }
+}
+
+/// What is known about a pin of a board
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FirmataPin {
+    /// Modes the pin supports with their resolution in bits, once the board reported them
+    pub capabilities: Vec<(PinMode, u8)>,
+    /// Mode last set or reported
+    pub mode: Option<PinMode>,
+    /// Channel the board reports the pin's analog readings on
+    pub analog_channel: Option<u8>,
+    /// Last value read or written, 0 or 1 for digital pins
+    pub value: u32,
+}
+
+/// Name and version of the firmware of a board
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmataFirmware {
+    pub name: String,
+    pub major: u8,
+    pub minor: u8,
+}
+
+/// State of a board attached with [`FirmataClient::attach`], updated as it reports
+#[derive(Debug, Clone, Default)]
+pub struct FirmataBoard {
+    /// Version of the Firmata protocol the board speaks
+    pub protocol_version: Option<(u8, u8)>,
+    pub firmware: Option<FirmataFirmware>,
+    pub pins: Vec<FirmataPin>,
+    parser: Parser,
+}
+
+/// Messages from a board other than pin readings, sent as [`FirmataReceived`] events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirmataMessage {
+    Version {
+        major: u8,
+        minor: u8,
+    },
+    Firmware(FirmataFirmware),
+    /// The capabilities of the pins were updated
+    Capabilities,
+    /// The analog channels of the pins were updated
+    AnalogMapping,
+    PinState {
+        pin: u8,
+        mode: PinMode,
+        value: u32,
+    },
+    I2cReply {
+        address: u16,
+        register: u16,
+        data: Vec<u8>,
+    },
+    String(String),
+    /// A SysEx message this crate does not handle, with its 7 bit data bytes
+    SysEx {
+        command: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// A message from the board on `port`
+#[derive(Debug, Clone, Event)]
+pub struct FirmataReceived {
+    pub port: String,
+    pub message: FirmataMessage,
+}
+
+/// A digital or analog input of the board on `port` read a new value
+#[derive(Debug, Clone, PartialEq, Eq, Event)]
+pub struct FirmataPinChanged {
+    pub port: String,
+    pub pin: u8,
+    pub value: u32,
+    pub previous: u32,
+}
+
+/// The boards attached with [`FirmataClient::attach`], by port
+#[derive(Debug, Default, Resource)]
+pub struct FirmataBoards {
+    boards: HashMap<String, FirmataBoard>,
+}
+
+impl FirmataBoards {
+    pub fn get(&self, port: &str) -> Option<&FirmataBoard> {
+        self.boards.get(port)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &FirmataBoard)> {
+        self.boards
+            .iter()
+            .map(|(port, board)| (port.as_str(), board))
+    }
+}
+
+/// Talks Firmata to the boards on ports opened with the default framing, mirroring their pins
+/// into [`FirmataBoards`].
+///
+/// Holds [`SerialResource`] mutably, use [`FirmataClient::serial`] for the untyped API in the
+/// same system.
+#[derive(SystemParam)]
+pub struct FirmataClient<'w> {
+    serial: ResMut<'w, SerialResource>,
+    boards: ResMut<'w, FirmataBoards>,
+}
+
+impl<'w> FirmataClient<'w> {
+    /// Mirror the board on an open port, and ask it for its version, firmware, capabilities and
+    /// analog channels
+    pub fn attach(&mut self, port: &str) -> Result<(), SerialError> {
+        let mut query = vec![REPORT_VERSION];
+        for command in [REPORT_FIRMWARE, CAPABILITY_QUERY, ANALOG_MAPPING_QUERY] {
+            query.extend(sysex(command, &[]));
+        }
+        self.serial.send_message(port, query.into())?;
+        self.boards
+            .boards
+            .insert(port.to_string(), FirmataBoard::default());
+        Ok(())
+    }
+
+    /// Stop mirroring the board on `port`. `false` if it was not attached
+    pub fn detach(&mut self, port: &str) -> bool {
+        self.boards.boards.remove(port).is_some()
+    }
+
+    pub fn board(&self, port: &str) -> Option<&FirmataBoard> {
+        self.boards.get(port)
+    }
+
+    pub fn set_pin_mode(&mut self, port: &str, pin: u8, mode: PinMode) -> Result<(), SerialError> {
+        check_pin(port, pin)?;
+        let bytes = self.board_mut(port)?.set_pin_mode(pin, mode);
+        self.send(port, bytes)
+    }
+
+    /// Set an output pin, along with the other output pins of its 8 pin port as last written
+    pub fn digital_write(&mut self, port: &str, pin: u8, value: bool) -> Result<(), SerialError> {
+        check_pin(port, pin)?;
+        let bytes = self.board_mut(port)?.digital_write(pin, value);
+        self.send(port, bytes)
+    }
+
+    /// Write a PWM duty cycle or servo position
+    pub fn analog_write(&mut self, port: &str, pin: u8, value: u32) -> Result<(), SerialError> {
+        check_pin(port, pin)?;
+        let bytes = self.board_mut(port)?.analog_write(pin, value);
+        self.send(port, bytes)
+    }
+
+    /// Start or stop reports of the 8 pin port holding `pin`, for its pins in an input mode
+    pub fn report_digital(&mut self, port: &str, pin: u8, enable: bool) -> Result<(), SerialError> {
+        check_pin(port, pin)?;
+        self.send(port, vec![REPORT_DIGITAL | (pin / 8), enable as u8])
+    }
+
+    /// Start or stop reports of an analog channel, see [`FirmataPin::analog_channel`]
+    pub fn report_analog(
+        &mut self,
+        port: &str,
+        channel: u8,
+        enable: bool,
+    ) -> Result<(), SerialError> {
+        if channel > 0x0F {
+            return Err(invalid(port, format!("analog channel {channel} above 15")));
+        }
+        self.send(port, vec![REPORT_ANALOG | channel, enable as u8])
+    }
+
+    /// How often the board reports analog channels and I2C reads
+    pub fn set_sampling_interval(
+        &mut self,
+        port: &str,
+        interval: Duration,
+    ) -> Result<(), SerialError> {
+        let ms = interval.as_millis().min(0x3FFF) as u16;
+        self.send(port, sysex(SAMPLING_INTERVAL, &fourteen_bit(ms)))
+    }
+
+    /// Ask for the modes and resolutions of all pins, answered with
+    /// [`FirmataMessage::Capabilities`]
+    pub fn query_capabilities(&mut self, port: &str) -> Result<(), SerialError> {
+        self.send(port, sysex(CAPABILITY_QUERY, &[]))
+    }
+
+    /// Ask for the mode and value of a pin, answered with [`FirmataMessage::PinState`]
+    pub fn query_pin_state(&mut self, port: &str, pin: u8) -> Result<(), SerialError> {
+        check_pin(port, pin)?;
+        self.send(port, sysex(PIN_STATE_QUERY, &[pin]))
+    }
+
+    /// Enable I2C, waiting `delay` between writing a register and reading it on devices that
+    /// need it
+    pub fn i2c_config(&mut self, port: &str, delay: Duration) -> Result<(), SerialError> {
+        let micros = delay.as_micros().min(0x3FFF) as u16;
+        self.send(port, sysex(I2C_CONFIG, &fourteen_bit(micros)))
+    }
+
+    pub fn i2c_write(&mut self, port: &str, address: u8, data: &[u8]) -> Result<(), SerialError> {
+        let bytes = i2c_request(port, address, I2cMode::Write, None, &to_pairs(data))?;
+        self.send(port, bytes)
+    }
+
+    /// Read `len` bytes from a device, from `register` if given, once or at every sampling
+    /// interval until [`FirmataClient::i2c_stop_reading`]. Each read is answered with a
+    /// [`FirmataMessage::I2cReply`]
+    pub fn i2c_read(
+        &mut self,
+        port: &str,
+        address: u8,
+        register: Option<u16>,
+        len: u16,
+        continuous: bool,
+    ) -> Result<(), SerialError> {
+        let bytes = i2c_read_request(port, address, register, len, continuous)?;
+        self.send(port, bytes)
+    }
+
+    pub fn i2c_stop_reading(&mut self, port: &str, address: u8) -> Result<(), SerialError> {
+        let bytes = i2c_request(port, address, I2cMode::StopReading, None, &[])?;
+        self.send(port, bytes)
+    }
+
+    /// Send a SysEx message, `data` holding 7 bit bytes. Replies this crate does not handle
+    /// arrive as [`FirmataMessage::SysEx`]
+    pub fn send_sysex(&mut self, port: &str, command: u8, data: &[u8]) -> Result<(), SerialError> {
+        if let Some(byte) = std::iter::once(&command).chain(data).find(|&&b| b > 0x7F) {
+            return Err(invalid(port, format!("SysEx byte {byte:#04x} above 0x7f")));
+        }
+        self.send(port, sysex(command, data))
+    }
+
+    pub fn serial(&mut self) -> &mut SerialResource {
+        &mut self.serial
+    }
+
+    fn board_mut(&mut self, port: &str) -> Result<&mut FirmataBoard, SerialError> {
+        self.boards
+            .boards
+            .get_mut(port)
+            .ok_or_else(|| SerialError::NoFirmataBoard(port.to_string()))
+    }
+
+    fn send(&mut self, port: &str, bytes: Vec<u8>) -> Result<(), SerialError> {
+        self.board_mut(port)?;
+        self.serial.send_message(port, bytes.into())
+    }
+}
+
+fn invalid(port: &str, reason: String) -> SerialError {
+    SerialError::InvalidFirmata(port.to_string(), reason)
+}
+
+fn check_pin(port: &str, pin: u8) -> Result<(), SerialError> {
+    if pin > 0x7F {
+        return Err(invalid(port, format!("pin {pin} above 127")));
+    }
+    Ok(())
+}
+
+fn sysex(command: u8, data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![START_SYSEX, command];
+    bytes.extend_from_slice(data);
+    bytes.push(END_SYSEX);
+    bytes
+}
+
+fn fourteen_bit(value: u16) -> [u8; 2] {
+    [(value & 0x7F) as u8, (value >> 7 & 0x7F) as u8]
+}
+
+/// Each byte as two 7 bit bytes, low bits first
+fn to_pairs(data: &[u8]) -> Vec<u8> {
+    data.iter().flat_map(|&b| [b & 0x7F, b >> 7]).collect()
+}
+
+fn from_pairs(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(2)
+        .map(|pair| pair[0] | pair[1] << 7)
+        .collect()
+}
+
+/// 7 bit bytes, low bits first, as sent by the board
+fn value(data: &[u8]) -> u32 {
+    data.iter()
+        .take(5)
+        .rev()
+        .fold(0, |value, &b| value << 7 | (b & 0x7F) as u32)
+}
+
+#[derive(Clone, Copy)]
+enum I2cMode {
+    Write = 0,
+    ReadOnce = 1,
+    ReadContinuously = 2,
+    StopReading = 3,
+}
+
+/// An I2C request with `data` already in 7 bit bytes
+fn i2c_request(
+    port: &str,
+    address: u8,
+    mode: I2cMode,
+    register: Option<u16>,
+    data: &[u8],
+) -> Result<Vec<u8>, SerialError> {
+    if address > 0x7F {
+        return Err(invalid(
+            port,
+            format!("I2C address {address:#04x} above 0x7f"),
+        ));
+    }
+    let mut bytes = vec![address, (mode as u8) << 3];
+    if let Some(register) = register {
+        bytes.extend(fourteen_bit(register));
+    }
+    bytes.extend_from_slice(data);
+    Ok(sysex(I2C_REQUEST, &bytes))
+}
+
+/// The length goes out as one 14 bit value, which is how the firmware tells it from a register
+fn i2c_read_request(
+    port: &str,
+    address: u8,
+    register: Option<u16>,
+    len: u16,
+    continuous: bool,
+) -> Result<Vec<u8>, SerialError> {
+    let mode = if continuous {
+        I2cMode::ReadContinuously
+    } else {
+        I2cMode::ReadOnce
+    };
+    i2c_request(port, address, mode, register, &fourteen_bit(len))
+}
+
+/// Splits the bytes from a board into messages
+#[derive(Debug, Clone, Default)]
+struct Parser {
+    message: Vec<u8>,
+}
+
+impl Parser {
+    /// The message `byte` completes, if any
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        let in_sysex = self.message.first() == Some(&START_SYSEX);
+        if byte == END_SYSEX && in_sysex {
+            return Some(std::mem::take(&mut self.message));
+        }
+        if byte & 0x80 != 0 {
+            // a new message, dropping one cut short
+            self.message = vec![byte];
+        } else if !self.message.is_empty() {
+            if in_sysex && self.message.len() >= MAX_SYSEX {
+                self.message.clear();
+                return None;
+            }
+            self.message.push(byte);
+        }
+        let len = match *self.message.first()? {
+            0x90..=0x9F | 0xE0..=0xEF | SET_PIN_MODE | REPORT_VERSION => 3,
+            0xC0..=0xDF => 2,
+            _ => return None,
+        };
+        (self.message.len() == len).then(|| std::mem::take(&mut self.message))
+    }
+}
+
+/// A change to a board, made by a message from it
+#[derive(Debug, PartialEq, Eq)]
+enum Update {
+    Pin { pin: u8, value: u32, previous: u32 },
+    Message(FirmataMessage),
+}
+
+impl FirmataBoard {
+    pub fn pin(&self, pin: u8) -> Option<&FirmataPin> {
+        self.pins.get(pin as usize)
+    }
+
+    /// The pin reporting on an analog channel
+    pub fn analog_pin(&self, channel: u8) -> Option<u8> {
+        self.pins
+            .iter()
+            .position(|pin| pin.analog_channel == Some(channel))
+            .map(|pin| pin as u8)
+    }
+
+    fn pin_mut(&mut self, pin: u8) -> &mut FirmataPin {
+        let index = pin as usize;
+        if self.pins.len() <= index {
+            self.pins.resize(index + 1, FirmataPin::default());
+        }
+        &mut self.pins[index]
+    }
+
+    fn set_pin_mode(&mut self, pin: u8, mode: PinMode) -> Vec<u8> {
+        self.pin_mut(pin).mode = Some(mode);
+        vec![SET_PIN_MODE, pin, mode.code()]
+    }
+
+    fn digital_write(&mut self, pin: u8, value: bool) -> Vec<u8> {
+        self.pin_mut(pin).value = value as u32;
+        let first = pin / 8 * 8;
+        let mut bits = 0u16;
+        for i in 0..8 {
+            let Some(state) = self.pin(first + i) else {
+                break;
+            };
+            let output = first + i == pin || state.mode == Some(PinMode::Output);
+            if output && state.value != 0 {
+                bits |= 1 << i;
+            }
+        }
+        let [low, high] = fourteen_bit(bits);
+        vec![DIGITAL_MESSAGE | (pin / 8), low, high]
+    }
+
+    fn analog_write(&mut self, pin: u8, value: u32) -> Vec<u8> {
+        self.pin_mut(pin).value = value;
+        if pin <= 0x0F && value <= 0x3FFF {
+            let [low, high] = fourteen_bit(value as u16);
+            return vec![ANALOG_MESSAGE | pin, low, high];
+        }
+        let mut data = vec![pin];
+        let mut rest = value;
+        loop {
+            data.push((rest & 0x7F) as u8);
+            rest >>= 7;
+            if rest == 0 {
+                break;
+            }
+        }
+        sysex(EXTENDED_ANALOG, &data)
+    }
+
+    fn feed(&mut self, bytes: &[u8]) -> Vec<Update> {
+        let mut updates = Vec::new();
+        for &byte in bytes {
+            if let Some(message) = self.parser.push(byte) {
+                self.handle(&message, &mut updates);
+            }
+        }
+        updates
+    }
+
+    fn read(&mut self, pin: u8, value: u32, updates: &mut Vec<Update>) {
+        let state = self.pin_mut(pin);
+        let previous = std::mem::replace(&mut state.value, value);
+        if previous != value {
+            updates.push(Update::Pin {
+                pin,
+                value,
+                previous,
+            });
+        }
+    }
+
+    fn handle(&mut self, message: &[u8], updates: &mut Vec<Update>) {
+        let data = &message[1..];
+        match message[0] {
+            status @ 0x90..=0x9F => {
+                let bits = value(data);
+                let first = (status & 0x0F) * 8;
+                for i in 0..8 {
+                    let pin = first + i;
+                    let input = self
+                        .pin(pin)
+                        .and_then(|state| state.mode)
+                        .is_some_and(PinMode::is_input);
+                    if input {
+                        self.read(pin, bits >> i & 1, updates);
+                    }
+                }
+            }
+            status @ 0xE0..=0xEF => {
+                if let Some(pin) = self.analog_pin(status & 0x0F) {
+                    self.read(pin, value(data), updates);
+                }
+            }
+            REPORT_VERSION => {
+                self.protocol_version = Some((data[0], data[1]));
+                updates.push(Update::Message(FirmataMessage::Version {
+                    major: data[0],
+                    minor: data[1],
+                }));
+            }
+            START_SYSEX => {
+                if let Some((&command, data)) = data.split_first() {
+                    let message = self.handle_sysex(command, data);
+                    updates.push(Update::Message(message));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_sysex(&mut self, command: u8, data: &[u8]) -> FirmataMessage {
+        match (command, data) {
+            (REPORT_FIRMWARE, [major, minor, name @ ..]) => {
+                let firmware = FirmataFirmware {
+                    name: String::from_utf8_lossy(&from_pairs(name)).into_owned(),
+                    major: *major,
+                    minor: *minor,
+                };
+                self.firmware = Some(firmware.clone());
+                FirmataMessage::Firmware(firmware)
+            }
+            (CAPABILITY_RESPONSE, _) => {
+                let mut pin = 0;
+                let mut modes = Vec::new();
+                let mut bytes = data.iter();
+                while let Some(&mode) = bytes.next() {
+                    if mode != NONE {
+                        modes.extend(bytes.next().map(|&bits| (PinMode::from_code(mode), bits)));
+                        continue;
+                    }
+                    self.pin_mut(pin).capabilities = std::mem::take(&mut modes);
+                    if pin == 0x7F {
+                        break;
+                    }
+                    pin += 1;
+                }
+                FirmataMessage::Capabilities
+            }
+            (ANALOG_MAPPING_RESPONSE, _) => {
+                for (pin, &channel) in data.iter().enumerate().take(128) {
+                    self.pin_mut(pin as u8).analog_channel = (channel != NONE).then_some(channel);
+                }
+                FirmataMessage::AnalogMapping
+            }
+            (PIN_STATE_RESPONSE, [pin, mode, state @ ..]) if *pin <= 0x7F => {
+                let mode = PinMode::from_code(*mode);
+                let value = value(state);
+                let pin_state = self.pin_mut(*pin);
+                pin_state.mode = Some(mode);
+                pin_state.value = value;
+                FirmataMessage::PinState {
+                    pin: *pin,
+                    mode,
+                    value,
+                }
+            }
+            (I2C_REPLY, [a0, a1, r0, r1, bytes @ ..]) => FirmataMessage::I2cReply {
+                address: value(&[*a0, *a1]) as u16,
+                register: value(&[*r0, *r1]) as u16,
+                data: from_pairs(bytes),
+            },
+            (STRING_DATA, _) => {
+                FirmataMessage::String(String::from_utf8_lossy(&from_pairs(data)).into_owned())
+            }
+            _ => FirmataMessage::SysEx {
+                command,
+                data: data.to_vec(),
+            },
+        }
+    }
+}
+
+pub(crate) fn receive_firmata(
+    mut boards: ResMut<FirmataBoards>,
+    mut data_ev: EventReader<SerialData>,
+    mut changed_ev: EventWriter<FirmataPinChanged>,
+    mut received_ev: EventWriter<FirmataReceived>,
+) {
+    if boards.boards.is_empty() {
+        data_ev.clear();
+        return;
+    }
+    for data in data_ev.read() {
+        let Some(board) = boards.boards.get_mut(&data.port) else {
+            continue;
+        };
+        for update in board.feed(&data.data) {
+            match update {
+                Update::Pin {
+                    pin,
+                    value,
+                    previous,
+                } => {
+                    changed_ev.send(FirmataPinChanged {
+                        port: data.port.clone(),
+                        pin,
+                        value,
+                        previous,
+                    });
+                }
+                Update::Message(message) => {
+                    received_ev.send(FirmataReceived {
+                        port: data.port.clone(),
+                        message,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Uno: digital pins 0-13 and analog pins 14-19 on channels 0-5
+    fn uno() -> FirmataBoard {
+        let mut capabilities = vec![START_SYSEX, CAPABILITY_RESPONSE];
+        let mut mapping = vec![START_SYSEX, ANALOG_MAPPING_RESPONSE];
+        for pin in 0..20u8 {
+            capabilities.extend([0x00, 1, 0x01, 1]);
+            if [3, 5, 6, 9, 10, 11].contains(&pin) {
+                capabilities.extend([0x03, 8]);
+            }
+            if pin >= 14 {
+                capabilities.extend([0x02, 10]);
+            }
+            capabilities.push(NONE);
+            mapping.push(if pin >= 14 { pin - 14 } else { NONE });
+        }
+        capabilities.push(END_SYSEX);
+        mapping.push(END_SYSEX);
+        let mut board = FirmataBoard::default();
+        board.feed(&capabilities);
+        board.feed(&mapping);
+        board
+    }
+
+    #[test]
+    fn reports() {
+        let mut board = uno();
+        assert_eq!(board.pins.len(), 20);
+        assert_eq!(
+            board.pin(9).unwrap().capabilities,
+            [(PinMode::Input, 1), (PinMode::Output, 1), (PinMode::Pwm, 8)]
+        );
+        assert_eq!(board.pin(15).unwrap().analog_channel, Some(1));
+        assert_eq!(board.analog_pin(5), Some(19));
+
+        board.set_pin_mode(2, PinMode::Input);
+        board.set_pin_mode(3, PinMode::InputPullup);
+        // split across reads, with a stray data byte first
+        let mut bytes = vec![
+            0x12,
+            REPORT_VERSION,
+            2,
+            5,
+            0xE1,
+            0x7F,
+            0x05,
+            DIGITAL_MESSAGE,
+        ];
+        bytes.extend([0b0001_1100, 0x00]);
+        let mut updates = board.feed(&bytes[..6]);
+        updates.extend(board.feed(&bytes[6..]));
+        assert_eq!(
+            updates,
+            [
+                Update::Message(FirmataMessage::Version { major: 2, minor: 5 }),
+                Update::Pin {
+                    pin: 15,
+                    value: 767,
+                    previous: 0
+                },
+                Update::Pin {
+                    pin: 2,
+                    value: 1,
+                    previous: 0
+                },
+                Update::Pin {
+                    pin: 3,
+                    value: 1,
+                    previous: 0
+                },
+            ]
+        );
+        // only changes, and none for pin 4, which is not an input
+        assert_eq!(board.feed(&[DIGITAL_MESSAGE, 0b0001_1000, 0x00]).len(), 1);
+        assert_eq!(board.pin(4).unwrap().value, 0);
+
+        let mut firmware = vec![START_SYSEX, REPORT_FIRMWARE, 2, 5];
+        firmware.extend(to_pairs(b"StandardFirmata.ino"));
+        firmware.push(END_SYSEX);
+        let mut i2c = vec![START_SYSEX, I2C_REPLY, 0x48, 0, 0x10, 0];
+        i2c.extend(to_pairs(&[0xAB, 0x01]));
+        i2c.push(END_SYSEX);
+        let other = [START_SYSEX, 0x01, 0x02, 0x03, END_SYSEX];
+        let mut updates = board.feed(&firmware);
+        updates.extend(board.feed(&i2c));
+        updates.extend(board.feed(&other));
+        assert_eq!(
+            updates,
+            [
+                Update::Message(FirmataMessage::Firmware(FirmataFirmware {
+                    name: "StandardFirmata.ino".to_string(),
+                    major: 2,
+                    minor: 5
+                })),
+                Update::Message(FirmataMessage::I2cReply {
+                    address: 0x48,
+                    register: 0x10,
+                    data: vec![0xAB, 0x01]
+                }),
+                Update::Message(FirmataMessage::SysEx {
+                    command: 0x01,
+                    data: vec![0x02, 0x03]
+                }),
+            ]
+        );
+        assert_eq!(board.firmware.as_ref().unwrap().major, 2);
+
+        let updates = board.feed(&[
+            START_SYSEX,
+            PIN_STATE_RESPONSE,
+            9,
+            0x03,
+            0x7F,
+            0x01,
+            END_SYSEX,
+        ]);
+        assert_eq!(
+            updates,
+            [Update::Message(FirmataMessage::PinState {
+                pin: 9,
+                mode: PinMode::Pwm,
+                value: 255
+            })]
+        );
+    }
+
+    #[test]
+    fn commands() {
+        let mut board = uno();
+        assert_eq!(
+            board.set_pin_mode(13, PinMode::Output),
+            [SET_PIN_MODE, 13, 0x01]
+        );
+        board.set_pin_mode(8, PinMode::Output);
+        // pin 8 stays on with pin 13
+        assert_eq!(board.digital_write(8, true), [DIGITAL_MESSAGE | 1, 0x01, 0]);
+        assert_eq!(
+            board.digital_write(13, true),
+            [DIGITAL_MESSAGE | 1, 0b0010_0001, 0]
+        );
+        // pin 15 is the high bit
+        assert_eq!(
+            board.digital_write(15, true),
+            [DIGITAL_MESSAGE | 1, 0x21, 0x01]
+        );
+        assert_eq!(board.analog_write(9, 200), [ANALOG_MESSAGE | 9, 0x48, 0x01]);
+        assert_eq!(
+            board.analog_write(20, 1500),
+            [START_SYSEX, EXTENDED_ANALOG, 20, 0x5C, 0x0B, END_SYSEX]
+        );
+        assert_eq!(board.pin(20).unwrap().value, 1500);
+        assert_eq!(
+            i2c_read_request("uno", 0x48, Some(0x10), 2, false).unwrap(),
+            [
+                START_SYSEX,
+                I2C_REQUEST,
+                0x48,
+                0x08,
+                0x10,
+                0x00,
+                0x02,
+                0x00,
+                END_SYSEX
+            ]
+        );
+        assert_eq!(
+            i2c_request("uno", 0x3C, I2cMode::Write, None, &to_pairs(&[0x80, 0x01])).unwrap(),
+            [
+                START_SYSEX,
+                I2C_REQUEST,
+                0x3C,
+                0x00,
+                0x00,
+                0x01,
+                0x01,
+                0x00,
+                END_SYSEX
+            ]
+        );
+        assert!(matches!(
+            i2c_request("uno", 0x80, I2cMode::StopReading, None, &[]),
+            Err(SerialError::InvalidFirmata(..))
+        ));
+    }
+}
@@ -1,44 +1,231 @@
 #![doc = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/README.md"))]
 
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use bevy::prelude::*;
+use bevy::{
+    ecs::schedule::{InternedScheduleLabel, ScheduleLabel},
+    prelude::*,
+};
 use bytes::Bytes;
-use parking_lot::Mutex;
+use mux::Mux;
 pub use serialport::{DataBits, FlowControl, Parity, StopBits};
-use tokio::runtime::Builder;
+use transaction::ReplyTo;
 
+pub use backend::{RuntimeConfig, SerialPortRuntime};
+pub use capture::{
+    parse_capture, read_capture, CaptureRecord, CaptureSink, CaptureWriter, Direction,
+};
+pub use codec::{Framing, LengthPrefix, LengthWidth};
+pub use diagnostic::SerialDiagnosticsPlugin;
 pub use error::SerialError;
+#[cfg(feature = "esp")]
+pub use esp::{EspBootloader, EspCommand, EspOptions, EspReply, EspResult};
+pub use fault::{FaultConfig, FaultInjection};
+#[cfg(feature = "firmata")]
+pub use firmata::{
+    FirmataBoard, FirmataBoards, FirmataClient, FirmataFirmware, FirmataMessage, FirmataPin,
+    FirmataPinChanged, FirmataReceived, PinMode,
+};
+#[cfg(any(
+    feature = "postcard",
+    feature = "bincode",
+    feature = "msgpack",
+    feature = "cbor",
+    feature = "json"
+))]
+pub use message::{
+    MessageFormat, MessagePorts, SerialDecodeError, SerialMessage, SerialMessagePlugin,
+    SerialMessages,
+};
+pub use mock::MockPort;
+pub use mux::{channel_name, CHANNEL_SEPARATOR};
+pub use pcapng::{PcapngWriter, LINKTYPE_USER0};
+#[cfg(target_os = "linux")]
+pub use pty::VirtualPortPair;
+pub use queue::{FrameQueue, OverflowPolicy, PushError, QueueConfig};
+pub use reliable::{DeliveryFailed, ReliableConfig};
+pub use replay::{ReplayPort, ReplayTiming};
+pub use rs485::{DirectionLine, Rs485Config};
 pub use serial_wrap::*;
+pub use stats::{SerialPortStats, SerialStats};
+#[cfg(feature = "stm32")]
+pub use stm32::{Stm32Bootloader, Stm32Command, Stm32Erase, Stm32Options, Stm32Reply, Stm32Result};
+pub use transaction::{ReplyMatcher, Transaction, TransactionFuture, TransactionResult};
+#[cfg(any(feature = "stm32", feature = "esp"))]
+pub use transfer::BootloaderProgress;
+pub use transfer::{
+    FileTransfers, TransferFile, TransferProgress, TransferProtocol, TransferResult,
+};
 
+mod backend;
+mod capture;
+mod clock;
 pub mod codec;
+mod crc;
+mod diagnostic;
 mod error;
+#[cfg(feature = "esp")]
+mod esp;
+mod fault;
+#[cfg(feature = "firmata")]
+mod firmata;
+#[cfg(any(
+    feature = "postcard",
+    feature = "bincode",
+    feature = "msgpack",
+    feature = "cbor",
+    feature = "json"
+))]
+mod message;
+mod mock;
+mod mux;
+mod pcapng;
+mod pipeline;
+#[cfg(target_os = "linux")]
+mod pty;
+mod queue;
+mod reliable;
+mod replay;
+mod rs485;
 mod serial_wrap;
+mod stats;
+#[cfg(feature = "stm32")]
+mod stm32;
+mod trace;
+mod transaction;
+mod transfer;
+mod xmodem;
+mod zmodem;
 /// Serial port plugin
-pub struct SerialPortPlugin;
+pub struct SerialPortPlugin {
+    /// Schedule [`SerialSet::Receive`] runs in
+    pub receive_schedule: InternedScheduleLabel,
+    /// Schedule [`SerialSet::Flush`] runs in
+    pub flush_schedule: InternedScheduleLabel,
+    /// Tokio runtime driving the ports
+    pub runtime: RuntimeConfig,
+    /// How long to wait for queued messages to be written when the app exits
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for SerialPortPlugin {
+    fn default() -> Self {
+        Self {
+            receive_schedule: PreUpdate.intern(),
+            flush_schedule: PostUpdate.intern(),
+            runtime: RuntimeConfig::default(),
+            shutdown_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl SerialPortPlugin {
+    /// Receive and flush in the same schedule, e.g. `FixedUpdate`.
+    ///
+    /// Systems ordered `.after(SerialSet::Receive).before(SerialSet::Flush)` see the data read
+    /// up to this run and have their sends written at the end of it.
+    pub fn in_schedule(schedule: impl ScheduleLabel) -> Self {
+        let schedule = schedule.intern();
+        Self {
+            receive_schedule: schedule,
+            flush_schedule: schedule,
+            ..default()
+        }
+    }
+
+    /// Drive the ports with the given runtime instead of a new multi-thread one
+    pub fn with_runtime(mut self, runtime: RuntimeConfig) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// How long to wait for queued messages to be written when the app exits
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+}
 
 impl Plugin for SerialPortPlugin {
     fn build(&self, app: &mut App) {
-        let tokio_rt = SerialPortRuntime(Arc::new(
-            Builder::new_multi_thread().enable_all().build().unwrap(),
-        ));
+        let tokio_rt = SerialPortRuntime::new(self.runtime.clone())
+            .expect("failed to build the serial port tokio runtime");
         app.insert_resource(tokio_rt)
             .init_resource::<SerialResource>()
             .add_event::<SerialData>()
-            .add_systems(PreUpdate, broadcast_serial_message);
+            .add_event::<TransactionResult>()
+            .add_event::<DeliveryFailed>()
+            .init_resource::<FileTransfers>()
+            .add_event::<TransferProgress>()
+            .add_event::<TransferResult>()
+            .add_systems(
+                self.receive_schedule,
+                (
+                    broadcast_serial_message,
+                    transfer::drive_transfers,
+                    #[cfg(feature = "stm32")]
+                    stm32::drive_stm32,
+                    #[cfg(feature = "esp")]
+                    esp::drive_esp,
+                    #[cfg(feature = "firmata")]
+                    firmata::receive_firmata,
+                )
+                    .chain()
+                    .in_set(SerialSet::Receive),
+            )
+            .add_systems(
+                self.flush_schedule,
+                flush_serial_messages.in_set(SerialSet::Flush),
+            )
+            .add_systems(Last, close_ports_on_exit(self.shutdown_timeout));
+        #[cfg(any(feature = "stm32", feature = "esp"))]
+        app.add_event::<BootloaderProgress>();
+        #[cfg(feature = "stm32")]
+        app.init_resource::<Stm32Bootloader>()
+            .add_event::<Stm32Result>();
+        #[cfg(feature = "esp")]
+        app.init_resource::<EspBootloader>()
+            .add_event::<EspResult>();
+        #[cfg(feature = "firmata")]
+        app.init_resource::<FirmataBoards>()
+            .add_event::<FirmataPinChanged>()
+            .add_event::<FirmataReceived>();
+        if self.receive_schedule == self.flush_schedule {
+            app.configure_sets(
+                self.receive_schedule,
+                (SerialSet::Receive, SerialSet::Flush).chain(),
+            );
+        }
     }
 }
 
+/// System sets of the serial port systems
+#[derive(SystemSet, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SerialSet {
+    /// Frames read from the ports are sent as [`SerialData`] events
+    Receive,
+    /// Messages queued with [`SerialResource::send_message`] are handed to the port writers
+    Flush,
+}
+
 #[derive(Debug, Event)]
 pub struct SerialData {
     pub port: String,
     pub data: Bytes,
+    /// Per-port sequence number, a gap means frames were dropped
+    pub sequence: u64,
+    /// When the reader task read the data
+    pub received_at: Instant,
+    /// Wall-clock read time, if [`SerialPortSetting::wall_clock_timestamps`] is enabled
+    pub received_at_wall: Option<SystemTime>,
 }
 
-#[derive(Resource, Deref, DerefMut)]
-pub struct SerialPortRuntime(Arc<tokio::runtime::Runtime>);
-pub type ArcRuntime = Arc<tokio::runtime::Runtime>;
-pub type RecvQueue = Arc<Mutex<Vec<Bytes>>>;
+pub type SendQueue = Arc<FrameQueue<Bytes>>;
+pub type RecvQueue = Arc<FrameQueue<SerialFrame>>;
 
 /// serial port resource
 #[derive(Default, Resource)]
@@ -49,7 +236,7 @@ pub struct SerialResource {
 impl SerialResource {
     pub fn open(
         &mut self,
-        task_pool: ArcRuntime,
+        task_pool: SerialPortRuntime,
         port: impl ToString,
         baud_rate: u32,
     ) -> Result<(), SerialError> {
@@ -67,7 +254,7 @@ impl SerialResource {
 
     pub fn open_with_setting(
         &mut self,
-        task_pool: ArcRuntime,
+        task_pool: SerialPortRuntime,
         setting: SerialPortSetting,
     ) -> Result<(), SerialError> {
         let port_name = setting.port_name.clone();
@@ -78,50 +265,1178 @@ impl SerialResource {
         Ok(())
     }
 
-    pub fn send_message(&mut self, port: &str, message: Bytes) {
-        if let Some(serial_wrap) = self.ports.get_mut(port) {
-            if let Err(err) = serial_wrap.msg_sender.lock().send(message) {
-                error!("send data to {} error {:?}", port, err)
+    /// Open an in-memory port driven by the returned [`MockPort`], for testing app logic
+    /// without a device
+    pub fn open_mock(&mut self, port: impl ToString) -> MockPort {
+        self.open_mock_with_setting(SerialPortSetting {
+            port_name: port.to_string(),
+            ..default()
+        })
+    }
+
+    pub fn open_mock_with_setting(&mut self, setting: SerialPortSetting) -> MockPort {
+        let port_name = setting.port_name.clone();
+        let (serial_port, mock) = SerialPortWrap::mock(setting);
+
+        self.ports.insert(port_name, serial_port);
+
+        mock
+    }
+
+    /// Open a virtual port reading what `port` read in a capture, e.g. one loaded with
+    /// [`read_capture`], to reproduce a session through the same [`SerialData`] pipeline
+    pub fn open_replay(
+        &mut self,
+        port: impl ToString,
+        records: Vec<CaptureRecord>,
+        timing: ReplayTiming,
+    ) -> Result<ReplayPort, SerialError> {
+        self.open_replay_with_setting(
+            SerialPortSetting {
+                port_name: port.to_string(),
+                ..default()
+            },
+            records,
+            timing,
+        )
+    }
+
+    pub fn open_replay_with_setting(
+        &mut self,
+        setting: SerialPortSetting,
+        records: Vec<CaptureRecord>,
+        timing: ReplayTiming,
+    ) -> Result<ReplayPort, SerialError> {
+        let port_name = setting.port_name.clone();
+        let (serial_port, replay) = SerialPortWrap::replay(setting, records, timing)?;
+
+        self.ports.insert(port_name, serial_port);
+
+        Ok(replay)
+    }
+
+    /// Send a request to `port` and wait for its reply, which comes back as a
+    /// [`TransactionResult`] event tagged with `id`.
+    ///
+    /// With [`SerialPortSetting::max_in_flight`] the request may be queued until earlier
+    /// transactions ended.
+    pub fn transact(
+        &mut self,
+        port: &str,
+        id: u64,
+        transaction: Transaction,
+    ) -> Result<(), SerialError> {
+        self.ports
+            .get_mut(port)
+            .ok_or_else(|| SerialError::PortNotFound(port.to_string()))?
+            .submit_transaction(transaction, ReplyTo::Event(id))
+    }
+
+    /// Send a request to `port` and wait for its reply as a future, e.g. from a task
+    pub fn transact_async(
+        &mut self,
+        port: &str,
+        transaction: Transaction,
+    ) -> Result<TransactionFuture, SerialError> {
+        let port_wrap = self
+            .ports
+            .get_mut(port)
+            .ok_or_else(|| SerialError::PortNotFound(port.to_string()))?;
+        let (reply_to, future) = ReplyTo::future(port);
+        port_wrap.submit_transaction(transaction, reply_to)?;
+        Ok(future)
+    }
+
+    /// Stop a transaction started with [`SerialResource::transact`], whether it is still queued
+    /// or awaiting its reply. No result is sent for it, `false` if there was no such transaction
+    pub fn cancel_transaction(&mut self, port: &str, id: u64) -> bool {
+        self.ports
+            .get_mut(port)
+            .is_some_and(|port_wrap| port_wrap.transactions.cancel(id))
+    }
+
+    /// Write out what is queued for `port` and close it, see [`SerialPortWrap::close`]
+    pub fn close(&mut self, port: &str, timeout: Duration) -> Result<(), SerialError> {
+        self.ports
+            .remove(port)
+            .ok_or_else(|| SerialError::PortNotFound(port.to_string()))?
+            .close(timeout)
+    }
+
+    /// Close every port, giving them `timeout` in total to write out what is queued
+    pub fn close_all(&mut self, timeout: Duration) -> Result<(), SerialError> {
+        for port_wrap in self.ports.values_mut() {
+            port_wrap.flush();
+        }
+        let deadline = Instant::now() + timeout;
+        let mut result = Ok(());
+        for (_, mut port_wrap) in std::mem::take(&mut self.ports) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if let Err(err) = port_wrap.close(remaining) {
+                result = Err(err);
             }
         }
+        result
+    }
+
+    /// Open channel `id` of `port` as a port of its own, named by [`channel_name`], e.g.
+    /// `COM1#console`.
+    ///
+    /// Frames of `port` starting with `id` are sent as [`SerialData`] of the channel without
+    /// that byte, and messages sent to the channel are prefixed with it. Frames no open channel
+    /// is tagged with stay with `port`. Needs a [`Framing`] that keeps frame boundaries.
+    /// Sequence numbers, statistics and transactions are those of `port` itself.
+    ///
+    /// Fails with [`SerialError::InvalidChannel`] if `channel` is empty or contains
+    /// [`CHANNEL_SEPARATOR`].
+    pub fn open_channel(
+        &mut self,
+        port: &str,
+        channel: &str,
+        id: u8,
+    ) -> Result<String, SerialError> {
+        if channel.is_empty() || channel.contains(CHANNEL_SEPARATOR) {
+            return Err(SerialError::InvalidChannel(
+                port.to_string(),
+                channel.to_string(),
+            ));
+        }
+        let name = channel_name(port, channel);
+        self.ports
+            .get_mut(port)
+            .ok_or_else(|| SerialError::PortNotFound(port.to_string()))?
+            .mux
+            .open(id, name.clone());
+        Ok(name)
+    }
+
+    /// Stop sending [`SerialData`] of a channel, `false` if it wasn't open
+    pub fn close_channel(&mut self, channel: &str) -> bool {
+        channel
+            .rsplit_once(CHANNEL_SEPARATOR)
+            .and_then(|(port, _)| self.ports.get_mut(port))
+            .is_some_and(|port_wrap| port_wrap.mux.close(channel))
+    }
+
+    /// The port a channel runs on and the channel's ID
+    fn channel(&mut self, channel: &str) -> Option<(&mut SerialPortWrap, u8)> {
+        let (port, _) = channel.rsplit_once(CHANNEL_SEPARATOR)?;
+        let port_wrap = self.ports.get_mut(port)?;
+        let id = port_wrap.mux.id(channel)?;
+        Some((port_wrap, id))
+    }
+
+    /// Traffic statistics of `port`
+    pub fn stats(&self, port: &str) -> Option<SerialPortStats> {
+        self.ports.get(port).map(SerialPortWrap::stats)
+    }
+
+    /// Queue a message to be written to `port` or a channel when [`SerialSet::Flush`] runs.
+    ///
+    /// Fails with [`SerialError::QueueFull`] when the port's send queue is full and its
+    /// policy is [`OverflowPolicy::Error`].
+    pub fn send_message(&mut self, port: &str, message: Bytes) -> Result<(), SerialError> {
+        if let Some(port_wrap) = self.ports.get_mut(port) {
+            return port_wrap.send(message);
+        }
+        let (port_wrap, id) = self
+            .channel(port)
+            .ok_or_else(|| SerialError::PortNotFound(port.to_string()))?;
+        port_wrap.send(Mux::tag(id, &message))
+    }
+
+    /// Set a modem line or change the baud rate of `port` right away, see
+    /// [`SerialPortWrap::control`]
+    pub fn control(&self, port: &str, control: PortControl) -> Result<(), SerialError> {
+        self.ports
+            .get(port)
+            .ok_or_else(|| SerialError::PortNotFound(port.to_string()))?
+            .control(control)
     }
 }
 
 fn broadcast_serial_message(
     mut serial_res: ResMut<SerialResource>,
     mut message_ev: EventWriter<SerialData>,
+    mut result_ev: EventWriter<TransactionResult>,
+    mut failed_ev: EventWriter<DeliveryFailed>,
 ) {
     let mut messages: Vec<SerialData> = Vec::new();
+    let mut results = Vec::new();
+    let mut failures = Vec::new();
 
     for (port_name, port_wrap) in serial_res.ports.iter_mut() {
-        let mut serial_messages: Vec<SerialData> = port_wrap
-            .get_messages()
-            .into_iter()
-            .map(|m| SerialData {
+        for m in port_wrap.get_messages() {
+            // taken by a running transfer
+            let Some(m) = port_wrap.divert(m) else {
+                continue;
+            };
+            if let Some((channel, data)) = port_wrap.mux.split(&m.data) {
+                messages.push(SerialData {
+                    port: channel.to_string(),
+                    data,
+                    sequence: m.sequence,
+                    received_at: m.received_at,
+                    received_at_wall: m.received_at_wall,
+                });
+                continue;
+            }
+            let data = SerialData {
                 port: port_name.clone(),
-                data: m,
-            })
-            .collect();
-
-        messages.append(&mut serial_messages);
+                data: m.data,
+                sequence: m.sequence,
+                received_at: m.received_at,
+                received_at_wall: m.received_at_wall,
+            };
+            // replies to transactions are only reported as their results
+            if let Some(data) = port_wrap.transactions.offer(data, &mut results) {
+                messages.push(data);
+            }
+        }
+        if !port_wrap.transactions.is_empty() {
+            port_wrap.update_transactions(&mut results);
+        }
+        port_wrap.update_reliable(&mut failures);
     }
 
     message_ev.send_batch(messages);
+    result_ev.send_batch(results);
+    failed_ev.send_batch(failures);
+}
+
+fn flush_serial_messages(mut serial_res: ResMut<SerialResource>) {
+    for port_wrap in serial_res.ports.values_mut() {
+        port_wrap.flush();
+    }
+}
+
+/// Close the ports and shut down the runtime once the app is exiting, so the last messages
+/// queued make it to the devices
+fn close_ports_on_exit(
+    timeout: Duration,
+) -> impl FnMut(EventReader<AppExit>, ResMut<SerialResource>, Commands) {
+    move |mut exit, mut serial_res, mut commands| {
+        if exit.read().next().is_none() {
+            return;
+        }
+        if let Err(err) = serial_res.close_all(timeout) {
+            error!("{}", err);
+        }
+        commands.add(move |world: &mut World| {
+            if let Some(runtime) = world.remove_resource::<SerialPortRuntime>() {
+                runtime.shutdown(timeout);
+            }
+        });
+    }
 }
 
 #[cfg(test)]
 mod unit_tests {
-    use bevy::prelude::{App, MinimalPlugins};
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll, Waker},
+        time::Duration,
+    };
+
+    use bevy::{
+        diagnostic::DiagnosticsStore,
+        prelude::{App, Events, FixedUpdate, MinimalPlugins},
+    };
+    use bytes::Bytes;
+    use parking_lot::Mutex;
 
-    use crate::SerialPortPlugin;
+    use crate::{
+        CaptureRecord, CaptureSink, DeliveryFailed, Direction, FaultConfig, FaultInjection,
+        FileTransfers, Framing, OverflowPolicy, QueueConfig, ReliableConfig, ReplayTiming,
+        Rs485Config, RuntimeConfig, SerialData, SerialDiagnosticsPlugin, SerialError,
+        SerialPortPlugin, SerialPortSetting, SerialResource, Transaction, TransactionResult,
+        TransferFile, TransferProgress, TransferProtocol, TransferResult,
+    };
+
+    #[cfg(feature = "esp")]
+    use crate::{EspBootloader, EspCommand, EspOptions, EspReply, EspResult};
+    #[cfg(feature = "firmata")]
+    use crate::{
+        FirmataBoards, FirmataClient, FirmataMessage, FirmataPinChanged, FirmataReceived, PinMode,
+    };
+    #[cfg(feature = "stm32")]
+    use crate::{
+        PortControl, Stm32Bootloader, Stm32Command, Stm32Options, Stm32Reply, Stm32Result,
+    };
+    #[cfg(feature = "firmata")]
+    use bevy::ecs::system::RunSystemOnce;
+
+    fn received(app: &mut App) -> Vec<Bytes> {
+        app.world_mut()
+            .resource_mut::<Events<SerialData>>()
+            .drain()
+            .map(|ev| ev.data)
+            .collect()
+    }
 
     /// This tests that we have properly set up the System parameters used in our systems, but
     /// doesn't test the 'real' functionality of the plugin.
     #[test]
     fn smoke_test_basic_integration_with_bevy_app() {
         let mut app = App::new();
-        app.add_plugins((MinimalPlugins, SerialPortPlugin));
+        app.add_plugins((
+            MinimalPlugins,
+            SerialPortPlugin::default(),
+            SerialDiagnosticsPlugin,
+        ));
         app.update();
         app.update();
     }
+
+    #[test]
+    fn smoke_test_fixed_update_schedule() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::in_schedule(FixedUpdate)));
+        app.update();
+        app.update();
+    }
+
+    #[test]
+    fn smoke_test_runtime_configs() {
+        #[cfg(feature = "tokio")]
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        #[cfg(feature = "tokio")]
+        let configs = [
+            RuntimeConfig::CurrentThread { thread_name: None },
+            RuntimeConfig::Handle(runtime.handle().clone()),
+        ];
+        #[cfg(not(feature = "tokio"))]
+        let configs = [RuntimeConfig::Threads, RuntimeConfig::IoTaskPool];
+
+        for config in configs {
+            let mut app = App::new();
+            app.add_plugins((
+                MinimalPlugins,
+                SerialPortPlugin::default().with_runtime(config),
+            ));
+            app.update();
+        }
+    }
+
+    #[test]
+    fn mock_port_round_trip() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock("mock");
+
+        mock.inject(b"ping").unwrap();
+        app.update();
+        assert_eq!(received(&mut app), vec![Bytes::from_static(b"ping")]);
+
+        app.world_mut()
+            .resource_mut::<SerialResource>()
+            .send_message("mock", Bytes::from_static(b"pong"))
+            .unwrap();
+        assert!(mock.written().is_empty());
+        app.update();
+        assert_eq!(mock.take_written(), vec![Bytes::from_static(b"pong")]);
+
+        mock.disconnect();
+        assert!(mock.inject(b"lost").is_err());
+        app.world_mut()
+            .resource_mut::<SerialResource>()
+            .send_message("mock", Bytes::from_static(b"lost"))
+            .unwrap();
+        app.update();
+        assert!(mock.written().is_empty());
+        let stats = app
+            .world()
+            .resource::<SerialResource>()
+            .stats("mock")
+            .unwrap();
+        assert_eq!((stats.read_errors, stats.write_errors), (1, 1));
+    }
+
+    #[test]
+    fn mock_port_overflow() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock_with_setting(SerialPortSetting {
+                port_name: "mock".to_string(),
+                recv_queue: QueueConfig::bounded(2, OverflowPolicy::DropOldest),
+                wall_clock_timestamps: true,
+                ..Default::default()
+            });
+        let drain = |app: &mut App| {
+            app.update();
+            app.world_mut()
+                .resource_mut::<Events<SerialData>>()
+                .drain()
+                .map(|ev| (ev.sequence, ev.received_at, ev.received_at_wall.unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        // the oldest three frames are dropped, leaving a gap in the sequence numbers
+        for i in 0..5u8 {
+            mock.inject([i]).unwrap();
+            mock.advance(Duration::from_millis(1));
+        }
+        let mut frames = drain(&mut app);
+        assert_eq!(frames.iter().map(|f| f.0).collect::<Vec<_>>(), [3, 4]);
+        mock.inject(b"next").unwrap();
+        frames.extend(drain(&mut app));
+        assert_eq!(frames.iter().map(|f| f.0).collect::<Vec<_>>(), [3, 4, 5]);
+        assert!(frames
+            .windows(2)
+            .all(|w| w[0].1 < w[1].1 && w[0].2 <= w[1].2));
+        let stats = app
+            .world()
+            .resource::<SerialResource>()
+            .stats("mock")
+            .unwrap();
+        assert_eq!(stats.dropped_received, 3);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            SerialPortPlugin::default(),
+            SerialDiagnosticsPlugin,
+        ));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock("dev/ttyUSB0");
+        app.world_mut()
+            .resource_mut::<SerialResource>()
+            .send_message("dev/ttyUSB0", Bytes::from_static(b"hi"))
+            .unwrap();
+        app.update();
+
+        mock.inject(b"hello").unwrap();
+        mock.disconnect();
+        mock.advance(Duration::from_secs(2));
+        app.update();
+        let store = app.world().resource::<DiagnosticsStore>();
+        let value = |metric| {
+            store
+                .get(&SerialDiagnosticsPlugin::path("dev/ttyUSB0", metric))
+                .and_then(|diagnostic| diagnostic.value())
+        };
+        assert_eq!(store.iter().count(), 11);
+        for (metric, expected) in [
+            (SerialDiagnosticsPlugin::RX_BYTES, 2.5),
+            (SerialDiagnosticsPlugin::TX_BYTES, 1.0),
+            (SerialDiagnosticsPlugin::RX_FRAMES, 0.5),
+            (SerialDiagnosticsPlugin::TX_FRAMES, 0.5),
+            (SerialDiagnosticsPlugin::READ_ERRORS, 1.0),
+            (SerialDiagnosticsPlugin::WRITE_ERRORS, 0.0),
+            (SerialDiagnosticsPlugin::DROPPED_RX, 0.0),
+            (SerialDiagnosticsPlugin::RECV_QUEUE, 0.0),
+            (SerialDiagnosticsPlugin::IDLE, 2.0),
+        ] {
+            assert_eq!(value(metric), Some(expected), "{metric}");
+        }
+        assert_eq!(
+            SerialDiagnosticsPlugin::path("dev/ttyUSB0", "rx_bytes").as_str(),
+            "serial/dev/ttyUSB0/rx_bytes"
+        );
+    }
+
+    #[test]
+    fn mock_port_with_faults() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock_with_setting(SerialPortSetting {
+                port_name: "mock".to_string(),
+                faults: FaultInjection {
+                    inbound: Some(FaultConfig {
+                        fragment: true,
+                        ..Default::default()
+                    }),
+                    outbound: Some(FaultConfig {
+                        latency: Duration::from_millis(20),
+                        ..Default::default()
+                    }),
+                },
+                ..Default::default()
+            });
+
+        mock.inject(b"abc").unwrap();
+        app.update();
+        assert_eq!(received(&mut app), [&b"a"[..], b"b", b"c"]);
+
+        app.world_mut()
+            .resource_mut::<SerialResource>()
+            .send_message("mock", Bytes::from_static(b"late"))
+            .unwrap();
+        app.update();
+        assert!(mock.written().is_empty());
+        mock.advance(Duration::from_millis(20));
+        app.update();
+        assert_eq!(mock.take_written(), vec![Bytes::from_static(b"late")]);
+    }
+
+    #[derive(Debug, Default)]
+    struct Recorded(Mutex<Vec<CaptureRecord>>);
+
+    impl CaptureSink for Recorded {
+        fn record(&self, record: &CaptureRecord) {
+            self.0.lock().push(record.clone());
+        }
+    }
+
+    #[test]
+    fn capture_and_replay() {
+        let recorded = Arc::new(Recorded::default());
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock_with_setting(SerialPortSetting {
+                port_name: "COM1".to_string(),
+                captures: vec![recorded.clone()],
+                ..Default::default()
+            });
+        mock.inject(b"hello").unwrap();
+        app.world_mut()
+            .resource_mut::<SerialResource>()
+            .send_message("COM1", Bytes::from_static(b"hi"))
+            .unwrap();
+        app.update();
+        mock.inject(b"bye").unwrap();
+
+        let records = recorded.0.lock().clone();
+        let directions: Vec<_> = records.iter().map(|r| r.direction).collect();
+        assert_eq!(directions, [Direction::Rx, Direction::Tx, Direction::Rx]);
+
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mut serial = app.world_mut().resource_mut::<SerialResource>();
+        for speed in [0.0, -2.0, f64::NAN] {
+            assert!(matches!(
+                serial.open_replay("COM1", records.clone(), ReplayTiming::Speed(speed)),
+                Err(SerialError::InvalidReplay(..))
+            ));
+        }
+        let replay = serial
+            .open_replay("COM1", records, ReplayTiming::Immediate)
+            .unwrap();
+        app.update();
+        assert_eq!(received(&mut app), [&b"hello"[..], b"bye"]);
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn transactions() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock("mock");
+        let results = |app: &mut App| -> Vec<TransactionResult> {
+            app.world_mut()
+                .resource_mut::<Events<TransactionResult>>()
+                .drain()
+                .collect()
+        };
+
+        app.world_mut()
+            .resource_mut::<SerialResource>()
+            .transact(
+                "mock",
+                7,
+                Transaction::new(Bytes::from_static(b"\x02?"))
+                    .matching_id(2, |reply| reply.first().map(|&id| id.into())),
+            )
+            .unwrap();
+        app.update();
+        assert_eq!(mock.take_written(), vec![Bytes::from_static(b"\x02?")]);
+        mock.inject(b"\x01other").unwrap();
+        mock.inject(b"\x02reply").unwrap();
+        app.update();
+        assert_eq!(received(&mut app), [&b"\x01other"[..]]);
+        let result = results(&mut app).pop().unwrap();
+        assert_eq!((result.port.as_str(), result.id), ("mock", 7));
+        assert_eq!(result.result.unwrap().data, &b"\x02reply"[..]);
+
+        app.world_mut()
+            .resource_mut::<SerialResource>()
+            .transact(
+                "mock",
+                8,
+                Transaction::new(Bytes::from_static(b"ping"))
+                    .with_timeout(Duration::from_millis(10))
+                    .with_retries(1),
+            )
+            .unwrap();
+        // the timeout only starts once the request is flushed
+        mock.advance(Duration::from_millis(50));
+        app.update();
+        mock.advance(Duration::from_millis(9));
+        app.update();
+        assert_eq!(mock.take_written().len(), 1);
+        mock.advance(Duration::from_millis(1));
+        app.update();
+        assert_eq!(mock.take_written().len(), 1);
+        assert!(results(&mut app).is_empty());
+        mock.advance(Duration::from_millis(10));
+        app.update();
+        let result = results(&mut app).pop().unwrap();
+        assert!(matches!(
+            result.result,
+            Err(SerialError::TransactionTimeout(port)) if port == "mock"
+        ));
+    }
+
+    #[test]
+    fn transaction_future() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock("mock");
+        let mut reply = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .transact_async("mock", Transaction::new(Bytes::from_static(b"ping")))
+            .unwrap();
+        app.update();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(Pin::new(&mut reply).poll(&mut cx).is_pending());
+        mock.inject(b"pong").unwrap();
+        app.update();
+        match Pin::new(&mut reply).poll(&mut cx) {
+            Poll::Ready(Ok(data)) => assert_eq!(data.data, &b"pong"[..]),
+            other => panic!("unexpected {other:?}"),
+        }
+        assert!(received(&mut app).is_empty());
+    }
+
+    #[test]
+    fn queued_transactions() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock_with_setting(SerialPortSetting {
+                port_name: "bus".to_string(),
+                max_in_flight: Some(1),
+                ..Default::default()
+            });
+        let mut serial_res = app.world_mut().resource_mut::<SerialResource>();
+        for (id, request, priority) in [(1, "first", 0), (2, "low", 0), (3, "urgent", 5)] {
+            let transaction = Transaction::new(Bytes::from(request)).with_priority(priority);
+            serial_res.transact("bus", id, transaction).unwrap();
+        }
+        assert!(serial_res.cancel_transaction("bus", 2));
+        assert!(!serial_res.cancel_transaction("bus", 2));
+
+        let mut completed = Vec::new();
+        for _ in 0..3 {
+            app.update();
+            if let Some(request) = mock.take_written().pop() {
+                mock.inject(request).unwrap();
+            }
+            completed.extend(
+                app.world_mut()
+                    .resource_mut::<Events<TransactionResult>>()
+                    .drain()
+                    .map(|result| result.id),
+            );
+        }
+        assert_eq!(completed, [1, 3]);
+    }
+
+    #[test]
+    fn rs485_echo_suppressed() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock_with_setting(SerialPortSetting {
+                port_name: "bus".to_string(),
+                rs485: Some(Rs485Config {
+                    suppress_echo: true,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+
+        app.world_mut()
+            .resource_mut::<SerialResource>()
+            .send_message("bus", Bytes::from_static(b"ping"))
+            .unwrap();
+        app.update();
+        mock.inject(mock.take_written_bytes()).unwrap();
+        mock.inject(b"pong").unwrap();
+        app.update();
+        assert_eq!(received(&mut app), [&b"pong"[..]]);
+    }
+
+    #[test]
+    fn reliable_delivery() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let reliable = |port: &str| SerialPortSetting {
+            port_name: port.to_string(),
+            framing: Framing::Cobs,
+            reliable: Some(ReliableConfig {
+                timeout: Duration::from_millis(20),
+                retries: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut serial_res = app.world_mut().resource_mut::<SerialResource>();
+        let host = serial_res.open_mock_with_setting(reliable("host"));
+        let device = serial_res.open_mock_with_setting(reliable("device"));
+
+        serial_res
+            .send_message("host", Bytes::from_static(b"config"))
+            .unwrap();
+        app.update();
+        // the first attempt is lost, the retransmission gets through
+        host.take_written();
+        host.advance(Duration::from_millis(20));
+        app.update();
+        app.update();
+        device.inject(host.take_written_bytes()).unwrap();
+        app.update();
+        assert_eq!(received(&mut app), [&b"config"[..]]);
+        host.inject(device.take_written_bytes()).unwrap();
+        app.update();
+        host.advance(Duration::from_millis(50));
+        app.update();
+        assert!(app
+            .world_mut()
+            .resource_mut::<Events<DeliveryFailed>>()
+            .is_empty());
+
+        // without an acknowledgement it fails after the retry
+        app.world_mut()
+            .resource_mut::<SerialResource>()
+            .send_message("host", Bytes::from_static(b"lost"))
+            .unwrap();
+        for _ in 0..3 {
+            app.update();
+            host.advance(Duration::from_millis(20));
+        }
+        app.update();
+        let failed: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<DeliveryFailed>>()
+            .drain()
+            .map(|ev| ev.data)
+            .collect();
+        assert_eq!(failed, [&b"lost"[..]]);
+    }
+
+    #[test]
+    fn channels() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mut serial_res = app.world_mut().resource_mut::<SerialResource>();
+        let mock = serial_res.open_mock_with_setting(SerialPortSetting {
+            port_name: "COM1".to_string(),
+            framing: Framing::Lines,
+            ..Default::default()
+        });
+        let console = serial_res.open_channel("COM1", "console", b'c').unwrap();
+        serial_res.open_channel("COM1", "control", b'#').unwrap();
+        assert_eq!(console, "COM1#console");
+        assert!(serial_res.open_channel("COM2", "console", 0).is_err());
+        for invalid in ["", "a#b"] {
+            assert!(matches!(
+                serial_res.open_channel("COM1", invalid, b'x'),
+                Err(SerialError::InvalidChannel(..))
+            ));
+        }
+
+        serial_res
+            .send_message("COM1#control", Bytes::from_static(b"reboot"))
+            .unwrap();
+        app.update();
+        assert_eq!(mock.take_written_bytes(), &b"#reboot\n"[..]);
+
+        mock.inject(b"cbooting\n#ok\nuntagged\n").unwrap();
+        app.update();
+        let data: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<SerialData>>()
+            .drain()
+            .map(|ev| (ev.port, ev.data))
+            .collect();
+        assert_eq!(
+            data,
+            [
+                ("COM1#console".to_string(), Bytes::from_static(b"booting")),
+                ("COM1#control".to_string(), Bytes::from_static(b"ok")),
+                ("COM1".to_string(), Bytes::from_static(b"untagged")),
+            ]
+        );
+
+        let mut serial_res = app.world_mut().resource_mut::<SerialResource>();
+        assert!(serial_res.close_channel("COM1#console"));
+        assert!(!serial_res.close_channel("COM1#console"));
+        assert!(serial_res
+            .send_message("COM1#console", Bytes::from_static(b"x"))
+            .is_err());
+    }
+
+    #[test]
+    fn file_transfer() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mut serial_res = app.world_mut().resource_mut::<SerialResource>();
+        let host = serial_res.open_mock("host");
+        let device = serial_res.open_mock("device");
+        let firmware = TransferFile::new("firmware.bin", vec![7u8; 5000]);
+        let mut transfers = app.world_mut().resource_mut::<FileTransfers>();
+        transfers.send("host", 1, TransferProtocol::Ymodem, vec![firmware.clone()]);
+        transfers.receive("device", 2, TransferProtocol::Ymodem);
+
+        app.update();
+        assert!(matches!(
+            app.world_mut()
+                .resource_mut::<SerialResource>()
+                .send_message("host", Bytes::from_static(b"hi")),
+            Err(SerialError::PortBusy(_))
+        ));
+        let mut results = Vec::new();
+        for _ in 0..100 {
+            app.update();
+            assert!(received(&mut app).is_empty());
+            device.inject(host.take_written_bytes()).unwrap();
+            host.inject(device.take_written_bytes()).unwrap();
+            results.extend(
+                app.world_mut()
+                    .resource_mut::<Events<TransferResult>>()
+                    .drain()
+                    .map(|ev| (ev.id, ev.result.unwrap())),
+            );
+            if results.len() == 2 {
+                break;
+            }
+        }
+        results.sort_by_key(|(id, _)| *id);
+        assert_eq!(results, [(1, vec![]), (2, vec![firmware])]);
+
+        // the ports are back to normal
+        host.inject(b"ready").unwrap();
+        app.update();
+        assert_eq!(received(&mut app), [&b"ready"[..]]);
+
+        let mut transfers = app.world_mut().resource_mut::<FileTransfers>();
+        transfers.receive("device", 3, TransferProtocol::XmodemCrc);
+        app.update();
+        assert_eq!(device.take_written_bytes(), &b"C"[..]);
+        assert!(app
+            .world_mut()
+            .resource_mut::<FileTransfers>()
+            .cancel("device"));
+        app.update();
+        assert_eq!(device.take_written_bytes(), &[0x18; 8][..]);
+        let result = app
+            .world_mut()
+            .resource_mut::<Events<TransferResult>>()
+            .drain()
+            .next()
+            .unwrap();
+        assert!(matches!(
+            result.result,
+            Err(SerialError::TransferCancelled(_))
+        ));
+    }
+
+    #[test]
+    fn transfer_needs_raw_port() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mut serial_res = app.world_mut().resource_mut::<SerialResource>();
+        serial_res.open_mock_with_setting(SerialPortSetting {
+            port_name: "lines".to_string(),
+            framing: Framing::Lines,
+            ..Default::default()
+        });
+        serial_res.open_mock_with_setting(SerialPortSetting {
+            port_name: "reliable".to_string(),
+            reliable: Some(ReliableConfig::default()),
+            ..Default::default()
+        });
+        let mut transfers = app.world_mut().resource_mut::<FileTransfers>();
+        transfers.receive("lines", 1, TransferProtocol::Xmodem);
+        transfers.receive("reliable", 2, TransferProtocol::Zmodem);
+
+        app.update();
+        let results: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<TransferResult>>()
+            .drain()
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|ev| matches!(ev.result, Err(SerialError::TransferFailed(..)))));
+        // the ports weren't claimed
+        assert!(app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .send_message("lines", Bytes::from_static(b"hi"))
+            .is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "stm32")]
+    fn stm32_bootloader() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let board = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock("board");
+        let options = Stm32Options {
+            enter: vec![
+                (PortControl::Dtr(true), Duration::ZERO),
+                (PortControl::Dtr(false), Duration::ZERO),
+            ],
+            ..Default::default()
+        };
+        app.world_mut().resource_mut::<Stm32Bootloader>().run(
+            "board",
+            1,
+            options,
+            vec![Stm32Command::GetId],
+        );
+
+        app.update();
+        assert_eq!(
+            board.take_controls(),
+            [PortControl::Dtr(true), PortControl::Dtr(false)]
+        );
+        for (written, reply) in [
+            (&[0x7F][..], &[0x79][..]),
+            (&[0x00, 0xFF], &[0x79, 1, 0x31, 0x02, 0x79]),
+            (&[0x02, 0xFD], &[0x79, 1, 0x04, 0x13, 0x79]),
+        ] {
+            assert_eq!(board.take_written_bytes(), written);
+            board.inject(reply).unwrap();
+            app.update();
+        }
+        let result = app
+            .world_mut()
+            .resource_mut::<Events<Stm32Result>>()
+            .drain()
+            .next()
+            .unwrap();
+        assert_eq!(result.result.unwrap(), [Stm32Reply::Id(0x0413)]);
+        assert!(!app.world().resource::<Stm32Bootloader>().is_active("board"));
+    }
+
+    #[test]
+    #[cfg(feature = "esp")]
+    fn esp_bootloader() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let board = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock("board");
+        app.world_mut().resource_mut::<EspBootloader>().run(
+            "board",
+            1,
+            EspOptions::default(),
+            vec![EspCommand::ReadRegister(0x3FF0_0050)],
+        );
+
+        app.update();
+        let sync = board.take_written_bytes();
+        assert_eq!(sync[..3], [0xC0, 0x00, 0x08]);
+        assert_eq!(sync.len(), 46);
+        // the boot log comes before the replies of an ESP8266
+        board.inject(&b"waiting for download\r\n"[..]).unwrap();
+        board
+            .inject(&[0xC0, 0x01, 0x08, 2, 0, 0, 0, 0, 0, 0, 0, 0xC0][..])
+            .unwrap();
+        app.update();
+        let detect = [
+            0xC0, 0x00, 0x0A, 4, 0, 0, 0, 0, 0, 0x00, 0x10, 0x00, 0x40, 0xC0,
+        ];
+        assert!(board.take_written_bytes().ends_with(&detect));
+        board
+            .inject(&[0xC0, 0x01, 0x0A, 2, 0, 0x01, 0xC1, 0xF0, 0xFF, 0, 0, 0xC0][..])
+            .unwrap();
+        app.update();
+        let read = [
+            0xC0, 0x00, 0x0A, 4, 0, 0, 0, 0, 0, 0x50, 0x00, 0xF0, 0x3F, 0xC0,
+        ];
+        assert_eq!(board.take_written_bytes(), &read[..]);
+        board
+            .inject(&[0xC0, 0x01, 0x0A, 2, 0, 0x78, 0x56, 0x34, 0x12, 0, 0, 0xC0][..])
+            .unwrap();
+        app.update();
+        let result = app
+            .world_mut()
+            .resource_mut::<Events<EspResult>>()
+            .drain()
+            .next()
+            .unwrap();
+        assert_eq!(result.result.unwrap(), [EspReply::Register(0x1234_5678)]);
+        assert!(!app.world().resource::<EspBootloader>().is_active("board"));
+    }
+
+    #[test]
+    #[cfg(feature = "firmata")]
+    fn firmata_board() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let uno = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock("uno");
+        app.world_mut()
+            .run_system_once(|mut firmata: FirmataClient| {
+                firmata.attach("uno").unwrap();
+                assert!(matches!(
+                    firmata.digital_write("mega", 13, true),
+                    Err(SerialError::NoFirmataBoard(_))
+                ));
+            });
+        app.update();
+        assert_eq!(
+            uno.take_written_bytes(),
+            &[0xF9, 0xF0, 0x79, 0xF7, 0xF0, 0x6B, 0xF7, 0xF0, 0x69, 0xF7][..]
+        );
+
+        // version, and pin 2 as an analog input on channel 0
+        uno.inject([
+            0xF9, 2, 5, 0xF0, 0x6C, 0x7F, 0x7F, 0x00, 1, 0x02, 10, 0x7F, 0xF7,
+        ])
+        .unwrap();
+        uno.inject([0xF0, 0x6A, 0x7F, 0x7F, 0x00, 0xF7]).unwrap();
+        app.update();
+        app.world_mut()
+            .run_system_once(|mut firmata: FirmataClient| {
+                firmata.set_pin_mode("uno", 1, PinMode::Output).unwrap();
+                firmata.digital_write("uno", 1, true).unwrap();
+                firmata.report_analog("uno", 0, true).unwrap();
+            });
+        app.update();
+        assert_eq!(
+            uno.take_written_bytes(),
+            &[0xF4, 1, 0x01, 0x90, 0x02, 0x00, 0xC0, 1][..]
+        );
+        let messages: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<FirmataReceived>>()
+            .drain()
+            .map(|ev| ev.message)
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                FirmataMessage::Version { major: 2, minor: 5 },
+                FirmataMessage::Capabilities,
+                FirmataMessage::AnalogMapping
+            ]
+        );
+
+        uno.inject([0xE0, 0x10, 0x04]).unwrap();
+        app.update();
+        let changed: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<FirmataPinChanged>>()
+            .drain()
+            .collect();
+        assert_eq!(
+            changed,
+            [FirmataPinChanged {
+                port: "uno".to_string(),
+                pin: 2,
+                value: 528,
+                previous: 0
+            }]
+        );
+        let boards = app.world().resource::<FirmataBoards>();
+        let board = boards.get("uno").unwrap();
+        assert_eq!(board.protocol_version, Some((2, 5)));
+        assert_eq!(board.pin(1).unwrap().value, 1);
+        assert_eq!(board.pin(2).unwrap().value, 528);
+    }
+
+    #[test]
+    fn zmodem_resume() {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, SerialPortPlugin::default()));
+        let mut serial_res = app.world_mut().resource_mut::<SerialResource>();
+        let host = serial_res.open_mock("host");
+        let device = serial_res.open_mock("device");
+        let log: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        let log = TransferFile::new("console.log", log);
+
+        let run = |app: &mut App, cancel_at: Option<u64>| {
+            let mut results = Vec::new();
+            let mut progress = Vec::new();
+            for _ in 0..200 {
+                app.update();
+                device.inject(host.take_written_bytes()).unwrap();
+                host.inject(device.take_written_bytes()).unwrap();
+                let world = app.world_mut();
+                progress.extend(
+                    world
+                        .resource_mut::<Events<TransferProgress>>()
+                        .drain()
+                        .filter(|ev| ev.port == "device")
+                        .map(|ev| ev.transferred),
+                );
+                if cancel_at.is_some_and(|at| progress.last() >= Some(&at)) {
+                    world.resource_mut::<FileTransfers>().cancel("device");
+                }
+                results.extend(world.resource_mut::<Events<TransferResult>>().drain());
+                if results.len() == 2 {
+                    results.sort_by_key(|ev| ev.id);
+                    return (results, progress);
+                }
+            }
+            panic!("the transfer did not end");
+        };
+
+        let mut transfers = app.world_mut().resource_mut::<FileTransfers>();
+        transfers.send("host", 1, TransferProtocol::Zmodem, vec![log.clone()]);
+        transfers.receive("device", 2, TransferProtocol::Zmodem);
+        let (results, _) = run(&mut app, Some(20_000));
+        assert!(matches!(
+            results[0].result,
+            Err(SerialError::TransferFailed(_, _))
+        ));
+        assert!(matches!(
+            results[1].result,
+            Err(SerialError::TransferCancelled(_))
+        ));
+        let partial = results[1].partial.clone();
+        assert_eq!(partial.len(), 1);
+        assert!(log.data.starts_with(&partial[0].data));
+
+        let mut transfers = app.world_mut().resource_mut::<FileTransfers>();
+        transfers.send("host", 3, TransferProtocol::Zmodem, vec![log.clone()]);
+        transfers.resume("device", 4, partial.clone());
+        let (results, progress) = run(&mut app, None);
+        assert_eq!(results[0].result.as_ref().unwrap(), &[]);
+        assert_eq!(results[1].result.as_ref().unwrap(), &[log]);
+        assert_eq!(progress[0], partial[0].data.len() as u64);
+    }
 }
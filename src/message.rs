@@ -0,0 +1,404 @@
+//! Typed messages: serde types read from and written to ports in a binary format.
+//!
+//! Each frame is one message, so ports carrying them need a [`Framing`](crate::Framing) that
+//! keeps message boundaries, like [`Framing::Cobs`](crate::Framing::Cobs). JSON Lines is
+//! [`MessageFormat::Json`] on a port with [`Framing::Lines`](crate::Framing::Lines), with
+//! `serde_json::Value` as the message type for untyped use.
+
+use std::{collections::HashMap, marker::PhantomData, time::Instant};
+
+use bevy::{
+    ecs::{
+        schedule::{InternedScheduleLabel, ScheduleLabel},
+        system::SystemParam,
+    },
+    prelude::*,
+};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{broadcast_serial_message, SerialData, SerialError, SerialResource, SerialSet};
+
+/// Serialization format of typed messages, each enabled by the crate feature of its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    #[cfg(feature = "postcard")]
+    Postcard,
+    #[cfg(feature = "bincode")]
+    Bincode,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "json")]
+    Json,
+}
+
+impl MessageFormat {
+    pub fn encode<T: Serialize>(self, message: &T) -> Result<Vec<u8>, String> {
+        match self {
+            #[cfg(feature = "postcard")]
+            MessageFormat::Postcard => postcard::to_allocvec(message).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            MessageFormat::Bincode => bincode::serialize(message).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            MessageFormat::MessagePack => {
+                rmp_serde::to_vec_named(message).map_err(|e| e.to_string())
+            }
+            #[cfg(feature = "cbor")]
+            MessageFormat::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(message, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(bytes)
+            }
+            #[cfg(feature = "json")]
+            MessageFormat::Json => serde_json::to_vec(message).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            #[cfg(feature = "postcard")]
+            MessageFormat::Postcard => postcard::from_bytes(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            MessageFormat::Bincode => bincode::deserialize(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            MessageFormat::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "cbor")]
+            MessageFormat::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "json")]
+            MessageFormat::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// A message read from a port bound to `T` with [`SerialMessages::bind`]
+#[derive(Debug, Event)]
+pub struct SerialMessage<T> {
+    pub port: String,
+    pub message: T,
+    /// Sequence number of the frame the message was read from
+    pub sequence: u64,
+    pub received_at: Instant,
+}
+
+/// A frame read from a port bound to a message type that could not be decoded as one
+#[derive(Debug, Clone, Event)]
+pub struct SerialDecodeError {
+    pub port: String,
+    pub data: Bytes,
+    pub error: String,
+}
+
+impl SerialDecodeError {
+    /// The frame as text, for line based formats
+    pub fn text(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.data)
+    }
+}
+
+/// The ports carrying messages of type `T`, and their formats
+#[derive(Resource)]
+pub struct MessagePorts<T> {
+    formats: HashMap<String, MessageFormat>,
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for MessagePorts<T> {
+    fn default() -> Self {
+        Self {
+            formats: HashMap::new(),
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<T> MessagePorts<T> {
+    pub fn format(&self, port: &str) -> Option<MessageFormat> {
+        self.formats.get(port).copied()
+    }
+}
+
+/// Sends [`SerialMessage<T>`] events for the ports bound to `T`
+pub struct SerialMessagePlugin<T> {
+    /// Schedule of [`SerialPortPlugin::receive_schedule`](crate::SerialPortPlugin::receive_schedule)
+    pub receive_schedule: InternedScheduleLabel,
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for SerialMessagePlugin<T> {
+    fn default() -> Self {
+        Self {
+            receive_schedule: PreUpdate.intern(),
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<T> SerialMessagePlugin<T> {
+    /// For a [`SerialPortPlugin::in_schedule`](crate::SerialPortPlugin::in_schedule) schedule
+    pub fn in_schedule(schedule: impl ScheduleLabel) -> Self {
+        Self {
+            receive_schedule: schedule.intern(),
+            _message: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> Plugin for SerialMessagePlugin<T> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MessagePorts<T>>()
+            .add_event::<SerialMessage<T>>()
+            .add_event::<SerialDecodeError>()
+            .add_systems(
+                self.receive_schedule,
+                decode_serial_messages::<T>
+                    .in_set(SerialSet::Receive)
+                    .after(broadcast_serial_message),
+            );
+    }
+}
+
+/// Typed access to the ports carrying messages of type `T`.
+///
+/// Holds [`SerialResource`] mutably, use [`SerialMessages::serial`] for the untyped API in the
+/// same system.
+#[derive(SystemParam)]
+pub struct SerialMessages<'w, T: Send + Sync + 'static> {
+    serial: ResMut<'w, SerialResource>,
+    ports: ResMut<'w, MessagePorts<T>>,
+}
+
+impl<'w, T: Serialize + Send + Sync + 'static> SerialMessages<'w, T> {
+    /// Read and write messages of type `T` on an open port
+    pub fn bind(&mut self, port: impl ToString, format: MessageFormat) {
+        self.ports.formats.insert(port.to_string(), format);
+    }
+
+    pub fn unbind(&mut self, port: &str) {
+        self.ports.formats.remove(port);
+    }
+
+    /// Serialize a message and queue it like [`SerialResource::send_message`]
+    pub fn send(&mut self, port: &str, message: &T) -> Result<(), SerialError> {
+        let format = self
+            .ports
+            .format(port)
+            .ok_or_else(|| SerialError::NoMessageFormat(port.to_string()))?;
+        let bytes = format
+            .encode(message)
+            .map_err(|err| SerialError::Serialize(port.to_string(), err))?;
+        self.serial.send_message(port, bytes.into())
+    }
+
+    pub fn serial(&mut self) -> &mut SerialResource {
+        &mut self.serial
+    }
+}
+
+fn decode_serial_messages<T: DeserializeOwned + Send + Sync + 'static>(
+    ports: Res<MessagePorts<T>>,
+    mut data_ev: EventReader<SerialData>,
+    mut message_ev: EventWriter<SerialMessage<T>>,
+    mut error_ev: EventWriter<SerialDecodeError>,
+) {
+    for data in data_ev.read() {
+        let Some(format) = ports.format(&data.port) else {
+            continue;
+        };
+        match format.decode(&data.data) {
+            Ok(message) => {
+                message_ev.send(SerialMessage {
+                    port: data.port.clone(),
+                    message,
+                    sequence: data.sequence,
+                    received_at: data.received_at,
+                });
+            }
+            Err(error) => {
+                error_ev.send(SerialDecodeError {
+                    port: data.port.clone(),
+                    data: data.data.clone(),
+                    error,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "postcard"))]
+mod tests {
+    use bevy::{ecs::system::RunSystemOnce, prelude::*};
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{codec::Framing, SerialPortPlugin, SerialPortSetting};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Telemetry {
+        Position { x: i32, y: i32 },
+        Battery(u8),
+    }
+
+    #[test]
+    fn typed_round_trip() {
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            SerialPortPlugin::default(),
+            SerialMessagePlugin::<Telemetry>::default(),
+        ));
+        let mock = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock_with_setting(SerialPortSetting {
+                port_name: "robot".to_string(),
+                framing: Framing::Cobs,
+                ..Default::default()
+            });
+        app.world_mut()
+            .run_system_once(|mut messages: SerialMessages<Telemetry>| {
+                messages.bind("robot", MessageFormat::Postcard);
+                messages
+                    .send("robot", &Telemetry::Position { x: -3, y: 4 })
+                    .unwrap();
+                assert!(messages.send("other", &Telemetry::Battery(1)).is_err());
+            });
+        app.update();
+
+        // the device echoes what it got, then sends garbage
+        mock.inject(mock.take_written_bytes()).unwrap();
+        mock.inject(b"\x02\xff\x00").unwrap();
+        app.update();
+        let messages: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<SerialMessage<Telemetry>>>()
+            .drain()
+            .map(|ev| ev.message)
+            .collect();
+        assert_eq!(messages, [Telemetry::Position { x: -3, y: 4 }]);
+        let errors: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<SerialDecodeError>>()
+            .drain()
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].data, &b"\xff"[..]);
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let formats = [
+            MessageFormat::Postcard,
+            #[cfg(feature = "bincode")]
+            MessageFormat::Bincode,
+            #[cfg(feature = "msgpack")]
+            MessageFormat::MessagePack,
+            #[cfg(feature = "cbor")]
+            MessageFormat::Cbor,
+            #[cfg(feature = "json")]
+            MessageFormat::Json,
+        ];
+        for format in formats {
+            let bytes = format.encode(&Telemetry::Battery(87)).unwrap();
+            assert_eq!(
+                format.decode::<Telemetry>(&bytes).unwrap(),
+                Telemetry::Battery(87)
+            );
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod json_lines_tests {
+    use bevy::{ecs::system::RunSystemOnce, prelude::*};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{codec::Framing, SerialPortPlugin, SerialPortSetting};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        value: f64,
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            SerialPortPlugin::default(),
+            SerialMessagePlugin::<Reading>::default(),
+            SerialMessagePlugin::<Value>::default(),
+        ));
+        let board = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock_with_setting(SerialPortSetting {
+                port_name: "board".to_string(),
+                framing: Framing::Lines,
+                ..Default::default()
+            });
+        let fixture = app
+            .world_mut()
+            .resource_mut::<SerialResource>()
+            .open_mock_with_setting(SerialPortSetting {
+                port_name: "fixture".to_string(),
+                framing: Framing::Lines,
+                ..Default::default()
+            });
+        app.world_mut()
+            .run_system_once(|mut values: SerialMessages<Value>| {
+                values.bind("fixture", MessageFormat::Json);
+            });
+        app.world_mut()
+            .run_system_once(|mut readings: SerialMessages<Reading>| {
+                readings.bind("board", MessageFormat::Json);
+                let reading = Reading {
+                    sensor: "t0".to_string(),
+                    value: 21.5,
+                };
+                readings.send("board", &reading).unwrap();
+            });
+        app.update();
+        assert_eq!(
+            board.take_written_bytes(),
+            &b"{\"sensor\":\"t0\",\"value\":21.5}\n"[..]
+        );
+
+        board
+            .inject(b"{\"sensor\": \"t1\", \"value\": 3}\r\nTraceback (most recent call last):\r\n")
+            .unwrap();
+        fixture.inject(b"{\"ready\": true}\n").unwrap();
+        app.update();
+        let readings: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<SerialMessage<Reading>>>()
+            .drain()
+            .map(|ev| ev.message)
+            .collect();
+        assert_eq!(
+            readings,
+            [Reading {
+                sensor: "t1".to_string(),
+                value: 3.0
+            }]
+        );
+        let values: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<SerialMessage<Value>>>()
+            .drain()
+            .map(|ev| ev.message)
+            .collect();
+        assert_eq!(values, [json!({ "ready": true })]);
+        let errors: Vec<_> = app
+            .world_mut()
+            .resource_mut::<Events<SerialDecodeError>>()
+            .drain()
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].text(), "Traceback (most recent call last):");
+    }
+}
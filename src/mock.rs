@@ -0,0 +1,201 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+
+use crate::{
+    backend::{PortIo, VirtualDriver},
+    clock::Clock,
+    pipeline::{Inbound, Outbound},
+    PortControl, PushError, RecvQueue, SendQueue, SerialError, SerialFrame,
+};
+
+/// Test side of an in-memory port opened with
+/// [`SerialResource::open_mock`](crate::SerialResource::open_mock).
+///
+/// Bytes injected here go through the port's codec and arrive as
+/// [`SerialData`](crate::SerialData) on the next [`SerialSet::Receive`](crate::SerialSet), and
+/// messages sent to the port are recorded here when [`SerialSet::Flush`](crate::SerialSet)
+/// runs, so app logic can be tested frame by frame without threads or devices.
+///
+/// With [`SerialPortSetting::faults`](crate::SerialPortSetting::faults) the faulty bytes are
+/// what arrives and what is recorded, delayed data shows up on the first receive or flush after
+/// it is due.
+///
+/// Time stands still on a mock port: frames are stamped, faults are delayed and transactions
+/// and reliable delivery time out by a clock that only moves with [`MockPort::advance`].
+#[derive(Clone)]
+pub struct MockPort {
+    shared: Arc<MockShared>,
+}
+
+struct MockShared {
+    port_name: String,
+    send_queue: SendQueue,
+    recv_queue: RecvQueue,
+    inbound: Mutex<Inbound>,
+    outbound: Mutex<Outbound>,
+    written: Mutex<Vec<Bytes>>,
+    /// writes delayed by faults, in the order they are due
+    delayed_writes: Mutex<VecDeque<(Instant, Bytes)>>,
+    controls: Mutex<Vec<PortControl>>,
+    connected: AtomicBool,
+    clock: Clock,
+}
+
+impl MockPort {
+    pub(crate) fn new(io: PortIo) -> Self {
+        Self {
+            shared: Arc::new(MockShared {
+                port_name: io.port_name,
+                send_queue: io.send_queue,
+                recv_queue: io.recv_queue,
+                inbound: Mutex::new(io.inbound),
+                outbound: Mutex::new(io.outbound),
+                written: Mutex::new(Vec::new()),
+                delayed_writes: Mutex::new(VecDeque::new()),
+                controls: Mutex::new(Vec::new()),
+                connected: AtomicBool::new(true),
+                clock: io.clock,
+            }),
+        }
+    }
+
+    pub(crate) fn driver(&self) -> Arc<dyn VirtualDriver> {
+        self.shared.clone()
+    }
+
+    pub fn port_name(&self) -> &str {
+        &self.shared.port_name
+    }
+
+    /// Bytes sent by the simulated device, as if read from the port.
+    ///
+    /// Blocks if the receive queue is full and its policy is
+    /// [`OverflowPolicy::Block`](crate::OverflowPolicy::Block).
+    pub fn inject(&self, bytes: impl AsRef<[u8]>) -> Result<(), SerialError> {
+        let shared = &self.shared;
+        if !self.is_connected() {
+            return Err(SerialError::PortClosed(shared.port_name.clone()));
+        }
+        let frames = {
+            let mut inbound = shared.inbound.lock();
+            inbound
+                .feed(bytes.as_ref())
+                .inspect_err(|_| inbound.read_failed())?
+        };
+        shared.deliver(frames)
+    }
+
+    /// Every write made to the port so far, one entry per message, or per chunk with faults
+    pub fn written(&self) -> Vec<Bytes> {
+        self.shared.written.lock().clone()
+    }
+
+    /// Take the writes made to the port so far
+    pub fn take_written(&self) -> Vec<Bytes> {
+        std::mem::take(&mut *self.shared.written.lock())
+    }
+
+    /// Take the writes made to the port so far as one stream of bytes
+    pub fn take_written_bytes(&self) -> Bytes {
+        let mut bytes = BytesMut::new();
+        for write in self.take_written() {
+            bytes.extend_from_slice(&write);
+        }
+        bytes.freeze()
+    }
+
+    /// Take the modem line and baud rate changes made with
+    /// [`SerialPortWrap::control`](crate::SerialPortWrap::control) so far
+    pub fn take_controls(&self) -> Vec<PortControl> {
+        std::mem::take(&mut *self.shared.controls.lock())
+    }
+
+    /// Simulate the device going away: reading fails once, injecting is refused and every
+    /// write fails until [`MockPort::reconnect`]
+    pub fn disconnect(&self) {
+        if self.shared.connected.swap(false, Ordering::AcqRel) {
+            self.shared.inbound.lock().read_failed();
+        }
+    }
+
+    pub fn reconnect(&self) {
+        self.shared.connected.store(true, Ordering::Release);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.shared.connected.load(Ordering::Acquire)
+    }
+
+    /// Move the port's clock forward, delayed data becomes due on the next receive or flush
+    pub fn advance(&self, by: Duration) {
+        self.shared.clock.advance(by);
+    }
+}
+
+impl MockShared {
+    fn deliver(&self, frames: Vec<SerialFrame>) -> Result<(), SerialError> {
+        for frame in frames {
+            match self.recv_queue.push(frame) {
+                Ok(()) | Err(PushError::Full) => {}
+                Err(PushError::Closed) => {
+                    return Err(SerialError::PortClosed(self.port_name.clone()))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the delayed writes that are due
+    fn write_due(&self) {
+        let now = self.clock.now();
+        let mut delayed = self.delayed_writes.lock();
+        while delayed.front().is_some_and(|(due, _)| *due <= now) {
+            let (_, bytes) = delayed.pop_front().unwrap();
+            self.written.lock().push(bytes);
+        }
+    }
+}
+
+impl VirtualDriver for MockShared {
+    fn flush(&self) {
+        let mut outbound = self.outbound.lock();
+        while let Some(message) = self.send_queue.try_pop() {
+            if !self.connected.load(Ordering::Acquire) {
+                outbound.write_failed();
+                continue;
+            }
+            match outbound.encode(message) {
+                Ok(chunks) => {
+                    outbound.written(chunks.iter().map(|(_, bytes)| bytes.len()).sum());
+                    self.delayed_writes.lock().extend(chunks);
+                }
+                Err(_) => outbound.write_failed(),
+            }
+        }
+        self.write_due();
+    }
+
+    fn poll(&self) {
+        self.write_due();
+        let frames = {
+            let mut inbound = self.inbound.lock();
+            inbound.poll().inspect_err(|_| inbound.read_failed())
+        };
+        if let Ok(frames) = frames {
+            let _ = self.deliver(frames);
+        }
+    }
+
+    fn control(&self, control: PortControl) {
+        self.controls.lock().push(control);
+    }
+}
@@ -0,0 +1,54 @@
+//! Logical channels: independent streams sharing one framed port, each frame tagged with the ID
+//! of its channel in its first byte.
+
+use std::collections::BTreeMap;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Separates the port from the channel in the name of a channel, as in `COM1#console`
+pub const CHANNEL_SEPARATOR: char = '#';
+
+/// Name a channel of `port` is known by in [`SerialResource`](crate::SerialResource)
+pub fn channel_name(port: &str, channel: &str) -> String {
+    format!("{port}{CHANNEL_SEPARATOR}{channel}")
+}
+
+/// The open channels of a port
+#[derive(Default)]
+pub(crate) struct Mux {
+    /// name of each channel by ID
+    channels: BTreeMap<u8, String>,
+}
+
+impl Mux {
+    /// Open channel `id` under `name`, replacing whatever had the name or the ID
+    pub(crate) fn open(&mut self, id: u8, name: String) {
+        self.channels.retain(|_, open| *open != name);
+        self.channels.insert(id, name);
+    }
+
+    pub(crate) fn close(&mut self, name: &str) -> bool {
+        let before = self.channels.len();
+        self.channels.retain(|_, open| open != name);
+        self.channels.len() != before
+    }
+
+    pub(crate) fn id(&self, name: &str) -> Option<u8> {
+        self.channels
+            .iter()
+            .find_map(|(&id, open)| (open == name).then_some(id))
+    }
+
+    pub(crate) fn tag(id: u8, message: &[u8]) -> Bytes {
+        let mut frame = BytesMut::with_capacity(message.len() + 1);
+        frame.put_u8(id);
+        frame.put_slice(message);
+        frame.freeze()
+    }
+
+    /// The channel a frame belongs to and its message, `None` if no open channel has its tag
+    pub(crate) fn split(&self, frame: &Bytes) -> Option<(&str, Bytes)> {
+        let name = self.channels.get(frame.first()?)?;
+        Some((name, frame.slice(1..)))
+    }
+}
@@ -0,0 +1,232 @@
+//! Captures in the pcapng format, to open serial traffic in Wireshark.
+//!
+//! Each port becomes an interface named after it, each chunk a packet whose flags tell whether
+//! it was read or written. Packets use a user link type, which Wireshark can map to any
+//! dissector under *Preferences → Protocols → DLT_USER*.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use parking_lot::Mutex;
+
+use crate::capture::{CaptureRecord, CaptureSink, Direction};
+
+/// `DLT_USER0`, the first of the link types reserved for private use
+pub const LINKTYPE_USER0: u16 = 147;
+
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+
+const OPT_END: u16 = 0;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+const EPB_INBOUND: u32 = 0b01;
+const EPB_OUTBOUND: u32 = 0b10;
+
+/// Writes records as a pcapng capture
+pub struct PcapngWriter {
+    link_type: u16,
+    state: Mutex<State>,
+}
+
+struct State {
+    out: Box<dyn Write + Send>,
+    header_written: bool,
+    /// interface id of each port seen so far
+    interfaces: HashMap<String, u32>,
+}
+
+impl PcapngWriter {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            link_type: LINKTYPE_USER0,
+            state: Mutex::new(State {
+                out: Box::new(out),
+                header_written: false,
+                interfaces: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Link type of the packets, [`LINKTYPE_USER0`] by default
+    pub fn with_link_type(mut self, link_type: u16) -> Self {
+        self.link_type = link_type;
+        self
+    }
+
+    fn write(&self, record: &CaptureRecord) -> io::Result<()> {
+        let mut state = self.state.lock();
+        if !state.header_written {
+            let mut body = Vec::new();
+            body.extend_from_slice(&0x1A2B_3C4D_u32.to_le_bytes());
+            body.extend_from_slice(&1u16.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            // section length unknown
+            body.extend_from_slice(&(-1i64).to_le_bytes());
+            write_block(&mut state.out, SECTION_HEADER, &body)?;
+            state.header_written = true;
+        }
+
+        let interface = match state.interfaces.get(&record.port) {
+            Some(&interface) => interface,
+            None => {
+                let mut body = Vec::new();
+                body.extend_from_slice(&self.link_type.to_le_bytes());
+                body.extend_from_slice(&0u16.to_le_bytes());
+                // no snap length limit
+                body.extend_from_slice(&0u32.to_le_bytes());
+                push_option(&mut body, IF_NAME, record.port.as_bytes());
+                push_option(&mut body, OPT_END, &[]);
+                write_block(&mut state.out, INTERFACE_DESCRIPTION, &body)?;
+                let interface = state.interfaces.len() as u32;
+                state.interfaces.insert(record.port.clone(), interface);
+                interface
+            }
+        };
+
+        // microseconds, the default resolution
+        let timestamp = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let flags = match record.direction {
+            Direction::Rx => EPB_INBOUND,
+            Direction::Tx => EPB_OUTBOUND,
+        };
+        let mut body = Vec::with_capacity(32 + record.data.len());
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        body.extend_from_slice(&(record.data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(record.data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&record.data);
+        pad(&mut body);
+        push_option(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut body, OPT_END, &[]);
+        write_block(&mut state.out, ENHANCED_PACKET, &body)?;
+
+        // flushed right away so a crash keeps everything up to it
+        state.out.flush()
+    }
+}
+
+impl fmt::Debug for PcapngWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PcapngWriter")
+            .field("link_type", &self.link_type)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CaptureSink for PcapngWriter {
+    fn record(&self, record: &CaptureRecord) {
+        if let Err(err) = self.write(record) {
+            bevy::log::error!("writing pcapng capture error {:?}", err);
+        }
+    }
+}
+
+fn write_block(out: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let len = (12 + body.len()) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&len.to_le_bytes())
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+/// Pad to a multiple of 32 bits
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn blocks() {
+        let out = Shared::default();
+        let writer = PcapngWriter::new(out.clone());
+        for (port, direction) in [
+            ("COM1", Direction::Rx),
+            ("COM2", Direction::Tx),
+            ("COM1", Direction::Tx),
+        ] {
+            writer.record(&CaptureRecord {
+                timestamp: UNIX_EPOCH + Duration::from_micros(0x1_0000_0002),
+                direction,
+                port: port.to_string(),
+                data: Bytes::from_static(b"hello"),
+            });
+        }
+
+        let bytes = out.0.lock().clone();
+        let mut blocks = Vec::new();
+        let mut at = 0;
+        while at < bytes.len() {
+            let len = u32_at(&bytes, at + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(&bytes, at + len - 4) as usize, len);
+            blocks.push(&bytes[at..at + len]);
+            at += len;
+        }
+        let types: Vec<u32> = blocks.iter().map(|block| u32_at(block, 0)).collect();
+        assert_eq!(types, [SECTION_HEADER, 1, 6, 1, 6, 6]);
+        assert_eq!(u32_at(blocks[0], 8), 0x1A2B_3C4D);
+        assert_eq!(&blocks[1][8..10], &LINKTYPE_USER0.to_le_bytes());
+        assert_eq!(&blocks[1][20..24], b"COM1");
+
+        let packet = blocks[5];
+        // interface, timestamp high and low, lengths, data and flags
+        assert_eq!(u32_at(packet, 8), 0);
+        assert_eq!((u32_at(packet, 12), u32_at(packet, 16)), (1, 2));
+        assert_eq!((u32_at(packet, 20), u32_at(packet, 24)), (5, 5));
+        assert_eq!(&packet[28..33], b"hello");
+        assert_eq!(u32_at(packet, 40), EPB_OUTBOUND);
+        assert_eq!(u32_at(blocks[4], 8), 1);
+    }
+}
@@ -0,0 +1,212 @@
+//! Byte handling shared by every backend: decoding what was read from a port into
+//! [`SerialFrame`]s and encoding queued messages into bytes to write.
+//!
+//! Faults configured in [`SerialPortSetting::faults`] are injected between the port and the
+//! codec, delayed chunks are held here until they are due. Raw chunks are also handed to the
+//! port's [`SerialPortSetting::captures`] here, and frames are traced at
+//! [`SerialPortSetting::trace_level`]. With RS-485 echo suppression the echo of what was
+//! written is dropped before anything but the captures sees it.
+
+use std::{
+    collections::VecDeque,
+    io,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    capture::{Captures, Direction},
+    clock::Clock,
+    codec::FramingCodec,
+    fault::FaultInjector,
+    rs485::Echo,
+    trace::Tracer,
+    SerialFrame, SerialPortSetting, SerialStats,
+};
+
+/// The read and write sides of a port
+pub(crate) fn pipeline(
+    setting: &SerialPortSetting,
+    stats: Arc<SerialStats>,
+    clock: Clock,
+) -> (Inbound, Outbound) {
+    let echo = setting
+        .rs485
+        .as_ref()
+        .filter(|rs485| rs485.suppress_echo)
+        .map(|_| Arc::new(Echo::new(setting.baud_rate)));
+    (
+        Inbound::new(setting, stats.clone(), echo.clone(), clock.clone()),
+        Outbound::new(setting, stats, echo, clock),
+    )
+}
+
+/// Read side of a port
+pub(crate) struct Inbound {
+    codec: FramingCodec,
+    buf: BytesMut,
+    sequence: u64,
+    wall_clock_timestamps: bool,
+    stats: Arc<SerialStats>,
+    faults: Option<FaultInjector>,
+    /// chunks delayed by the faults, in the order they are due
+    delayed: VecDeque<(Instant, Bytes)>,
+    captures: Captures,
+    tracer: Option<Tracer>,
+    echo: Option<Arc<Echo>>,
+    clock: Clock,
+}
+
+impl Inbound {
+    fn new(
+        setting: &SerialPortSetting,
+        stats: Arc<SerialStats>,
+        echo: Option<Arc<Echo>>,
+        clock: Clock,
+    ) -> Self {
+        Self {
+            codec: setting.framing.into(),
+            buf: BytesMut::new(),
+            sequence: 0,
+            wall_clock_timestamps: setting.wall_clock_timestamps,
+            stats,
+            faults: setting.faults.inbound.clone().map(FaultInjector::new),
+            delayed: VecDeque::new(),
+            captures: Captures::new(setting.port_name.clone(), setting.captures.clone()),
+            tracer: Tracer::new(&setting.port_name, setting.trace_level),
+            echo,
+            clock,
+        }
+    }
+
+    /// Decode the frames completed by a chunk read from the port
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> io::Result<Vec<SerialFrame>> {
+        self.stats.record_read(chunk.len());
+        self.captures
+            .record(Direction::Rx, SystemTime::now(), chunk);
+        let stripped;
+        let chunk = match &self.echo {
+            Some(echo) => {
+                stripped = echo.strip(chunk);
+                &stripped[..]
+            }
+            None => chunk,
+        };
+        match &mut self.faults {
+            Some(faults) => {
+                let chunks = faults.apply(chunk, self.clock.now())?;
+                self.delayed.extend(chunks);
+                self.poll()
+            }
+            None => self.decode(chunk),
+        }
+    }
+
+    /// Decode the delayed chunks that are due
+    pub(crate) fn poll(&mut self) -> io::Result<Vec<SerialFrame>> {
+        let now = self.clock.now();
+        let mut frames = Vec::new();
+        while self.next_due().is_some_and(|due| due <= now) {
+            let (_, chunk) = self.delayed.pop_front().unwrap();
+            frames.extend(self.decode(&chunk)?);
+        }
+        Ok(frames)
+    }
+
+    /// When the next delayed chunk is due
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.delayed.front().map(|(due, _)| *due)
+    }
+
+    fn decode(&mut self, chunk: &[u8]) -> io::Result<Vec<SerialFrame>> {
+        self.buf.extend_from_slice(chunk);
+
+        let received_at = self.clock.now();
+        let received_at_wall = self.wall_clock_timestamps.then(SystemTime::now);
+        let mut frames = Vec::new();
+        while let Some(data) = self.codec.decode(&mut self.buf)? {
+            self.stats.record_frame_read();
+            if let Some(tracer) = &mut self.tracer {
+                tracer.frame(Direction::Rx, Some(self.sequence), &data);
+            }
+            frames.push(SerialFrame {
+                data,
+                sequence: self.sequence,
+                received_at,
+                received_at_wall,
+            });
+            self.sequence += 1;
+        }
+
+        Ok(frames)
+    }
+
+    pub(crate) fn read_failed(&self) {
+        self.stats.record_read_error();
+    }
+}
+
+/// Write side of a port
+pub(crate) struct Outbound {
+    codec: FramingCodec,
+    stats: Arc<SerialStats>,
+    faults: Option<FaultInjector>,
+    captures: Captures,
+    tracer: Option<Tracer>,
+    echo: Option<Arc<Echo>>,
+    clock: Clock,
+}
+
+impl Outbound {
+    fn new(
+        setting: &SerialPortSetting,
+        stats: Arc<SerialStats>,
+        echo: Option<Arc<Echo>>,
+        clock: Clock,
+    ) -> Self {
+        Self {
+            codec: setting.framing.into(),
+            stats,
+            faults: setting.faults.outbound.clone().map(FaultInjector::new),
+            captures: Captures::new(setting.port_name.clone(), setting.captures.clone()),
+            tracer: Tracer::new(&setting.port_name, setting.trace_level),
+            echo,
+            clock,
+        }
+    }
+
+    /// Encode a queued message into the chunks to write, each not before it is due
+    pub(crate) fn encode(&mut self, message: Bytes) -> io::Result<Vec<(Instant, Bytes)>> {
+        let mut dst = BytesMut::new();
+        self.codec.encode(message, &mut dst)?;
+        let bytes = dst.freeze();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.frame(Direction::Tx, None, &bytes);
+        }
+        let now = self.clock.now();
+        let chunks = match &mut self.faults {
+            Some(faults) => faults.apply(&bytes, now)?,
+            None => vec![(now, bytes)],
+        };
+        let wall_now = SystemTime::now();
+        for (due, chunk) in &chunks {
+            self.captures
+                .record(Direction::Tx, wall_now + (*due - now), chunk);
+            if let Some(echo) = &self.echo {
+                echo.expect(chunk, *due);
+            }
+        }
+        Ok(chunks)
+    }
+
+    pub(crate) fn written(&self, bytes: usize) {
+        self.stats.record_written(bytes);
+    }
+
+    pub(crate) fn write_failed(&self) {
+        self.stats.record_write_error();
+    }
+}
@@ -0,0 +1,184 @@
+//! Virtual null-modem cables made of pseudo-terminals.
+
+use std::{
+    ffi::CStr,
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// How long the bridge waits for data before checking whether the pair was dropped
+const BRIDGE_POLL: Duration = Duration::from_millis(50);
+
+/// Two pseudo-terminals wired together like a null-modem cable: what is written to one end is
+/// read from the other.
+///
+/// Both ends are real ttys, so they can be opened with
+/// [`SerialResource::open`](crate::SerialResource::open), e.g. one by the app and one by a
+/// simulated device. The cable is cut when the pair is dropped.
+pub struct VirtualPortPair {
+    paths: [String; 2],
+    stop: Arc<AtomicBool>,
+    bridge: Option<JoinHandle<()>>,
+    /// held open so the masters keep working while no one has the ports open
+    _slaves: [OwnedFd; 2],
+}
+
+impl VirtualPortPair {
+    pub fn new() -> io::Result<Self> {
+        let (master_a, slave_a) = open_pty()?;
+        let (master_b, slave_b) = open_pty()?;
+        let paths = [tty_name(&slave_a)?, tty_name(&slave_b)?];
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let bridge = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("virtual null-modem".to_string())
+                .spawn(move || bridge(File::from(master_a), File::from(master_b), &stop))?
+        };
+
+        Ok(Self {
+            paths,
+            stop,
+            bridge: Some(bridge),
+            _slaves: [slave_a, slave_b],
+        })
+    }
+
+    /// Device path of the first end, e.g. `/dev/pts/3`
+    pub fn a(&self) -> &str {
+        &self.paths[0]
+    }
+
+    /// Device path of the second end
+    pub fn b(&self) -> &str {
+        &self.paths[1]
+    }
+
+    pub fn paths(&self) -> (&str, &str) {
+        (self.a(), self.b())
+    }
+}
+
+impl Drop for VirtualPortPair {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(bridge) = self.bridge.take() {
+            let _ = bridge.join();
+        }
+    }
+}
+
+/// Open a pseudo-terminal in raw mode, returning its master and slave
+fn open_pty() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut master = -1;
+    let mut slave = -1;
+    // SAFETY: openpty only writes the two descriptors, name, termios and winsize may be null
+    let res = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just opened and are owned by nobody else
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+    // SAFETY: termios is plain data, filled by tcgetattr before being used
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    set_nonblocking(&master)?;
+
+    Ok((master, slave))
+}
+
+fn set_nonblocking(fd: &OwnedFd) -> io::Result<()> {
+    // SAFETY: fcntl on a descriptor we own
+    unsafe {
+        let flags = libc::fcntl(fd.as_raw_fd(), libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn tty_name(fd: &OwnedFd) -> io::Result<String> {
+    let mut buf = [0 as libc::c_char; 128];
+    // SAFETY: ttyname_r writes a nul terminated name of at most `buf.len()` bytes
+    let res = unsafe { libc::ttyname_r(fd.as_raw_fd(), buf.as_mut_ptr(), buf.len()) };
+    if res != 0 {
+        return Err(io::Error::from_raw_os_error(res));
+    }
+    // SAFETY: ttyname_r succeeded, so `buf` holds a nul terminated string
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+/// Copy everything written to one end to the other until `stop` is set
+fn bridge(mut a: File, mut b: File, stop: &AtomicBool) {
+    let mut buf = [0; 4096];
+    while !stop.load(Ordering::Acquire) {
+        let mut fds = [a.as_raw_fd(), b.as_raw_fd()].map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        // SAFETY: `fds` is a valid array of pollfd for the duration of the call
+        let ready = unsafe {
+            libc::poll(
+                fds.as_mut_ptr(),
+                fds.len() as libc::nfds_t,
+                BRIDGE_POLL.as_millis() as libc::c_int,
+            )
+        };
+        if ready <= 0 {
+            continue;
+        }
+        if fds[0].revents & libc::POLLIN != 0 {
+            forward(&mut a, &mut b, &mut buf, stop);
+        }
+        if fds[1].revents & libc::POLLIN != 0 {
+            forward(&mut b, &mut a, &mut buf, stop);
+        }
+    }
+}
+
+fn forward(from: &mut File, to: &mut File, buf: &mut [u8], stop: &AtomicBool) {
+    let n = match from.read(buf) {
+        Ok(n) => n,
+        Err(_) => return,
+    };
+    let mut data = &buf[..n];
+    while !data.is_empty() && !stop.load(Ordering::Acquire) {
+        match to.write(data) {
+            Ok(written) => data = &data[written..],
+            // the other end's input buffer is full until its port is read
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(1))
+            }
+            Err(_) => return,
+        }
+    }
+}
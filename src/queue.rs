@@ -0,0 +1,400 @@
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use parking_lot::{Condvar, Mutex};
+use tokio::sync::Notify;
+
+/// What a queue does with a new frame when it is already at capacity
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued frame to make room for the new one
+    #[default]
+    DropOldest,
+    /// Discard the new frame and keep the queue as it is
+    DropNewest,
+    /// Wait until the consumer has made room.
+    ///
+    /// For the send queue this blocks the system calling
+    /// [`SerialResource::send_message`](crate::SerialResource::send_message), for the receive
+    /// queue it stops reading from the port until the app drains it.
+    Block,
+    /// Discard the new frame and report it to the producer as an error
+    Error,
+}
+
+/// Capacity limit of a frame queue
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Maximum number of frames held by the queue, `None` means unbounded
+    pub capacity: Option<usize>,
+    /// What to do with a new frame when the queue is full
+    pub policy: OverflowPolicy,
+}
+
+impl QueueConfig {
+    /// A queue without a capacity limit
+    pub const UNBOUNDED: Self = Self {
+        capacity: None,
+        policy: OverflowPolicy::DropOldest,
+    };
+
+    /// A queue holding at most `capacity` frames
+    pub fn bounded(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            capacity: Some(capacity),
+            policy,
+        }
+    }
+}
+
+/// Returned by [`FrameQueue::push`] when a frame was not queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushError {
+    /// The queue is full and its policy is [`OverflowPolicy::Error`]
+    Full,
+    /// The queue has been closed
+    Closed,
+}
+
+/// Frame queue shared between bevy systems and the serial io tasks
+pub struct FrameQueue<T> {
+    inner: Mutex<Inner<T>>,
+    config: QueueConfig,
+    /// whether pushed frames wait for [`FrameQueue::release`] before the consumer sees them
+    held: bool,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+    /// wakes a producer blocked in [`FrameQueue::push`]
+    space: Condvar,
+    /// wakes a producer blocked in [`FrameQueue::push_async`]
+    space_async: Notify,
+    /// wakes the consumer waiting in [`FrameQueue::pop`]
+    ready: Notify,
+    /// wakes the consumer blocked in [`FrameQueue::pop_blocking`]
+    ready_sync: Condvar,
+}
+
+struct Inner<T> {
+    items: VecDeque<T>,
+    /// number of frames at the front of `items` [`FrameQueue::pop`] may take
+    released: usize,
+}
+
+impl<T> Inner<T> {
+    fn push_back(&mut self, item: T, held: bool) {
+        self.items.push_back(item);
+        if !held {
+            self.released += 1;
+        }
+    }
+
+    fn pop_front(&mut self) -> Option<T> {
+        let item = self.items.pop_front();
+        if item.is_some() {
+            self.released = self.released.saturating_sub(1);
+        }
+        item
+    }
+
+    fn release(&mut self) -> bool {
+        let released = self.released != self.items.len();
+        self.released = self.items.len();
+        released
+    }
+}
+
+impl<T> FrameQueue<T> {
+    pub fn new(config: QueueConfig) -> Self {
+        Self::with_hold(config, false)
+    }
+
+    /// A queue whose frames are only handed to the consumer once [`FrameQueue::release`] is
+    /// called, used to flush sends at a fixed point of the frame.
+    pub fn held(config: QueueConfig) -> Self {
+        Self::with_hold(config, true)
+    }
+
+    fn with_hold(config: QueueConfig, held: bool) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                items: VecDeque::new(),
+                released: 0,
+            }),
+            config,
+            held,
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            space: Condvar::new(),
+            space_async: Notify::new(),
+            ready: Notify::new(),
+            ready_sync: Condvar::new(),
+        }
+    }
+
+    pub fn config(&self) -> QueueConfig {
+        self.config
+    }
+
+    /// Number of frames currently queued
+    pub fn len(&self) -> usize {
+        self.inner.lock().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().items.is_empty()
+    }
+
+    /// Number of frames discarded because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Hand every frame pushed so far to the consumer
+    pub fn release(&self) {
+        if self.inner.lock().release() {
+            self.notify_ready();
+        }
+    }
+
+    /// Close the queue, waking everyone waiting on it.
+    ///
+    /// Frames already queued are released and can still be taken, new ones are rejected.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        // take the lock so a producer between its check and its wait can't miss the wakeup
+        self.inner.lock().release();
+        self.space.notify_all();
+        self.space_async.notify_waiters();
+        self.notify_ready();
+    }
+
+    /// Push a frame from synchronous code, blocking the caller if the policy is
+    /// [`OverflowPolicy::Block`] and the queue is full.
+    ///
+    /// A blocked push releases a held queue, otherwise nothing would ever make room.
+    pub fn push(&self, item: T) -> Result<(), PushError> {
+        let mut inner = self.inner.lock();
+        loop {
+            if self.is_closed() {
+                return Err(PushError::Closed);
+            }
+            match self.config.capacity {
+                Some(capacity) if inner.items.len() >= capacity => match self.config.policy {
+                    OverflowPolicy::Block => {
+                        if inner.release() {
+                            self.notify_ready();
+                        }
+                        self.space.wait(&mut inner);
+                        continue;
+                    }
+                    policy => return self.overflow(&mut inner, item, policy),
+                },
+                _ => {
+                    inner.push_back(item, self.held);
+                    drop(inner);
+                    if !self.held {
+                        self.notify_ready();
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Push a frame from async code, waiting for room if the policy is
+    /// [`OverflowPolicy::Block`] and the queue is full.
+    pub async fn push_async(&self, item: T) -> Result<(), PushError> {
+        loop {
+            let space = self.space_async.notified();
+            {
+                let mut inner = self.inner.lock();
+                if self.is_closed() {
+                    return Err(PushError::Closed);
+                }
+                match self.config.capacity {
+                    Some(capacity) if inner.items.len() >= capacity => match self.config.policy {
+                        OverflowPolicy::Block => {
+                            if inner.release() {
+                                self.notify_ready();
+                            }
+                        }
+                        policy => return self.overflow(&mut inner, item, policy),
+                    },
+                    _ => {
+                        inner.push_back(item, self.held);
+                        drop(inner);
+                        if !self.held {
+                            self.notify_ready();
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+            space.await;
+        }
+    }
+
+    fn overflow(
+        &self,
+        inner: &mut Inner<T>,
+        item: T,
+        policy: OverflowPolicy,
+    ) -> Result<(), PushError> {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        match policy {
+            OverflowPolicy::DropOldest => {
+                inner.pop_front();
+                inner.push_back(item, self.held);
+                if !self.held {
+                    self.notify_ready();
+                }
+                Ok(())
+            }
+            OverflowPolicy::Error => Err(PushError::Full),
+            OverflowPolicy::DropNewest | OverflowPolicy::Block => Ok(()),
+        }
+    }
+
+    /// Wait for the next frame, `None` once the queue is closed and empty.
+    ///
+    /// Only a single task may wait on a queue at a time.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            let ready = self.ready.notified();
+            if let Some(item) = self.try_pop() {
+                return Some(item);
+            }
+            if self.is_closed() {
+                return None;
+            }
+            ready.await;
+        }
+    }
+
+    /// Take the next released frame without waiting
+    pub fn try_pop(&self) -> Option<T> {
+        let item = {
+            let mut inner = self.inner.lock();
+            if inner.released == 0 {
+                return None;
+            }
+            inner.pop_front()
+        };
+        if item.is_some() {
+            self.notify_space();
+        }
+        item
+    }
+
+    /// Take every queued frame, released or not
+    pub fn drain(&self) -> Vec<T> {
+        let items: Vec<T> = {
+            let mut inner = self.inner.lock();
+            inner.released = 0;
+            inner.items.drain(..).collect()
+        };
+        if !items.is_empty() {
+            self.notify_space();
+        }
+        items
+    }
+
+    /// Wait for the next frame from synchronous code, `None` once the queue is closed and
+    /// empty.
+    pub fn pop_blocking(&self) -> Option<T> {
+        let mut inner = self.inner.lock();
+        loop {
+            if inner.released > 0 {
+                let item = inner.pop_front();
+                drop(inner);
+                self.notify_space();
+                return item;
+            }
+            if self.is_closed() {
+                return None;
+            }
+            self.ready_sync.wait(&mut inner);
+        }
+    }
+
+    fn notify_ready(&self) {
+        self.ready.notify_one();
+        self.ready_sync.notify_one();
+    }
+
+    fn notify_space(&self) {
+        self.space.notify_all();
+        self.space_async.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_policies() {
+        let queue = FrameQueue::new(QueueConfig::bounded(2, OverflowPolicy::DropOldest));
+        for i in 0..4 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.drain(), vec![2, 3]);
+        assert_eq!(queue.dropped(), 2);
+
+        let queue = FrameQueue::new(QueueConfig::bounded(2, OverflowPolicy::DropNewest));
+        for i in 0..4 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.drain(), vec![0, 1]);
+        assert_eq!(queue.dropped(), 2);
+
+        let queue = FrameQueue::new(QueueConfig::bounded(1, OverflowPolicy::Error));
+        queue.push(0).unwrap();
+        assert_eq!(queue.push(1), Err(PushError::Full));
+        assert_eq!(queue.dropped(), 1);
+
+        queue.close();
+        assert_eq!(queue.push(2), Err(PushError::Closed));
+        assert_eq!(queue.try_pop(), Some(0));
+    }
+
+    #[test]
+    fn blocked_push_resumes_after_drain() {
+        let queue = std::sync::Arc::new(FrameQueue::new(QueueConfig::bounded(
+            1,
+            OverflowPolicy::Block,
+        )));
+        queue.push(0).unwrap();
+        let producer = {
+            let queue = queue.clone();
+            std::thread::spawn(move || queue.push(1))
+        };
+        while queue.try_pop().is_none() {
+            std::thread::yield_now();
+        }
+        producer.join().unwrap().unwrap();
+        assert_eq!(queue.drain(), vec![1]);
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn held_frames_wait_for_release() {
+        let queue = FrameQueue::held(QueueConfig::UNBOUNDED);
+        queue.push(0).unwrap();
+        queue.push(1).unwrap();
+        assert_eq!(queue.try_pop(), None);
+        queue.release();
+        queue.push(2).unwrap();
+        assert_eq!(queue.try_pop(), Some(0));
+        assert_eq!(queue.try_pop(), Some(1));
+        assert_eq!(queue.try_pop(), None);
+        queue.close();
+        assert_eq!(queue.try_pop(), Some(2));
+    }
+}
@@ -0,0 +1,399 @@
+//! Reliable delivery over a framed port: sequence numbers, acknowledgements, a sliding window
+//! and retransmission, with duplicates dropped and frames delivered in order.
+//!
+//! Each frame starts with a kind byte (0 data, 1 acknowledgement) and the little-endian 16-bit
+//! sequence number, and ends with the CRC-16/XMODEM of everything before it. Data frames also
+//! carry the oldest sequence number the sender hasn't given up on, so the receiver stops
+//! waiting for the rest, and the message. Both ends start at sequence number 0 when the port
+//! is opened.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use bevy::{log::warn, prelude::Event};
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{crc::crc16_xmodem, SerialError, SerialFrame};
+
+const DATA: u8 = 0;
+const ACK: u8 = 1;
+const HEADER_LEN: usize = 5;
+const CRC_LEN: usize = 2;
+
+/// Reliable delivery of a port, see [`SerialPortSetting::reliable`](crate::SerialPortSetting::reliable).
+///
+/// Needs a [`Framing`](crate::Framing) that keeps frame boundaries, and the same settings on
+/// the other end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReliableConfig {
+    /// Messages sent and not yet acknowledged at once, further ones wait their turn
+    pub window: u16,
+    /// How long to wait for the acknowledgement of each attempt, from when
+    /// [`SerialSet::Flush`](crate::SerialSet) hands it to the writer
+    pub timeout: Duration,
+    /// How often to send a message again before reporting it with [`DeliveryFailed`]
+    pub retries: u32,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        Self {
+            window: 8,
+            timeout: Duration::from_millis(250),
+            retries: 5,
+        }
+    }
+}
+
+/// A message sent to a port with reliable delivery that was never acknowledged
+#[derive(Debug, Clone, Event)]
+pub struct DeliveryFailed {
+    pub port: String,
+    pub data: Bytes,
+}
+
+fn encode(kind: u8, seq: u16, base: u16, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + payload.len() + CRC_LEN);
+    frame.put_u8(kind);
+    frame.put_u16_le(seq);
+    frame.put_u16_le(base);
+    frame.put_slice(payload);
+    let crc = crc16_xmodem(&frame);
+    frame.put_u16_le(crc);
+    frame.freeze()
+}
+
+/// Kind, sequence number and base of an intact frame
+fn decode(frame: &[u8]) -> Option<(u8, u16, u16)> {
+    let (body, crc) = frame.split_at(frame.len().checked_sub(CRC_LEN)?);
+    if body.len() < HEADER_LEN || crc16_xmodem(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return None;
+    }
+    Some((
+        body[0],
+        u16::from_le_bytes([body[1], body[2]]),
+        u16::from_le_bytes([body[3], body[4]]),
+    ))
+}
+
+struct Outstanding {
+    seq: u16,
+    data: Bytes,
+    /// when the latest attempt times out, `None` until it is flushed
+    deadline: Option<Instant>,
+    retries_left: u32,
+    /// acknowledged or given up on
+    done: bool,
+}
+
+/// Both directions of reliable delivery on one port
+pub(crate) struct Reliable {
+    window: u16,
+    timeout: Duration,
+    retries: u32,
+    next_tx: u16,
+    unacked: VecDeque<Outstanding>,
+    /// messages waiting for room in the window
+    backlog: VecDeque<Bytes>,
+    next_rx: u16,
+    /// frames received ahead of `next_rx`
+    received: HashMap<u16, SerialFrame>,
+}
+
+impl Reliable {
+    pub(crate) fn new(config: &ReliableConfig) -> Self {
+        Self {
+            // within half the sequence space, so old and new frames can be told apart
+            window: config.window.clamp(1, u16::MAX / 2),
+            timeout: config.timeout,
+            retries: config.retries,
+            next_tx: 0,
+            unacked: VecDeque::new(),
+            backlog: VecDeque::new(),
+            next_rx: 0,
+            received: HashMap::new(),
+        }
+    }
+
+    /// Send a message if the window has room, or keep it until it has
+    pub(crate) fn send(
+        &mut self,
+        data: Bytes,
+        mut send: impl FnMut(Bytes) -> Result<(), SerialError>,
+    ) -> Result<(), SerialError> {
+        if self.backlog.is_empty() && self.unacked.len() < self.window as usize {
+            self.transmit(data, &mut send)
+        } else {
+            self.backlog.push_back(data);
+            Ok(())
+        }
+    }
+
+    fn transmit(
+        &mut self,
+        data: Bytes,
+        send: &mut impl FnMut(Bytes) -> Result<(), SerialError>,
+    ) -> Result<(), SerialError> {
+        let base = self.base();
+        send(encode(DATA, self.next_tx, base, &data))?;
+        self.unacked.push_back(Outstanding {
+            seq: self.next_tx,
+            data,
+            deadline: None,
+            retries_left: self.retries,
+            done: false,
+        });
+        self.next_tx = self.next_tx.wrapping_add(1);
+        Ok(())
+    }
+
+    /// The oldest sequence number still being sent
+    fn base(&self) -> u16 {
+        self.unacked
+            .iter()
+            .find(|o| !o.done)
+            .map_or(self.next_tx, |o| o.seq)
+    }
+
+    /// Start the timeouts of the frames sent since the last flush, now that they are handed to
+    /// the writer
+    pub(crate) fn flushed(&mut self, now: Instant) {
+        for outstanding in &mut self.unacked {
+            outstanding.deadline.get_or_insert(now + self.timeout);
+        }
+    }
+
+    pub(crate) fn is_idle(&self) -> bool {
+        self.unacked.is_empty() && self.backlog.is_empty() && self.received.is_empty()
+    }
+
+    /// Handle a frame read from the port, returning the messages now deliverable in order
+    pub(crate) fn receive(
+        &mut self,
+        frame: SerialFrame,
+        mut send: impl FnMut(Bytes) -> Result<(), SerialError>,
+    ) -> Vec<SerialFrame> {
+        let Some((kind, seq, base)) = decode(&frame.data) else {
+            warn!("dropping a corrupted frame of {} bytes", frame.data.len());
+            return Vec::new();
+        };
+        match kind {
+            ACK => {
+                if let Some(outstanding) = self.unacked.iter_mut().find(|o| o.seq == seq) {
+                    outstanding.done = true;
+                }
+                Vec::new()
+            }
+            DATA => {
+                let window = self.window;
+                let mut frames = Vec::new();
+                let skipped = base.wrapping_sub(self.next_rx);
+                if skipped != 0 && skipped < u16::MAX / 2 {
+                    // the sender gave up on the frames before `base`, deliver what came of them
+                    while self.next_rx != base {
+                        frames.extend(self.received.remove(&self.next_rx));
+                        self.next_rx = self.next_rx.wrapping_add(1);
+                    }
+                }
+                if seq.wrapping_sub(self.next_rx) < window {
+                    acknowledge(seq, &mut send);
+                    let data = frame.data.slice(HEADER_LEN..frame.data.len() - CRC_LEN);
+                    self.received
+                        .entry(seq)
+                        .or_insert(SerialFrame { data, ..frame });
+                    frames.extend(self.deliver());
+                    frames
+                } else {
+                    if self.next_rx.wrapping_sub(seq) <= window {
+                        // delivered already, its acknowledgement got lost
+                        acknowledge(seq, &mut send);
+                    }
+                    frames
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn deliver(&mut self) -> Vec<SerialFrame> {
+        let mut frames = Vec::new();
+        while let Some(frame) = self.received.remove(&self.next_rx) {
+            frames.push(frame);
+            self.next_rx = self.next_rx.wrapping_add(1);
+        }
+        frames
+    }
+
+    /// Send again or give up on what timed out and send what the window has room for
+    pub(crate) fn update(
+        &mut self,
+        port: &str,
+        now: Instant,
+        mut send: impl FnMut(Bytes) -> Result<(), SerialError>,
+        failures: &mut Vec<DeliveryFailed>,
+    ) {
+        let base = self.base();
+        for outstanding in self.unacked.iter_mut() {
+            let timed_out = outstanding.deadline.is_some_and(|deadline| now >= deadline);
+            if outstanding.done || !timed_out {
+                continue;
+            }
+            if outstanding.retries_left == 0 {
+                outstanding.done = true;
+                failures.push(DeliveryFailed {
+                    port: port.to_string(),
+                    data: outstanding.data.clone(),
+                });
+                continue;
+            }
+            outstanding.retries_left -= 1;
+            outstanding.deadline = None;
+            if let Err(err) = send(encode(DATA, outstanding.seq, base, &outstanding.data)) {
+                warn!(
+                    "failed to resend frame {} to {}: {}",
+                    outstanding.seq, port, err
+                );
+            }
+        }
+        while self.unacked.front().is_some_and(|o| o.done) {
+            self.unacked.pop_front();
+        }
+        while self.unacked.len() < self.window as usize {
+            let Some(data) = self.backlog.pop_front() else {
+                break;
+            };
+            if self.transmit(data.clone(), &mut send).is_err() {
+                failures.push(DeliveryFailed {
+                    port: port.to_string(),
+                    data,
+                });
+            }
+        }
+    }
+}
+
+fn acknowledge(seq: u16, send: &mut impl FnMut(Bytes) -> Result<(), SerialError>) {
+    if let Err(err) = send(encode(ACK, seq, 0, &[])) {
+        warn!("failed to acknowledge frame {}: {}", seq, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(data: Bytes) -> SerialFrame {
+        SerialFrame {
+            data,
+            sequence: 0,
+            received_at: Instant::now(),
+            received_at_wall: None,
+        }
+    }
+
+    fn to(link: &mut Vec<Bytes>) -> impl FnMut(Bytes) -> Result<(), SerialError> + '_ {
+        |frame| {
+            link.push(frame);
+            Ok(())
+        }
+    }
+
+    fn payloads(frames: Vec<SerialFrame>) -> Vec<Bytes> {
+        frames.into_iter().map(|frame| frame.data).collect()
+    }
+
+    #[test]
+    fn lossy_link() {
+        let config = ReliableConfig {
+            window: 4,
+            timeout: Duration::ZERO,
+            retries: 10,
+        };
+        let (mut a, mut b) = (Reliable::new(&config), Reliable::new(&config));
+        let mut a_to_b = Vec::new();
+        let now = Instant::now();
+        let mut b_to_a = Vec::new();
+        let messages: Vec<Bytes> = (0..20u8).map(|i| Bytes::from(vec![i; 3])).collect();
+        for message in &messages {
+            a.send(message.clone(), to(&mut a_to_b)).unwrap();
+        }
+
+        let mut delivered = Vec::new();
+        let mut failures = Vec::new();
+        let mut count = 0;
+        for _ in 0..100 {
+            for f in std::mem::take(&mut a_to_b) {
+                count += 1;
+                // drop every third frame, duplicate every fifth and flip a bit in every seventh
+                match count {
+                    c if c % 3 == 0 => continue,
+                    c if c % 5 == 0 => {
+                        delivered.extend(b.receive(frame(f.clone()), to(&mut b_to_a)))
+                    }
+                    c if c % 7 == 0 => {
+                        let mut corrupt = f.to_vec();
+                        corrupt[3] ^= 1;
+                        delivered.extend(b.receive(frame(corrupt.into()), to(&mut b_to_a)));
+                        continue;
+                    }
+                    _ => {}
+                }
+                delivered.extend(b.receive(frame(f), to(&mut b_to_a)));
+            }
+            for f in std::mem::take(&mut b_to_a) {
+                count += 1;
+                if count % 3 != 0 {
+                    a.receive(frame(f), to(&mut a_to_b));
+                }
+            }
+            a.flushed(now);
+            a.update("a", now, to(&mut a_to_b), &mut failures);
+            if a.is_idle() {
+                break;
+            }
+        }
+        assert!(failures.is_empty());
+        assert!(a.is_idle() && b.is_idle());
+        assert_eq!(payloads(delivered), messages);
+    }
+
+    #[test]
+    fn failures_and_gaps() {
+        let config = ReliableConfig {
+            window: 2,
+            timeout: Duration::ZERO,
+            retries: 1,
+        };
+        let mut a = Reliable::new(&config);
+        let mut sent = Vec::new();
+        let now = Instant::now();
+        for message in [b"one", b"two", b"six"] {
+            a.send(Bytes::from_static(message), to(&mut sent)).unwrap();
+        }
+        assert_eq!(sent.len(), 2);
+
+        // nothing times out before it is flushed
+        let mut failures = Vec::new();
+        a.update("a", now, to(&mut sent), &mut failures);
+        assert_eq!(sent.len(), 2);
+
+        // the first update resends, the second gives up and makes room for the third message
+        a.flushed(now);
+        a.update("a", now, to(&mut sent), &mut failures);
+        assert_eq!(sent.len(), 4);
+        a.flushed(now);
+        a.update("a", now, to(&mut sent), &mut failures);
+        let failed: Vec<_> = failures.iter().map(|f| f.data.clone()).collect();
+        assert_eq!(failed, [&b"one"[..], &b"two"[..]]);
+        assert_eq!(sent.len(), 5);
+
+        // a receiver that lost the first two frames skips them with the third
+        let mut b = Reliable::new(&config);
+        let mut acks = Vec::new();
+        let frames = b.receive(frame(sent[4].clone()), to(&mut acks));
+        assert_eq!(payloads(frames), [&b"six"[..]]);
+        assert_eq!(decode(&acks[0]), Some((ACK, 2, 0)));
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::{
+    backend::{PortIo, VirtualDriver},
+    capture::{CaptureRecord, Direction},
+    pipeline::{Inbound, Outbound},
+    PushError, RecvQueue, SendQueue, SerialError,
+};
+
+/// How fast [`SerialResource::open_replay`](crate::SerialResource::open_replay) plays a capture
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ReplayTiming {
+    /// With the gaps between chunks as they were captured
+    #[default]
+    Original,
+    /// This many times faster than captured, must be positive
+    Speed(f64),
+    /// Everything on the first update
+    Immediate,
+}
+
+/// Progress of a port replaying a capture
+#[derive(Clone)]
+pub struct ReplayPort {
+    shared: Arc<ReplayShared>,
+}
+
+struct ReplayShared {
+    port_name: String,
+    send_queue: SendQueue,
+    recv_queue: RecvQueue,
+    inbound: Mutex<Inbound>,
+    outbound: Mutex<Outbound>,
+    /// chunks still to be read, with when they are due
+    pending: Mutex<VecDeque<(Instant, Bytes)>>,
+}
+
+impl ReplayPort {
+    /// Plays the chunks the capture read from `io.port_name`
+    pub(crate) fn new(
+        io: PortIo,
+        records: Vec<CaptureRecord>,
+        timing: ReplayTiming,
+    ) -> Result<Self, SerialError> {
+        let invalid = |reason: String| SerialError::InvalidReplay(io.port_name.clone(), reason);
+        if let ReplayTiming::Speed(speed) = timing {
+            if !(speed.is_finite() && speed > 0.0) {
+                return Err(invalid(format!("speed {speed} is not a positive number")));
+            }
+        }
+        let start = Instant::now();
+        let mut first = None;
+        let pending = records
+            .into_iter()
+            .filter(|record| record.direction == Direction::Rx && record.port == io.port_name)
+            .map(|record| {
+                let first = *first.get_or_insert(record.timestamp);
+                let gap = record
+                    .timestamp
+                    .duration_since(first)
+                    .unwrap_or(Duration::ZERO);
+                let gap = match timing {
+                    ReplayTiming::Original => Some(gap),
+                    ReplayTiming::Speed(speed) => {
+                        Duration::try_from_secs_f64(gap.as_secs_f64() / speed).ok()
+                    }
+                    ReplayTiming::Immediate => Some(Duration::ZERO),
+                };
+                match gap.and_then(|gap| start.checked_add(gap)) {
+                    Some(due) => Ok((due, record.data)),
+                    None => Err(invalid(format!("{timing:?} is too slow"))),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            shared: Arc::new(ReplayShared {
+                port_name: io.port_name,
+                send_queue: io.send_queue,
+                recv_queue: io.recv_queue,
+                inbound: Mutex::new(io.inbound),
+                outbound: Mutex::new(io.outbound),
+                pending: Mutex::new(pending),
+            }),
+        })
+    }
+
+    pub(crate) fn driver(&self) -> Arc<dyn VirtualDriver> {
+        self.shared.clone()
+    }
+
+    pub fn port_name(&self) -> &str {
+        &self.shared.port_name
+    }
+
+    /// Chunks not played yet
+    pub fn remaining(&self) -> usize {
+        self.shared.pending.lock().len()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+
+impl VirtualDriver for ReplayShared {
+    /// Messages sent to a replayed port are encoded and discarded
+    fn flush(&self) {
+        let mut outbound = self.outbound.lock();
+        while let Some(message) = self.send_queue.try_pop() {
+            match outbound.encode(message) {
+                Ok(chunks) => outbound.written(chunks.iter().map(|(_, bytes)| bytes.len()).sum()),
+                Err(_) => outbound.write_failed(),
+            }
+        }
+    }
+
+    fn poll(&self) {
+        let now = Instant::now();
+        let mut pending = self.pending.lock();
+        let mut inbound = self.inbound.lock();
+        let mut frames = Vec::new();
+        while pending.front().is_some_and(|(due, _)| *due <= now) {
+            let (_, chunk) = pending.pop_front().unwrap();
+            match inbound.feed(&chunk) {
+                Ok(decoded) => frames.extend(decoded),
+                Err(_) => inbound.read_failed(),
+            }
+        }
+        // chunks delayed by faults
+        match inbound.poll() {
+            Ok(decoded) => frames.extend(decoded),
+            Err(_) => inbound.read_failed(),
+        }
+        for frame in frames {
+            if let Err(PushError::Closed) = self.recv_queue.push(frame) {
+                pending.clear();
+                return;
+            }
+        }
+    }
+}
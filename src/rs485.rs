@@ -0,0 +1,157 @@
+//! Half-duplex RS-485: switching the transceiver's direction around each write and dropping
+//! the echo of our own transmissions.
+
+use std::{
+    collections::VecDeque,
+    io,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::{backend::Control, PortControl};
+
+/// How long an echo may take to come back after the transmission, on top of its time on the
+/// wire, before it is no longer expected
+const ECHO_GRACE: Duration = Duration::from_millis(100);
+
+/// The modem control line switching a transceiver between sending and receiving
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DirectionLine {
+    #[default]
+    Rts,
+    Dtr,
+}
+
+/// RS-485 mode of a port, see [`SerialPortSetting::rs485`](crate::SerialPortSetting::rs485)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rs485Config {
+    /// Line enabling the transmitter
+    pub line: DirectionLine,
+    /// Level of the line while transmitting, the opposite while receiving
+    pub transmit_level: bool,
+    /// Wait between enabling the transmitter and the first byte
+    pub pre_delay: Duration,
+    /// Wait between the last byte leaving the port and disabling the transmitter
+    pub post_delay: Duration,
+    /// Drop our own transmissions from what is read, for transceivers that echo them
+    pub suppress_echo: bool,
+}
+
+impl Default for Rs485Config {
+    fn default() -> Self {
+        Self {
+            line: DirectionLine::Rts,
+            transmit_level: true,
+            pre_delay: Duration::ZERO,
+            post_delay: Duration::ZERO,
+            suppress_echo: false,
+        }
+    }
+}
+
+/// Drives the direction line of a port around each write
+pub(crate) struct DirectionControl {
+    control: Control,
+    line: DirectionLine,
+    transmit_level: bool,
+    pub pre_delay: Duration,
+    pub post_delay: Duration,
+}
+
+impl DirectionControl {
+    /// Take control of the line through the control handle of the port, starting out receiving
+    pub(crate) fn new(config: &Rs485Config, control: Control) -> io::Result<Self> {
+        let mut control = Self {
+            control,
+            line: config.line,
+            transmit_level: config.transmit_level,
+            pre_delay: config.pre_delay,
+            post_delay: config.post_delay,
+        };
+        control.set_transmitting(false)?;
+        Ok(control)
+    }
+
+    pub(crate) fn set_transmitting(&mut self, transmitting: bool) -> io::Result<()> {
+        let level = transmitting == self.transmit_level;
+        (self.control)(match self.line {
+            DirectionLine::Rts => PortControl::Rts(level),
+            DirectionLine::Dtr => PortControl::Dtr(level),
+        })
+    }
+}
+
+/// The echo expected back of what was written
+pub(crate) struct Echo {
+    baud_rate: u32,
+    expected: Mutex<Expected>,
+}
+
+struct Expected {
+    bytes: VecDeque<u8>,
+    /// when the echo of the last byte should be back at the latest
+    deadline: Instant,
+}
+
+impl Echo {
+    pub(crate) fn new(baud_rate: u32) -> Self {
+        Self {
+            baud_rate,
+            expected: Mutex::new(Expected {
+                bytes: VecDeque::new(),
+                deadline: Instant::now(),
+            }),
+        }
+    }
+
+    /// Expect the echo of bytes written no earlier than `due`
+    pub(crate) fn expect(&self, bytes: &[u8], due: Instant) {
+        // assume up to 12 bits per byte: start, 8 data, parity and 2 stop bits
+        let on_wire = Duration::from_secs_f64(bytes.len() as f64 * 12.0 / self.baud_rate as f64);
+        let mut expected = self.expected.lock();
+        let start = due.max(expected.deadline.min(Instant::now()));
+        expected.bytes.extend(bytes);
+        expected.deadline = expected.deadline.max(start + on_wire + ECHO_GRACE);
+    }
+
+    /// The bytes of a chunk read from the port that are not an echo
+    pub(crate) fn strip(&self, chunk: &[u8]) -> Vec<u8> {
+        let mut expected = self.expected.lock();
+        if Instant::now() > expected.deadline {
+            expected.bytes.clear();
+        }
+        let mut rest = Vec::new();
+        for &byte in chunk {
+            match expected.bytes.front() {
+                Some(&echo) if echo == byte => {
+                    expected.bytes.pop_front();
+                }
+                Some(_) => {
+                    // garbled by a collision, no more of the echo can be told apart
+                    expected.bytes.clear();
+                    rest.push(byte);
+                }
+                None => rest.push(byte),
+            }
+        }
+        rest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn echo_is_stripped() {
+        let echo = Echo::new(9600);
+        echo.expect(b"ping", Instant::now());
+        assert_eq!(echo.strip(b"pi"), b"");
+        assert_eq!(echo.strip(b"ngpong"), b"pong");
+        assert_eq!(echo.strip(b"ping"), b"ping");
+
+        echo.expect(b"abc", Instant::now());
+        assert_eq!(echo.strip(b"axc"), b"xc");
+    }
+}
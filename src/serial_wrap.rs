@@ -1,18 +1,30 @@
-use bevy::log::error;
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
+use bevy::log::Level;
 use bytes::Bytes;
-use futures::{
-    stream::{SplitSink, SplitStream, StreamExt},
-    SinkExt,
-};
-use parking_lot::Mutex;
 use serialport::{DataBits, FlowControl, Parity, StopBits};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tokio_serial::{SerialPortBuilderExt, SerialStream};
-use tokio_util::codec::{Decoder, Framed};
 
-use crate::{codec::RawCodec, error::SerialError, ArcRuntime, RecvQueue};
+use crate::{
+    backend::{self, PortDriver, PortIo},
+    capture::{CaptureRecord, CaptureSink},
+    clock::Clock,
+    codec::Framing,
+    error::SerialError,
+    fault::FaultInjection,
+    mock::MockPort,
+    mux::Mux,
+    pipeline,
+    queue::{FrameQueue, PushError, QueueConfig},
+    reliable::{DeliveryFailed, Reliable, ReliableConfig},
+    replay::{ReplayPort, ReplayTiming},
+    rs485::Rs485Config,
+    stats::{SerialPortStats, SerialStats},
+    transaction::{ReplyTo, Transaction, TransactionResult, Transactions},
+    RecvQueue, SendQueue, SerialPortRuntime,
+};
 
 /// settings for initialize serial port
 #[derive(Debug)]
@@ -31,6 +43,27 @@ pub struct SerialPortSetting {
     pub stop_bits: StopBits,
     /// Amount of time to wait to receive data before timing out
     pub timeout: Duration,
+    /// Limit of frames waiting to be written to the port
+    pub send_queue: QueueConfig,
+    /// Limit of frames read from the port and not yet broadcast as [`SerialData`](crate::SerialData)
+    pub recv_queue: QueueConfig,
+    /// Also record the wall-clock time each frame was read at
+    pub wall_clock_timestamps: bool,
+    /// Faults injected into the traffic, for testing against unreliable links
+    pub faults: FaultInjection,
+    /// Sinks recording every chunk read from and written to the port
+    pub captures: Vec<Arc<dyn CaptureSink>>,
+    /// Log every frame read and written as a hex dump at this level
+    pub trace_level: Option<Level>,
+    /// Limit of transactions awaiting their replies at once, e.g. 1 for devices that can't
+    /// take pipelined commands. Further transactions wait in a queue
+    pub max_in_flight: Option<usize>,
+    /// Half-duplex RS-485 direction control and echo suppression
+    pub rs485: Option<Rs485Config>,
+    /// How the bytes read are split into frames and messages are framed when written
+    pub framing: Framing,
+    /// Acknowledge and retransmit every message, for links that lose or corrupt frames
+    pub reliable: Option<ReliableConfig>,
 }
 
 impl Default for SerialPortSetting {
@@ -43,68 +76,372 @@ impl Default for SerialPortSetting {
             parity: Parity::None,
             stop_bits: StopBits::One,
             timeout: Duration::from_millis(0),
+            send_queue: QueueConfig::UNBOUNDED,
+            recv_queue: QueueConfig::UNBOUNDED,
+            wall_clock_timestamps: false,
+            faults: FaultInjection::default(),
+            captures: Vec::new(),
+            trace_level: None,
+            max_in_flight: None,
+            rs485: None,
+            framing: Framing::Raw,
+            reliable: None,
         }
     }
 }
 
+/// A change to a port's modem lines or settings, made right away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortControl {
+    /// Assert (`true`) or clear Data Terminal Ready
+    Dtr(bool),
+    /// Assert (`true`) or clear Request To Send
+    Rts(bool),
+    BaudRate(u32),
+}
+
+/// A frame read from a port, stamped by the reader task
+#[derive(Debug, Clone)]
+pub struct SerialFrame {
+    pub data: Bytes,
+    /// Per-port sequence number, counting every frame read including dropped ones
+    pub sequence: u64,
+    /// When the frame was read
+    pub received_at: Instant,
+    /// Wall-clock time the frame was read at, if
+    /// [`SerialPortSetting::wall_clock_timestamps`] is enabled
+    pub received_at_wall: Option<SystemTime>,
+}
+
 pub struct SerialPortWrap {
-    pub msg_sender: Arc<Mutex<UnboundedSender<Bytes>>>,
+    pub port_name: String,
+    pub send_queue: SendQueue,
     pub recv_queue: RecvQueue,
+    pub stats: Arc<SerialStats>,
+    pub(crate) transactions: Transactions,
+    pub(crate) mux: Mux,
+    /// frames read while a transfer has the port, `None` while they are broadcast
+    claimed: Option<Vec<Bytes>>,
+    reliable: Option<Reliable>,
+    framing: Framing,
+    driver: PortDriver,
+    /// what transaction and delivery timeouts are measured in
+    clock: Clock,
 }
 
 impl SerialPortWrap {
-    pub fn new(task_pool: ArcRuntime, setting: SerialPortSetting) -> Result<Self, SerialError> {
-        let recv_queue = Arc::new(Mutex::new(Vec::new()));
-
-        let pool_clone = task_pool.clone();
-
-        let (message_sender, mut message_receiver) = unbounded_channel::<Bytes>();
-        let recv_queue_2 = recv_queue.clone();
-        let (mut sender, mut reader) = pool_clone.block_on(async move {
-            let serial_port = tokio_serial::new(setting.port_name, setting.baud_rate)
-                .data_bits(setting.data_bits)
-                .flow_control(setting.flow_control)
-                .parity(setting.parity)
-                .stop_bits(setting.stop_bits)
-                .open_native_async()?;
-
-            let stream = RawCodec.framed(serial_port);
-            Ok::<
-                (
-                    SplitSink<Framed<SerialStream, RawCodec>, Bytes>,
-                    SplitStream<Framed<SerialStream, RawCodec>>,
-                ),
-                serialport::Error,
-            >(stream.split())
+    pub fn new(
+        task_pool: SerialPortRuntime,
+        setting: SerialPortSetting,
+    ) -> Result<Self, SerialError> {
+        Self::with_driver(&setting, Clock::default(), |io| {
+            backend::spawn_port(&task_pool, &setting, io).map(PortDriver::Tasks)
+        })
+    }
+
+    /// An in-memory port driven by the returned [`MockPort`] instead of a device
+    pub fn mock(setting: SerialPortSetting) -> (Self, MockPort) {
+        let mut mock = None;
+        let wrap = Self::with_driver(&setting, Clock::manual(), |io| {
+            let port = MockPort::new(io);
+            let driver = PortDriver::Virtual(port.driver());
+            mock = Some(port);
+            Ok(driver)
+        })
+        .expect("a mock port can't fail to open");
+        (wrap, mock.unwrap())
+    }
+
+    /// A port reading what a capture read from a port of the same name
+    pub fn replay(
+        setting: SerialPortSetting,
+        records: Vec<CaptureRecord>,
+        timing: ReplayTiming,
+    ) -> Result<(Self, ReplayPort), SerialError> {
+        let mut replay = None;
+        let wrap = Self::with_driver(&setting, Clock::default(), |io| {
+            let port = ReplayPort::new(io, records, timing)?;
+            let driver = PortDriver::Virtual(port.driver());
+            replay = Some(port);
+            Ok(driver)
+        })?;
+        Ok((wrap, replay.unwrap()))
+    }
+
+    fn with_driver(
+        setting: &SerialPortSetting,
+        clock: Clock,
+        driver: impl FnOnce(PortIo) -> Result<PortDriver, SerialError>,
+    ) -> Result<Self, SerialError> {
+        let port_name = setting.port_name.clone();
+        let send_queue: SendQueue = Arc::new(FrameQueue::held(setting.send_queue));
+        let recv_queue = Arc::new(FrameQueue::new(setting.recv_queue));
+        let stats = Arc::new(SerialStats::new(clock.clone()));
+        let (inbound, outbound) = pipeline::pipeline(setting, stats.clone(), clock.clone());
+
+        let driver = driver(PortIo {
+            port_name: port_name.clone(),
+            send_queue: send_queue.clone(),
+            recv_queue: recv_queue.clone(),
+            inbound,
+            outbound,
+            clock: clock.clone(),
         })?;
-        task_pool.clone().spawn(async move {
-            task_pool.spawn(async move {
-                while let Some(message) = message_receiver.recv().await {
-                    match sender.send(message).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("{:?}", err);
-                        }
-                    }
-                }
-            });
-
-            task_pool.spawn(async move {
-                while let Some(Ok(recv_message)) = reader.next().await {
-                    recv_queue_2.lock().push(recv_message);
-                }
-            });
-
-            Ok::<(), SerialError>(())
-        });
 
         Ok(Self {
-            msg_sender: Arc::new(Mutex::new(message_sender)),
+            port_name,
+            send_queue,
             recv_queue,
+            stats,
+            transactions: Transactions::new(setting.max_in_flight),
+            mux: Mux::default(),
+            claimed: None,
+            reliable: setting.reliable.as_ref().map(Reliable::new),
+            framing: setting.framing,
+            driver,
+            clock,
         })
     }
 
-    pub fn get_messages(&mut self) -> Vec<Bytes> {
-        self.recv_queue.clone().lock().drain(..).collect()
+    /// The time on the port's clock, which only moves with [`MockPort::advance`] on a mock port
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Traffic counters and queue depths of the port
+    pub fn stats(&self) -> SerialPortStats {
+        SerialPortStats {
+            dropped_received: self.recv_queue.dropped(),
+            dropped_sent: self.send_queue.dropped(),
+            send_queue_len: self.send_queue.len(),
+            recv_queue_len: self.recv_queue.len(),
+            ..self.stats.snapshot()
+        }
+    }
+
+    /// Queue a message to be written to the port on the next [`SerialPortWrap::flush`].
+    ///
+    /// With [`SerialPortSetting::reliable`] the message may wait for room in the window.
+    pub fn send(&mut self, message: Bytes) -> Result<(), SerialError> {
+        if self.is_claimed() {
+            return Err(SerialError::PortBusy(self.port_name.clone()));
+        }
+        send(
+            &self.send_queue,
+            &mut self.reliable,
+            &self.port_name,
+            message,
+        )
+    }
+
+    /// Send a transaction's request, right away or once the port has room for it
+    pub(crate) fn submit_transaction(
+        &mut self,
+        transaction: Transaction,
+        reply_to: ReplyTo,
+    ) -> Result<(), SerialError> {
+        if self.is_claimed() {
+            return Err(SerialError::PortBusy(self.port_name.clone()));
+        }
+        let Self {
+            port_name,
+            send_queue,
+            transactions,
+            reliable,
+            ..
+        } = self;
+        transactions.submit(transaction, reply_to, |request| {
+            send(send_queue, reliable, port_name, request)
+        })
+    }
+
+    /// Retransmit or fail the messages that weren't acknowledged in time and send those
+    /// waiting for room in the window
+    pub(crate) fn update_reliable(&mut self, failures: &mut Vec<DeliveryFailed>) {
+        let Self {
+            port_name,
+            send_queue,
+            reliable,
+            clock,
+            ..
+        } = self;
+        if let Some(reliable) = reliable.as_mut().filter(|reliable| !reliable.is_idle()) {
+            reliable.update(
+                port_name,
+                clock.now(),
+                |frame| push_send(send_queue, port_name, frame),
+                failures,
+            );
+        }
+    }
+
+    /// Retry or fail the transactions that timed out and send the queued ones
+    pub(crate) fn update_transactions(&mut self, results: &mut Vec<TransactionResult>) {
+        let Self {
+            port_name,
+            send_queue,
+            transactions,
+            reliable,
+            clock,
+            ..
+        } = self;
+        transactions.update(
+            port_name,
+            clock.now(),
+            |request| send(send_queue, reliable, port_name, request),
+            results,
+        );
+    }
+
+    /// Divert what is read from the port from [`SerialData`](crate::SerialData) to a transfer
+    pub(crate) fn claim(&mut self) {
+        self.claimed.get_or_insert_with(Vec::new);
+    }
+
+    pub(crate) fn release(&mut self) {
+        self.claimed = None;
+    }
+
+    pub(crate) fn is_claimed(&self) -> bool {
+        self.claimed.is_some()
+    }
+
+    /// Why a transfer can't take the port, which must pass its bytes on as they are
+    pub(crate) fn unclaimable(&self) -> Option<&'static str> {
+        if self.framing != Framing::Raw {
+            Some("the port needs raw framing")
+        } else if self.reliable.is_some() {
+            Some("the port must not use reliable delivery")
+        } else {
+            None
+        }
+    }
+
+    /// Keep a frame read while the port is claimed, giving it back otherwise
+    pub(crate) fn divert(&mut self, frame: SerialFrame) -> Option<SerialFrame> {
+        match &mut self.claimed {
+            Some(claimed) => {
+                claimed.push(frame.data);
+                None
+            }
+            None => Some(frame),
+        }
+    }
+
+    pub(crate) fn take_claimed(&mut self) -> Vec<Bytes> {
+        self.claimed
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Queue bytes as they are, bypassing reliable delivery and the claim of a transfer
+    pub(crate) fn send_raw(&self, bytes: Bytes) -> Result<(), SerialError> {
+        push_send(&self.send_queue, &self.port_name, bytes)
+    }
+
+    /// Set a modem line or change the baud rate, without waiting for queued messages to be
+    /// written. Recorded by a [`MockPort`], ignored by a replayed port
+    pub fn control(&self, control: PortControl) -> Result<(), SerialError> {
+        match &self.driver {
+            PortDriver::Tasks(tasks) => tasks.control(control),
+            PortDriver::Virtual(driver) => {
+                driver.control(control);
+                Ok(())
+            }
+        }
+    }
+
+    /// Hand every queued message to the writer task, starting the timeouts of the
+    /// transactions and reliable frames sent
+    pub fn flush(&mut self) {
+        self.send_queue.release();
+        let now = self.clock.now();
+        self.transactions.flushed(now);
+        if let Some(reliable) = &mut self.reliable {
+            reliable.flushed(now);
+        }
+        if let PortDriver::Virtual(driver) = &self.driver {
+            driver.flush();
+        }
+    }
+
+    pub fn get_messages(&mut self) -> Vec<SerialFrame> {
+        if let PortDriver::Virtual(driver) = &self.driver {
+            driver.poll();
+        }
+        let frames = self.recv_queue.drain();
+        let Self {
+            port_name,
+            send_queue,
+            reliable: Some(reliable),
+            ..
+        } = self
+        else {
+            return frames;
+        };
+        frames
+            .into_iter()
+            .flat_map(|frame| {
+                reliable.receive(frame, |frame| push_send(send_queue, port_name, frame))
+            })
+            .collect()
+    }
+
+    /// Write out every queued message, then stop the reader and writer.
+    ///
+    /// Waits at most `timeout` for the queued messages to be written and drained by the port,
+    /// failing with [`SerialError::CloseTimeout`] if they weren't.
+    pub fn close(&mut self, timeout: Duration) -> Result<(), SerialError> {
+        let deadline = Instant::now() + timeout;
+        self.send_queue.close();
+        let flushed = match &self.driver {
+            PortDriver::Tasks(tasks) => tasks.join_writer(deadline),
+            PortDriver::Virtual(driver) => {
+                driver.flush();
+                true
+            }
+        };
+        self.recv_queue.close();
+        if let PortDriver::Tasks(tasks) = &self.driver {
+            tasks.stop(deadline);
+        }
+
+        if flushed {
+            Ok(())
+        } else {
+            Err(SerialError::CloseTimeout(self.port_name.clone()))
+        }
+    }
+}
+
+/// Queue a message, through reliable delivery if the port has it
+fn send(
+    send_queue: &SendQueue,
+    reliable: &mut Option<Reliable>,
+    port_name: &str,
+    message: Bytes,
+) -> Result<(), SerialError> {
+    match reliable {
+        Some(reliable) => reliable.send(message, |frame| push_send(send_queue, port_name, frame)),
+        None => push_send(send_queue, port_name, message),
+    }
+}
+
+fn push_send(send_queue: &SendQueue, port_name: &str, message: Bytes) -> Result<(), SerialError> {
+    send_queue.push(message).map_err(|err| match err {
+        PushError::Full => SerialError::QueueFull(port_name.to_string()),
+        PushError::Closed => SerialError::PortClosed(port_name.to_string()),
+    })
+}
+
+impl Drop for SerialPortWrap {
+    fn drop(&mut self) {
+        // let the writer finish in the background, but stop reading right away
+        self.send_queue.close();
+        self.recv_queue.close();
+        if let PortDriver::Tasks(tasks) = &self.driver {
+            tasks.reader.abort();
+        }
     }
 }
@@ -0,0 +1,96 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use parking_lot::Mutex;
+
+use crate::clock::Clock;
+
+/// Traffic counters of a port, updated by its io tasks
+#[derive(Debug, Default)]
+pub struct SerialStats {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    frames_read: AtomicU64,
+    frames_written: AtomicU64,
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+    last_activity: Mutex<Option<Instant>>,
+    clock: Clock,
+}
+
+impl SerialStats {
+    pub(crate) fn new(clock: Clock) -> Self {
+        Self {
+            clock,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn record_read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub(crate) fn record_frame_read(&self) {
+        self.frames_read.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.frames_written.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub(crate) fn record_read_error(&self) {
+        self.read_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_write_error(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock() = Some(self.clock.now());
+    }
+
+    pub fn last_activity(&self) -> Option<Instant> {
+        *self.last_activity.lock()
+    }
+
+    pub(crate) fn snapshot(&self) -> SerialPortStats {
+        SerialPortStats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            frames_read: self.frames_read.load(Ordering::Relaxed),
+            frames_written: self.frames_written.load(Ordering::Relaxed),
+            read_errors: self.read_errors.load(Ordering::Relaxed),
+            write_errors: self.write_errors.load(Ordering::Relaxed),
+            last_activity: self.last_activity(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Snapshot of the traffic of a port, see [`SerialResource::stats`](crate::SerialResource::stats)
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SerialPortStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub frames_read: u64,
+    pub frames_written: u64,
+    pub read_errors: u64,
+    pub write_errors: u64,
+    /// Frames discarded because the receive queue was full
+    pub dropped_received: u64,
+    /// Frames discarded because the send queue was full
+    pub dropped_sent: u64,
+    /// Frames waiting to be written
+    pub send_queue_len: usize,
+    /// Frames read and not yet broadcast
+    pub recv_queue_len: usize,
+    /// Time of the last successful read or write
+    pub last_activity: Option<Instant>,
+}
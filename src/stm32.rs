@@ -0,0 +1,1123 @@
+//! The STM32 system memory bootloader over UART, as described in ST's AN3155.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::{
+    transfer::{BootloaderProgress, Sessions, Transfer, TransferIo},
+    PortControl, SerialError, SerialResource,
+};
+
+/// Sent first, for the bootloader to measure the baud rate
+const SYNC: u8 = 0x7F;
+const ACK: u8 = 0x79;
+const NACK: u8 = 0x1F;
+
+const GET: u8 = 0x00;
+const GET_ID: u8 = 0x02;
+const READ_MEMORY: u8 = 0x11;
+const GO: u8 = 0x21;
+const WRITE_MEMORY: u8 = 0x31;
+const ERASE: u8 = 0x43;
+const EXTENDED_ERASE: u8 = 0x44;
+
+/// Most bytes read or written by one command
+const CHUNK: usize = 256;
+/// Pages erased by one command, to report progress in between
+const ERASE_PAGES: usize = 16;
+const SYNC_ATTEMPTS: u32 = 5;
+
+/// A command run by [`Stm32Bootloader::run`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stm32Command {
+    /// Bootloader version and the commands it supports
+    Get,
+    /// Product ID of the chip
+    GetId,
+    ReadMemory {
+        address: u32,
+        len: usize,
+    },
+    Erase(Stm32Erase),
+    /// `data` is padded with `0xFF` to a multiple of 4 bytes
+    WriteMemory {
+        address: u32,
+        data: Bytes,
+    },
+    /// Read memory back and compare it with `data`
+    Verify {
+        address: u32,
+        data: Bytes,
+    },
+    /// Start the application at `address`, nothing can follow
+    Go {
+        address: u32,
+    },
+}
+
+/// What to erase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stm32Erase {
+    Mass,
+    Pages(Vec<u16>),
+}
+
+/// What a command returned, one per command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stm32Reply {
+    Get {
+        version: u8,
+        commands: Vec<u8>,
+    },
+    Id(u16),
+    Read(Bytes),
+    /// Erase, write, verify or go succeeded
+    Done,
+}
+
+/// How to get into the bootloader and back out, and how long to wait for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stm32Options {
+    /// Modem line changes, each followed by a wait, starting the bootloader
+    pub enter: Vec<(PortControl, Duration)>,
+    /// Modem line changes, each followed by a wait, starting the application once the commands
+    /// are done, failed or were cancelled, unless they ended with [`Stm32Command::Go`]
+    pub exit: Vec<(PortControl, Duration)>,
+    /// Longest wait for a reply
+    pub timeout: Duration,
+    /// Longest wait for an erase, which takes seconds on large parts
+    pub erase_timeout: Duration,
+}
+
+impl Default for Stm32Options {
+    fn default() -> Self {
+        Self {
+            enter: Vec::new(),
+            exit: Vec::new(),
+            timeout: Duration::from_secs(1),
+            erase_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Stm32Options {
+    /// Reset into the bootloader with `reset` wired to NRST and `boot0` to BOOT0, asserting a
+    /// line holding the chip in reset or selecting the bootloader, e.g.
+    /// `with_reset_lines(PortControl::Dtr, PortControl::Rts)`. Reset again with BOOT0 released
+    /// once done
+    pub fn with_reset_lines(
+        mut self,
+        reset: fn(bool) -> PortControl,
+        boot0: fn(bool) -> PortControl,
+    ) -> Self {
+        let pulse = Duration::from_millis(100);
+        self.enter = vec![
+            (boot0(true), Duration::ZERO),
+            (reset(true), pulse),
+            (reset(false), pulse),
+        ];
+        self.exit = vec![
+            (boot0(false), Duration::ZERO),
+            (reset(true), pulse),
+            (reset(false), Duration::ZERO),
+        ];
+        self
+    }
+}
+
+/// Outcome of a session started with [`Stm32Bootloader::run`]
+#[derive(Debug, Event)]
+pub struct Stm32Result {
+    pub port: String,
+    /// The ID the session was started with
+    pub id: u64,
+    /// A reply for each command
+    pub result: Result<Vec<Stm32Reply>, SerialError>,
+}
+
+/// Sessions with STM32 bootloaders, one per port. Each ends with a [`Stm32Result`] event and
+/// reports [`BootloaderProgress`] on the way.
+///
+/// The bootloader talks 8 data bits with [`Parity::Even`](crate::Parity), at up to 115200 baud.
+#[derive(Resource)]
+pub struct Stm32Bootloader {
+    sessions: Sessions<Vec<Stm32Reply>>,
+}
+
+impl Default for Stm32Bootloader {
+    fn default() -> Self {
+        Self {
+            sessions: Sessions::new(
+                SerialError::BootloaderFailed,
+                SerialError::BootloaderCancelled,
+            ),
+        }
+    }
+}
+
+impl Stm32Bootloader {
+    /// Start the bootloader of the chip on `port`, run `commands` and start the application,
+    /// once [`SerialSet::Receive`](crate::SerialSet) next runs
+    pub fn run(
+        &mut self,
+        port: impl ToString,
+        id: u64,
+        options: Stm32Options,
+        commands: Vec<Stm32Command>,
+    ) {
+        self.sessions.start(port.to_string(), id, move || {
+            Ok(Box::new(Stm32Session::new(options, commands)))
+        });
+    }
+
+    /// Abort the session on `port`, ending it with [`SerialError::BootloaderCancelled`] once
+    /// the exit sequence ran. `false` if there was none
+    pub fn cancel(&mut self, port: &str) -> bool {
+        self.sessions.cancel(port)
+    }
+
+    /// Whether a session runs or is about to start on `port`
+    pub fn is_active(&self, port: &str) -> bool {
+        self.sessions.is_active(port)
+    }
+}
+
+pub(crate) fn drive_stm32(
+    mut bootloader: ResMut<Stm32Bootloader>,
+    mut serial_res: ResMut<SerialResource>,
+    mut progress_ev: EventWriter<BootloaderProgress>,
+    mut result_ev: EventWriter<Stm32Result>,
+) {
+    bootloader.sessions.drive(
+        &mut serial_res,
+        |port, id, (stage, done, total)| {
+            progress_ev.send(BootloaderProgress {
+                port: port.to_string(),
+                id,
+                stage,
+                done,
+                total,
+            });
+        },
+        |ended| {
+            result_ev.send(Stm32Result {
+                port: ended.port,
+                id: ended.id,
+                result: ended.result,
+            });
+        },
+    );
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Expect {
+    Ack,
+    /// a count N, then N + 1 bytes
+    Counted,
+    Bytes(usize),
+}
+
+/// What to do with the bytes an exchange got back
+enum Then {
+    Nothing,
+    Get {
+        reply: bool,
+    },
+    Id,
+    Read {
+        done: usize,
+        total: usize,
+    },
+    Verify {
+        address: u32,
+        expected: Bytes,
+        done: usize,
+        total: usize,
+    },
+    /// report progress, and the command as done once `done` reaches `total`
+    Progress {
+        stage: &'static str,
+        done: usize,
+        total: usize,
+    },
+    Reply(Stm32Reply),
+}
+
+/// Bytes sent and the reply expected
+struct Exchange {
+    what: &'static str,
+    send: Vec<u8>,
+    expect: VecDeque<Expect>,
+    timeout: Duration,
+    then: Then,
+}
+
+enum Step {
+    Control(PortControl),
+    Wait(Duration),
+    Exchange(Exchange),
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, &b| sum ^ b)
+}
+
+/// An address followed by its checksum
+fn address(address: u32) -> Vec<u8> {
+    let mut bytes = address.to_be_bytes().to_vec();
+    bytes.push(checksum(&bytes));
+    bytes
+}
+
+/// The address `offset` bytes past `address`, failing past the end of the address space
+fn offset(address: u32, offset: usize) -> Result<u32, String> {
+    u32::try_from(offset)
+        .ok()
+        .and_then(|offset| address.checked_add(offset))
+        .ok_or_else(|| {
+            format!("{offset:#x} bytes past {address:#010x} is out of the address space")
+        })
+}
+
+/// Bytes followed by their checksum
+fn checked(mut bytes: Vec<u8>) -> Vec<u8> {
+    bytes.push(checksum(&bytes));
+    bytes
+}
+
+pub(crate) struct Stm32Session {
+    options: Stm32Options,
+    commands: VecDeque<Stm32Command>,
+    steps: VecDeque<Step>,
+    /// the exchange awaiting its reply and its deadline
+    current: Option<(Exchange, Instant)>,
+    received: Vec<u8>,
+    buf: BytesMut,
+    waiting: Option<Instant>,
+    sync_attempts: u32,
+    extended_erase: bool,
+    read: BytesMut,
+    replies: Vec<Stm32Reply>,
+    /// the exit sequence was queued, or the application started with Go
+    exited: bool,
+    /// why the session ends once the exit sequence ran
+    failure: Option<String>,
+}
+
+impl Stm32Session {
+    pub(crate) fn new(options: Stm32Options, commands: Vec<Stm32Command>) -> Self {
+        let mut session = Self {
+            options,
+            commands: commands.into(),
+            steps: VecDeque::new(),
+            current: None,
+            received: Vec::new(),
+            buf: BytesMut::new(),
+            waiting: None,
+            sync_attempts: 0,
+            extended_erase: false,
+            read: BytesMut::new(),
+            replies: Vec::new(),
+            exited: false,
+            failure: None,
+        };
+        session.lines(session.options.enter.clone());
+        session.exchange("sync", vec![SYNC], &[Expect::Ack], Then::Nothing);
+        // which erase command the bootloader has
+        session.command(
+            "get",
+            GET,
+            &[Expect::Ack, Expect::Counted, Expect::Ack],
+            Then::Get { reply: false },
+        );
+        session
+    }
+
+    fn lines(&mut self, sequence: Vec<(PortControl, Duration)>) {
+        for (control, wait) in sequence {
+            self.steps.push_back(Step::Control(control));
+            self.steps.push_back(Step::Wait(wait));
+        }
+    }
+
+    fn exchange(&mut self, what: &'static str, send: Vec<u8>, expect: &[Expect], then: Then) {
+        self.steps.push_back(Step::Exchange(Exchange {
+            what,
+            send,
+            expect: expect.iter().copied().collect(),
+            timeout: self.options.timeout,
+            then,
+        }));
+    }
+
+    fn command(&mut self, what: &'static str, code: u8, expect: &[Expect], then: Then) {
+        self.exchange(what, vec![code, !code], expect, then);
+    }
+
+    /// Queue the exchanges of a command
+    fn expand(&mut self, command: Stm32Command) -> Result<(), String> {
+        match command {
+            Stm32Command::Get => self.command(
+                "get",
+                GET,
+                &[Expect::Ack, Expect::Counted, Expect::Ack],
+                Then::Get { reply: true },
+            ),
+            Stm32Command::GetId => self.command(
+                "get ID",
+                GET_ID,
+                &[Expect::Ack, Expect::Counted, Expect::Ack],
+                Then::Id,
+            ),
+            Stm32Command::ReadMemory { address, len } => {
+                if len == 0 {
+                    self.replies.push(Stm32Reply::Read(Bytes::new()));
+                }
+                self::offset(address, len.saturating_sub(1))?;
+                for offset in (0..len).step_by(CHUNK) {
+                    let n = (len - offset).min(CHUNK);
+                    let then = Then::Read {
+                        done: offset + n,
+                        total: len,
+                    };
+                    self.read_chunk(self::offset(address, offset)?, n, then);
+                }
+            }
+            Stm32Command::Verify { address, data } => {
+                if data.is_empty() {
+                    self.replies.push(Stm32Reply::Done);
+                }
+                self::offset(address, data.len().saturating_sub(1))?;
+                for offset in (0..data.len()).step_by(CHUNK) {
+                    let n = (data.len() - offset).min(CHUNK);
+                    let chunk_address = self::offset(address, offset)?;
+                    let then = Then::Verify {
+                        address: chunk_address,
+                        expected: data.slice(offset..offset + n),
+                        done: offset + n,
+                        total: data.len(),
+                    };
+                    self.read_chunk(chunk_address, n, then);
+                }
+            }
+            Stm32Command::WriteMemory { address, data } => {
+                let mut data = data.to_vec();
+                data.resize(data.len().next_multiple_of(4), 0xFF);
+                if data.is_empty() {
+                    self.replies.push(Stm32Reply::Done);
+                }
+                self::offset(address, data.len().saturating_sub(1))?;
+                for (i, chunk) in data.chunks(CHUNK).enumerate() {
+                    let offset = i * CHUNK;
+                    let chunk_address = self::offset(address, offset)?;
+                    self.command("write memory", WRITE_MEMORY, &[Expect::Ack], Then::Nothing);
+                    self.exchange(
+                        "write memory",
+                        self::address(chunk_address),
+                        &[Expect::Ack],
+                        Then::Nothing,
+                    );
+                    let mut send = vec![(chunk.len() - 1) as u8];
+                    send.extend_from_slice(chunk);
+                    let then = Then::Progress {
+                        stage: "write",
+                        done: offset + chunk.len(),
+                        total: data.len(),
+                    };
+                    self.exchange("write memory", checked(send), &[Expect::Ack], then);
+                }
+            }
+            Stm32Command::Erase(erase) => self.erase(erase)?,
+            Stm32Command::Go { address } => {
+                self.command("go", GO, &[Expect::Ack], Then::Nothing);
+                self.exchange(
+                    "go",
+                    self::address(address),
+                    &[Expect::Ack],
+                    Then::Reply(Stm32Reply::Done),
+                );
+                self.exited = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_chunk(&mut self, address: u32, len: usize, then: Then) {
+        self.command("read memory", READ_MEMORY, &[Expect::Ack], Then::Nothing);
+        self.exchange(
+            "read memory",
+            self::address(address),
+            &[Expect::Ack],
+            Then::Nothing,
+        );
+        let n = (len - 1) as u8;
+        self.exchange(
+            "read memory",
+            vec![n, !n],
+            &[Expect::Ack, Expect::Bytes(len)],
+            then,
+        );
+    }
+
+    fn erase(&mut self, erase: Stm32Erase) -> Result<(), String> {
+        let pages = match erase {
+            Stm32Erase::Mass => {
+                // the legacy global erase is 0xFF with the complement as its checksum
+                let send = if self.extended_erase {
+                    checked(vec![0xFF, 0xFF])
+                } else {
+                    vec![0xFF, 0x00]
+                };
+                self.erase_exchange(send, 1, 1);
+                return Ok(());
+            }
+            Stm32Erase::Pages(pages) => pages,
+        };
+        if pages.is_empty() {
+            self.replies.push(Stm32Reply::Done);
+        }
+        for (i, group) in pages.chunks(ERASE_PAGES).enumerate() {
+            let mut send = Vec::new();
+            if self.extended_erase {
+                send.extend_from_slice(&(group.len() as u16 - 1).to_be_bytes());
+                for page in group {
+                    send.extend_from_slice(&page.to_be_bytes());
+                }
+            } else {
+                send.push(group.len() as u8 - 1);
+                for &page in group {
+                    let page = u8::try_from(page)
+                        .map_err(|_| format!("page {page} needs the extended erase command"))?;
+                    send.push(page);
+                }
+            }
+            self.erase_exchange(checked(send), i * ERASE_PAGES + group.len(), pages.len());
+        }
+        Ok(())
+    }
+
+    /// Erase what `send` selects, checksum included, waiting longer for the reply
+    fn erase_exchange(&mut self, send: Vec<u8>, done: usize, total: usize) {
+        let code = if self.extended_erase {
+            EXTENDED_ERASE
+        } else {
+            ERASE
+        };
+        self.command("erase", code, &[Expect::Ack], Then::Nothing);
+        self.steps.push_back(Step::Exchange(Exchange {
+            what: "erase",
+            send,
+            expect: [Expect::Ack].into(),
+            timeout: self.options.erase_timeout,
+            then: Then::Progress {
+                stage: "erase",
+                done,
+                total,
+            },
+        }));
+    }
+
+    /// Run steps until one needs a reply or time to pass
+    fn advance(&mut self, now: Instant, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        while self.current.is_none() && io.outcome.is_none() {
+            if let Some(until) = self.waiting {
+                if now < until {
+                    return;
+                }
+                self.waiting = None;
+            }
+            let Some(step) = self.steps.pop_front() else {
+                if let Some(command) = self.commands.pop_front() {
+                    if let Err(reason) = self.expand(command) {
+                        io.fail(reason);
+                    }
+                } else if !self.exited {
+                    self.exited = true;
+                    self.lines(self.options.exit.clone());
+                } else if let Some(reason) = self.failure.take() {
+                    io.fail(reason);
+                } else {
+                    io.finish(std::mem::take(&mut self.replies));
+                }
+                continue;
+            };
+            match step {
+                Step::Control(control) => io.control.push(control),
+                Step::Wait(wait) => {
+                    if !wait.is_zero() {
+                        self.waiting = Some(now + wait);
+                    }
+                }
+                Step::Exchange(exchange) => {
+                    io.send(exchange.send.clone());
+                    self.received.clear();
+                    let deadline = now + exchange.timeout;
+                    self.current = Some((exchange, deadline));
+                }
+            }
+        }
+    }
+
+    fn complete(&mut self, then: Then, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        let received = std::mem::take(&mut self.received);
+        match then {
+            Then::Nothing => {}
+            Then::Get { reply } => {
+                let Some((&version, commands)) = received.split_first() else {
+                    io.fail("empty reply to get");
+                    return;
+                };
+                self.extended_erase = commands.contains(&EXTENDED_ERASE);
+                if reply {
+                    self.replies.push(Stm32Reply::Get {
+                        version,
+                        commands: commands.to_vec(),
+                    });
+                }
+            }
+            Then::Id => match received[..] {
+                [high, low] => self
+                    .replies
+                    .push(Stm32Reply::Id(u16::from_be_bytes([high, low]))),
+                _ => io.fail(format!("product ID of {} bytes", received.len())),
+            },
+            Then::Read { done, total } => {
+                self.read.extend_from_slice(&received);
+                io.progress("read", done as u64, Some(total as u64));
+                if done == total {
+                    let data = std::mem::take(&mut self.read).freeze();
+                    self.replies.push(Stm32Reply::Read(data));
+                }
+            }
+            Then::Verify {
+                address,
+                expected,
+                done,
+                total,
+            } => {
+                if let Some(i) = (0..expected.len()).find(|&i| received[i] != expected[i]) {
+                    io.fail(match offset(address, i) {
+                        Ok(at) => format!(
+                            "verify failed at {:#010x}: read {:#04x}, expected {:#04x}",
+                            at, received[i], expected[i]
+                        ),
+                        Err(reason) => reason,
+                    });
+                    return;
+                }
+                self.progress("verify", done, total, io);
+            }
+            Then::Progress { stage, done, total } => self.progress(stage, done, total, io),
+            Then::Reply(reply) => self.replies.push(reply),
+        }
+    }
+
+    fn progress(
+        &mut self,
+        stage: &str,
+        done: usize,
+        total: usize,
+        io: &mut TransferIo<Vec<Stm32Reply>>,
+    ) {
+        io.progress(stage, done as u64, Some(total as u64));
+        if done == total {
+            self.replies.push(Stm32Reply::Done);
+        }
+    }
+}
+
+impl Stm32Session {
+    /// Feed what was read to the exchange awaiting its reply
+    fn read(&mut self, data: &[u8], now: Instant, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        self.buf.extend_from_slice(data);
+        while let Some((exchange, _)) = &mut self.current {
+            let Some(&expect) = exchange.expect.front() else {
+                break;
+            };
+            match expect {
+                Expect::Ack => {
+                    let Some(&byte) = self.buf.first() else {
+                        return;
+                    };
+                    self.buf.advance(1);
+                    let sync = exchange.send == [SYNC];
+                    match byte {
+                        // a NACK to the sync means the baud rate is known already
+                        ACK | NACK if sync => {}
+                        ACK => {}
+                        NACK => {
+                            io.fail(format!("the bootloader refused {}", exchange.what));
+                            return;
+                        }
+                        // noise while the chip resets
+                        _ if sync => continue,
+                        _ => {
+                            io.fail(format!("unexpected reply {byte:#04x} to {}", exchange.what));
+                            return;
+                        }
+                    }
+                    exchange.expect.pop_front();
+                }
+                Expect::Counted => {
+                    let Some(&n) = self.buf.first() else {
+                        return;
+                    };
+                    self.buf.advance(1);
+                    exchange.expect[0] = Expect::Bytes(n as usize + 1);
+                }
+                Expect::Bytes(n) => {
+                    if self.buf.len() < n {
+                        return;
+                    }
+                    self.received.extend_from_slice(&self.buf.split_to(n));
+                    exchange.expect.pop_front();
+                }
+            }
+            if exchange.expect.is_empty() {
+                let (exchange, _) = self.current.take().expect("checked above");
+                self.complete(exchange.then, io);
+                self.advance(now, io);
+                if io.outcome.is_some() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Retry the sync or give up on an exchange past its deadline
+    fn timeout(&mut self, now: Instant, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        let Some((exchange, deadline)) = &mut self.current else {
+            self.advance(now, io);
+            return;
+        };
+        if now < *deadline {
+            return;
+        }
+        if exchange.send == [SYNC] && self.sync_attempts < SYNC_ATTEMPTS {
+            self.sync_attempts += 1;
+            *deadline = now + exchange.timeout;
+            io.send(vec![SYNC]);
+            return;
+        }
+        io.fail(format!("no reply to {}", exchange.what));
+    }
+
+    /// Drop what is left to do and run the exit sequence, before failing with `reason`
+    fn abort(&mut self, reason: String, now: Instant, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        self.commands.clear();
+        self.current = None;
+        if !self.exited {
+            self.exited = true;
+            self.steps.clear();
+            self.waiting = None;
+            self.lines(self.options.exit.clone());
+        }
+        self.failure = Some(reason);
+        self.advance(now, io);
+    }
+
+    /// Take the device out of the bootloader before reporting a failure
+    fn exit_on_failure(&mut self, now: Instant, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        if self.exited {
+            return;
+        }
+        if let Some(Err(reason)) = &mut io.outcome {
+            let reason = std::mem::take(reason);
+            io.outcome = None;
+            self.abort(reason, now, io);
+        }
+    }
+}
+
+impl Transfer<Vec<Stm32Reply>> for Stm32Session {
+    fn start(&mut self, now: Instant, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        self.advance(now, io);
+        self.exit_on_failure(now, io);
+    }
+
+    fn receive(&mut self, data: &[u8], now: Instant, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        self.read(data, now, io);
+        self.exit_on_failure(now, io);
+    }
+
+    fn update(&mut self, now: Instant, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        self.timeout(now, io);
+        self.exit_on_failure(now, io);
+    }
+
+    /// The bootloader has nothing to abort, it waits for the next command
+    fn cancel(&mut self, now: Instant, io: &mut TransferIo<Vec<Stm32Reply>>) {
+        self.abort("cancelled".to_string(), now, io);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FLASH: u32 = 0x0800_0000;
+    const PAGE: usize = 1024;
+
+    #[derive(Debug, Clone, Copy)]
+    enum DeviceState {
+        Idle,
+        Address(u8),
+        Length(u8, u32),
+        Erase,
+    }
+
+    /// A bootloader with 16 KiB of flash, answering whole messages
+    struct Device {
+        synced: bool,
+        state: DeviceState,
+        flash: Vec<u8>,
+        /// bit flipped in every byte written at this offset
+        faulty: Option<usize>,
+        silent: bool,
+        started: Option<u32>,
+        /// offers the erase command 0x43 instead of the extended one
+        legacy: bool,
+    }
+
+    impl Device {
+        fn new() -> Self {
+            Self {
+                synced: false,
+                state: DeviceState::Idle,
+                flash: vec![0xFF; 16 * PAGE],
+                faulty: None,
+                silent: false,
+                started: None,
+                legacy: false,
+            }
+        }
+
+        fn offset(&self, address: u32) -> usize {
+            (address - FLASH) as usize
+        }
+
+        fn handle(&mut self, msg: &[u8]) -> Vec<u8> {
+            if self.silent {
+                return Vec::new();
+            }
+            if !self.synced {
+                self.synced = msg == [SYNC];
+                return vec![ACK];
+            }
+            let valid = |bytes: &[u8]| checksum(bytes) == 0;
+            match self.state {
+                DeviceState::Idle => {
+                    let [code, inverse] = msg[..] else {
+                        return vec![NACK];
+                    };
+                    if code != !inverse {
+                        return vec![NACK];
+                    }
+                    match code {
+                        GET => {
+                            let erase = if self.legacy { ERASE } else { EXTENDED_ERASE };
+                            let commands = [GET, GET_ID, READ_MEMORY, GO, WRITE_MEMORY, erase];
+                            let mut reply = vec![ACK, commands.len() as u8, 0x31];
+                            reply.extend(commands);
+                            reply.push(ACK);
+                            reply
+                        }
+                        GET_ID => vec![ACK, 1, 0x04, 0x10, ACK],
+                        READ_MEMORY | GO | WRITE_MEMORY => {
+                            self.state = DeviceState::Address(code);
+                            vec![ACK]
+                        }
+                        ERASE | EXTENDED_ERASE if (code == ERASE) == self.legacy => {
+                            self.state = DeviceState::Erase;
+                            vec![ACK]
+                        }
+                        _ => vec![NACK],
+                    }
+                }
+                DeviceState::Address(code) => {
+                    if msg.len() != 5 || !valid(msg) {
+                        self.state = DeviceState::Idle;
+                        return vec![NACK];
+                    }
+                    let address = u32::from_be_bytes([msg[0], msg[1], msg[2], msg[3]]);
+                    if !(FLASH..FLASH + self.flash.len() as u32).contains(&address) {
+                        self.state = DeviceState::Idle;
+                        return vec![NACK];
+                    }
+                    self.state = if code == GO {
+                        self.started = Some(address);
+                        DeviceState::Idle
+                    } else {
+                        DeviceState::Length(code, address)
+                    };
+                    vec![ACK]
+                }
+                DeviceState::Length(code, address) => {
+                    self.state = DeviceState::Idle;
+                    let n = msg[0] as usize + 1;
+                    let offset = self.offset(address);
+                    if code == READ_MEMORY {
+                        let mut reply = vec![ACK];
+                        reply.extend_from_slice(&self.flash[offset..offset + n]);
+                        return reply;
+                    }
+                    if msg.len() != n + 2 || !valid(msg) {
+                        return vec![NACK];
+                    }
+                    for (i, &byte) in msg[1..=n].iter().enumerate() {
+                        self.flash[offset + i] &= byte;
+                        if self.faulty == Some(offset + i) {
+                            self.flash[offset + i] ^= 1;
+                        }
+                    }
+                    vec![ACK]
+                }
+                DeviceState::Erase => {
+                    self.state = DeviceState::Idle;
+                    let global: &[u8] = if self.legacy {
+                        &[0xFF, 0x00]
+                    } else {
+                        &[0xFF, 0xFF, 0x00]
+                    };
+                    if msg == global {
+                        self.flash.fill(0xFF);
+                        return vec![ACK];
+                    }
+                    if !valid(msg) {
+                        return vec![NACK];
+                    }
+                    let pages: Vec<usize> = if self.legacy {
+                        msg[1..msg.len() - 1]
+                            .iter()
+                            .map(|&page| page as usize)
+                            .collect()
+                    } else {
+                        msg[2..msg.len() - 1]
+                            .chunks(2)
+                            .map(|page| u16::from_be_bytes([page[0], page[1]]) as usize)
+                            .collect()
+                    };
+                    for page in pages {
+                        self.flash[page * PAGE..(page + 1) * PAGE].fill(0xFF);
+                    }
+                    vec![ACK]
+                }
+            }
+        }
+    }
+
+    type Outcome = (
+        Result<Vec<Stm32Reply>, String>,
+        Vec<PortControl>,
+        Vec<String>,
+    );
+
+    fn run(device: &mut Device, options: Stm32Options, commands: Vec<Stm32Command>) -> Outcome {
+        let mut now = Instant::now();
+        let mut session = Stm32Session::new(options, commands);
+        let mut io = TransferIo::default();
+        let mut controls = Vec::new();
+        let mut stages = Vec::new();
+        session.start(now, &mut io);
+        for _ in 0..10_000 {
+            controls.append(&mut io.control);
+            if let Some(outcome) = io.outcome.take() {
+                return (outcome, controls, stages);
+            }
+            for (stage, _, _) in io.progress.drain(..) {
+                if stages.last() != Some(&stage) {
+                    stages.push(stage);
+                }
+            }
+            let out = std::mem::take(&mut io.out);
+            if out.is_empty() {
+                now += Duration::from_millis(50);
+                session.update(now, &mut io);
+            }
+            for msg in out {
+                let reply = device.handle(&msg);
+                session.receive(&reply, now, &mut io);
+            }
+        }
+        panic!("the session did not end");
+    }
+
+    #[test]
+    fn flash_and_start() {
+        let firmware: Bytes = (0..3000u32).map(|i| i as u8).collect::<Vec<_>>().into();
+        let mut device = Device::new();
+        device.flash[5 * PAGE] = 0;
+        let options = Stm32Options::default().with_reset_lines(PortControl::Dtr, PortControl::Rts);
+        let commands = vec![
+            Stm32Command::Get,
+            Stm32Command::GetId,
+            Stm32Command::Erase(Stm32Erase::Pages(vec![0, 1, 2, 5])),
+            Stm32Command::WriteMemory {
+                address: FLASH,
+                data: firmware.clone(),
+            },
+            Stm32Command::Verify {
+                address: FLASH,
+                data: firmware.clone(),
+            },
+            Stm32Command::ReadMemory {
+                address: FLASH + 2998,
+                len: 4,
+            },
+            Stm32Command::Go { address: FLASH },
+        ];
+        let (replies, controls, stages) = run(&mut device, options, commands);
+        let replies = replies.unwrap();
+        assert_eq!(
+            replies[0],
+            Stm32Reply::Get {
+                version: 0x31,
+                commands: vec![GET, GET_ID, READ_MEMORY, GO, WRITE_MEMORY, 0x44]
+            }
+        );
+        assert_eq!(replies[1], Stm32Reply::Id(0x0410));
+        assert_eq!(
+            replies[2..5],
+            [Stm32Reply::Done, Stm32Reply::Done, Stm32Reply::Done]
+        );
+        // padded to a multiple of 4
+        assert_eq!(
+            replies[5],
+            Stm32Reply::Read(Bytes::from_static(&[0xB6, 0xB7, 0xFF, 0xFF]))
+        );
+        assert_eq!(replies[6], Stm32Reply::Done);
+        assert_eq!(&device.flash[..3000], &firmware[..]);
+        assert_eq!(device.flash[5 * PAGE], 0xFF);
+        assert_eq!(device.started, Some(FLASH));
+        // reset into the bootloader, not out of it after Go
+        assert_eq!(
+            controls,
+            [
+                PortControl::Rts(true),
+                PortControl::Dtr(true),
+                PortControl::Dtr(false)
+            ]
+        );
+        assert_eq!(stages, ["erase", "write", "verify", "read"]);
+    }
+
+    #[test]
+    fn exit_after_failure_or_cancel() {
+        let options = Stm32Options::default().with_reset_lines(PortControl::Dtr, PortControl::Rts);
+        let enter = [
+            PortControl::Rts(true),
+            PortControl::Dtr(true),
+            PortControl::Dtr(false),
+        ];
+        let exit = [
+            PortControl::Rts(false),
+            PortControl::Dtr(true),
+            PortControl::Dtr(false),
+        ];
+
+        let mut device = Device::new();
+        device.silent = true;
+        let (result, controls, _) = run(&mut device, options.clone(), Vec::new());
+        assert_eq!(result, Err("no reply to sync".to_string()));
+        assert_eq!(controls, [enter, exit].concat());
+
+        let mut now = Instant::now();
+        let mut session = Stm32Session::new(options, vec![Stm32Command::GetId]);
+        let mut io = TransferIo::default();
+        session.start(now, &mut io);
+        while io.out.is_empty() {
+            now += Duration::from_millis(50);
+            session.update(now, &mut io);
+        }
+        session.cancel(now, &mut io);
+        while io.outcome.is_none() {
+            now += Duration::from_millis(50);
+            session.update(now, &mut io);
+        }
+        assert_eq!(io.outcome, Some(Err("cancelled".to_string())));
+        assert_eq!(io.control, [enter, exit].concat());
+        assert_eq!(io.out, [Bytes::from_static(&[SYNC])]);
+    }
+
+    #[test]
+    fn legacy_erase() {
+        let mut device = Device::new();
+        device.legacy = true;
+        device.flash.fill(0);
+        let commands = vec![Stm32Command::Erase(Stm32Erase::Pages(vec![1, 3]))];
+        let (result, _, _) = run(&mut device, Stm32Options::default(), commands);
+        assert_eq!(result, Ok(vec![Stm32Reply::Done]));
+        assert!(device.flash[PAGE..2 * PAGE].iter().all(|&b| b == 0xFF));
+        assert!(device.flash[3 * PAGE..4 * PAGE].iter().all(|&b| b == 0xFF));
+        assert_eq!(device.flash[0], 0);
+        assert_eq!(device.flash[2 * PAGE], 0);
+
+        let commands = vec![Stm32Command::Erase(Stm32Erase::Mass)];
+        let (result, _, _) = run(&mut device, Stm32Options::default(), commands);
+        assert_eq!(result, Ok(vec![Stm32Reply::Done]));
+        assert!(device.flash.iter().all(|&b| b == 0xFF));
+
+        let commands = vec![Stm32Command::Erase(Stm32Erase::Pages(vec![300]))];
+        let (result, _, _) = run(&mut device, Stm32Options::default(), commands);
+        assert_eq!(
+            result,
+            Err("page 300 needs the extended erase command".to_string())
+        );
+    }
+
+    #[test]
+    fn failures() {
+        let mut device = Device::new();
+        device.faulty = Some(700);
+        let data = Bytes::from(vec![0x5A; 1024]);
+        let commands = vec![
+            Stm32Command::Erase(Stm32Erase::Mass),
+            Stm32Command::WriteMemory {
+                address: FLASH,
+                data: data.clone(),
+            },
+            Stm32Command::Verify {
+                address: FLASH,
+                data,
+            },
+        ];
+        let (result, _, stages) = run(&mut device, Stm32Options::default(), commands);
+        assert_eq!(
+            result,
+            Err("verify failed at 0x080002bc: read 0x5b, expected 0x5a".to_string())
+        );
+        assert_eq!(stages, ["erase", "write", "verify"]);
+
+        let commands = vec![Stm32Command::WriteMemory {
+            address: 0x2000_0000,
+            data: Bytes::from_static(&[1, 2, 3, 4]),
+        }];
+        let (result, _, _) = run(&mut Device::new(), Stm32Options::default(), commands);
+        assert_eq!(
+            result,
+            Err("the bootloader refused write memory".to_string())
+        );
+
+        let mut device = Device::new();
+        device.silent = true;
+        let (result, _, _) = run(&mut device, Stm32Options::default(), Vec::new());
+        assert_eq!(result, Err("no reply to sync".to_string()));
+
+        // a range running past the top of the address space fails before it is read
+        let commands = vec![Stm32Command::ReadMemory {
+            address: 0xFFFF_FF80,
+            len: 0x100,
+        }];
+        let (result, _, stages) = run(&mut Device::new(), Stm32Options::default(), commands);
+        assert_eq!(
+            result,
+            Err("0xff bytes past 0xffffff80 is out of the address space".to_string())
+        );
+        assert!(stages.is_empty());
+    }
+}
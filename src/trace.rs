@@ -0,0 +1,105 @@
+//! Logging the traffic of a port frame by frame, see
+//! [`SerialPortSetting::trace_level`](crate::SerialPortSetting::trace_level).
+
+use std::fmt::Write;
+
+use bevy::utils::tracing::{event, span, Level, Span};
+
+use crate::capture::Direction;
+
+/// Dispatch to a tracing macro taking a constant level
+macro_rules! at_level {
+    ($macro:ident, $level:expr, $($arg:tt)+) => {
+        match $level {
+            Level::ERROR => $macro!(Level::ERROR, $($arg)+),
+            Level::WARN => $macro!(Level::WARN, $($arg)+),
+            Level::INFO => $macro!(Level::INFO, $($arg)+),
+            Level::DEBUG => $macro!(Level::DEBUG, $($arg)+),
+            _ => $macro!(Level::TRACE, $($arg)+),
+        }
+    };
+}
+
+/// Logs every frame of one direction of a port, inside a span carrying the port name
+pub(crate) struct Tracer {
+    level: Level,
+    span: Span,
+    /// numbers the frames when the codec doesn't
+    count: u64,
+}
+
+impl Tracer {
+    pub(crate) fn new(port: &str, level: Option<Level>) -> Option<Self> {
+        let level = level?;
+        Some(Self {
+            span: at_level!(span, level, "serial_port", port),
+            level,
+            count: 0,
+        })
+    }
+
+    /// Log a frame with its boundaries as the codec found them, `sequence` numbers it
+    pub(crate) fn frame(&mut self, direction: Direction, sequence: Option<u64>, data: &[u8]) {
+        let sequence = sequence.unwrap_or(self.count);
+        self.count = sequence + 1;
+        let arrow = match direction {
+            Direction::Rx => "<-",
+            Direction::Tx => "->",
+        };
+        let _span = self.span.enter();
+        at_level!(
+            event,
+            self.level,
+            "{arrow} frame {sequence}, {} bytes\n{}",
+            data.len(),
+            hex_dump(data)
+        );
+    }
+}
+
+/// 16 bytes per line: offset, hex and the printable ASCII characters
+pub(crate) fn hex_dump(data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        if i > 0 {
+            dump.push('\n');
+        }
+        let _ = write!(dump, "{:08x} ", i * 16);
+        for column in 0..16 {
+            if column == 8 {
+                dump.push(' ');
+            }
+            match line.get(column) {
+                Some(byte) => {
+                    let _ = write!(dump, " {byte:02x}");
+                }
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str("  |");
+        dump.extend(line.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push('|');
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump() {
+        assert_eq!(
+            hex_dump(b"Hello, serial world!\r\n"),
+            "00000000  48 65 6c 6c 6f 2c 20 73  65 72 69 61 6c 20 77 6f  |Hello, serial wo|\n\
+             00000010  72 6c 64 21 0d 0a                                 |rld!..|"
+        );
+        assert_eq!(hex_dump(b""), "");
+    }
+}
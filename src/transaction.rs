@@ -0,0 +1,370 @@
+//! Request/response transactions: a request sent to a port and the reply matched to it.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+use crate::{SerialData, SerialError};
+
+/// Accepts a frame as the reply
+pub type ReplyPredicate = Box<dyn Fn(&[u8]) -> bool + Send + Sync>;
+/// Finds the ID of the request a frame replies to
+pub type ReplyIdExtractor = Box<dyn Fn(&[u8]) -> Option<u64> + Send + Sync>;
+
+/// How the reply to a [`Transaction`] is recognised among the frames read from its port
+pub enum ReplyMatcher {
+    /// The first frame read after the request
+    NextFrame,
+    /// The first frame the predicate accepts
+    Predicate(ReplyPredicate),
+    /// The first frame the closure extracts this ID from
+    Id { id: u64, extract: ReplyIdExtractor },
+}
+
+impl ReplyMatcher {
+    fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            ReplyMatcher::NextFrame => true,
+            ReplyMatcher::Predicate(predicate) => predicate(data),
+            ReplyMatcher::Id { id, extract } => extract(data) == Some(*id),
+        }
+    }
+}
+
+impl fmt::Debug for ReplyMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplyMatcher::NextFrame => f.write_str("NextFrame"),
+            ReplyMatcher::Predicate(_) => f.write_str("Predicate"),
+            ReplyMatcher::Id { id, .. } => f.debug_struct("Id").field("id", id).finish(),
+        }
+    }
+}
+
+/// A request and how to wait for its reply, started with
+/// [`SerialResource::transact`](crate::SerialResource::transact) or
+/// [`SerialResource::transact_async`](crate::SerialResource::transact_async).
+///
+/// Replies are taken out of the frames read from the port, so they are not sent as
+/// [`SerialData`] events.
+#[derive(Debug)]
+pub struct Transaction {
+    pub request: Bytes,
+    pub matcher: ReplyMatcher,
+    /// How long to wait for the reply to each attempt, from when [`SerialSet::Flush`](crate::SerialSet)
+    /// hands it to the writer
+    pub timeout: Duration,
+    /// How often to send the request again when no reply came in time
+    pub retries: u32,
+    /// Transactions queued behind [`SerialPortSetting::max_in_flight`](crate::SerialPortSetting::max_in_flight)
+    /// are sent highest priority first
+    pub priority: i32,
+}
+
+impl Transaction {
+    /// Wait one second for the next frame, without retries
+    pub fn new(request: Bytes) -> Self {
+        Self {
+            request,
+            matcher: ReplyMatcher::NextFrame,
+            timeout: Duration::from_secs(1),
+            retries: 0,
+            priority: 0,
+        }
+    }
+
+    /// Take the first frame the predicate accepts as the reply
+    pub fn matching(mut self, predicate: impl Fn(&[u8]) -> bool + Send + Sync + 'static) -> Self {
+        self.matcher = ReplyMatcher::Predicate(Box::new(predicate));
+        self
+    }
+
+    /// Take the first frame `extract` finds `id` in as the reply
+    pub fn matching_id(
+        mut self,
+        id: u64,
+        extract: impl Fn(&[u8]) -> Option<u64> + Send + Sync + 'static,
+    ) -> Self {
+        self.matcher = ReplyMatcher::Id {
+            id,
+            extract: Box::new(extract),
+        };
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// Outcome of a transaction started with [`SerialResource::transact`](crate::SerialResource::transact)
+#[derive(Debug, Event)]
+pub struct TransactionResult {
+    pub port: String,
+    /// The ID the transaction was started with
+    pub id: u64,
+    /// The reply, or [`SerialError::TransactionTimeout`] once every attempt timed out
+    pub result: Result<SerialData, SerialError>,
+}
+
+/// Reply of a transaction started with
+/// [`SerialResource::transact_async`](crate::SerialResource::transact_async).
+///
+/// Resolves on the [`SerialSet::Receive`](crate::SerialSet) the reply arrives or the
+/// transaction times out in, dropping it abandons the transaction.
+pub struct TransactionFuture {
+    port: String,
+    reply: oneshot::Receiver<Result<SerialData, SerialError>>,
+}
+
+impl Future for TransactionFuture {
+    type Output = Result<SerialData, SerialError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.reply).poll(cx).map(|result| {
+            // the port was closed before the transaction ended
+            result.unwrap_or_else(|_| Err(SerialError::PortClosed(self.port.clone())))
+        })
+    }
+}
+
+/// Where the outcome of a transaction goes
+pub(crate) enum ReplyTo {
+    Event(u64),
+    Future(oneshot::Sender<Result<SerialData, SerialError>>),
+}
+
+impl ReplyTo {
+    pub(crate) fn future(port: &str) -> (Self, TransactionFuture) {
+        let (tx, rx) = oneshot::channel();
+        (
+            ReplyTo::Future(tx),
+            TransactionFuture {
+                port: port.to_string(),
+                reply: rx,
+            },
+        )
+    }
+
+    fn is_abandoned(&self) -> bool {
+        match self {
+            ReplyTo::Event(_) => false,
+            ReplyTo::Future(tx) => tx.is_closed(),
+        }
+    }
+
+    fn complete(
+        self,
+        port: &str,
+        result: Result<SerialData, SerialError>,
+        results: &mut Vec<TransactionResult>,
+    ) {
+        match self {
+            ReplyTo::Event(id) => results.push(TransactionResult {
+                port: port.to_string(),
+                id,
+                result,
+            }),
+            ReplyTo::Future(tx) => {
+                let _ = tx.send(result);
+            }
+        }
+    }
+}
+
+struct Pending {
+    transaction: Transaction,
+    reply_to: ReplyTo,
+    /// when the latest attempt was handed to the writer, `None` until the next flush. Frames
+    /// read before it are no reply to it
+    sent_at: Option<Instant>,
+    retries_left: u32,
+}
+
+struct Queued {
+    transaction: Transaction,
+    reply_to: ReplyTo,
+}
+
+/// The transactions of one port, awaiting replies or a free slot to be sent in
+pub(crate) struct Transactions {
+    max_in_flight: usize,
+    in_flight: VecDeque<Pending>,
+    /// in the order they are sent: highest priority first, oldest first within a priority
+    queued: VecDeque<Queued>,
+}
+
+impl Transactions {
+    /// `None` sends every transaction right away
+    pub(crate) fn new(max_in_flight: Option<usize>) -> Self {
+        Self {
+            max_in_flight: max_in_flight.map_or(usize::MAX, |max| max.max(1)),
+            in_flight: VecDeque::new(),
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Send a transaction's request if a slot is free, or queue it until one is
+    pub(crate) fn submit(
+        &mut self,
+        transaction: Transaction,
+        reply_to: ReplyTo,
+        send: impl FnOnce(Bytes) -> Result<(), SerialError>,
+    ) -> Result<(), SerialError> {
+        if self.in_flight.len() < self.max_in_flight && self.queued.is_empty() {
+            send(transaction.request.clone())?;
+            self.start(transaction, reply_to);
+        } else {
+            let at = self
+                .queued
+                .iter()
+                .position(|queued| queued.transaction.priority < transaction.priority)
+                .unwrap_or(self.queued.len());
+            self.queued.insert(
+                at,
+                Queued {
+                    transaction,
+                    reply_to,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, transaction: Transaction, reply_to: ReplyTo) {
+        self.in_flight.push_back(Pending {
+            retries_left: transaction.retries,
+            transaction,
+            reply_to,
+            sent_at: None,
+        });
+    }
+
+    /// Start the timeouts of the requests sent since the last flush, now that they are
+    /// handed to the writer
+    pub(crate) fn flushed(&mut self, now: Instant) {
+        for pending in &mut self.in_flight {
+            pending.sent_at.get_or_insert(now);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.in_flight.is_empty() && self.queued.is_empty()
+    }
+
+    /// Forget the transaction started with `id`, whether queued or awaiting its reply
+    pub(crate) fn cancel(&mut self, id: u64) -> bool {
+        let is_id = |reply_to: &ReplyTo| matches!(reply_to, ReplyTo::Event(i) if *i == id);
+        if let Some(i) = self.queued.iter().position(|q| is_id(&q.reply_to)) {
+            self.queued.remove(i);
+            true
+        } else if let Some(i) = self.in_flight.iter().position(|p| is_id(&p.reply_to)) {
+            self.in_flight.remove(i);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Complete the oldest transaction `data` is the reply to, giving `data` back if it is none
+    pub(crate) fn offer(
+        &mut self,
+        data: SerialData,
+        results: &mut Vec<TransactionResult>,
+    ) -> Option<SerialData> {
+        let matched = self.in_flight.iter().position(|pending| {
+            pending
+                .sent_at
+                .is_some_and(|sent_at| data.received_at >= sent_at)
+                && pending.transaction.matcher.matches(&data.data)
+        });
+        match matched {
+            Some(i) => {
+                let pending = self.in_flight.remove(i).unwrap();
+                let port = data.port.clone();
+                pending.reply_to.complete(&port, Ok(data), results);
+                None
+            }
+            None => Some(data),
+        }
+    }
+
+    /// Retry or fail the transactions that timed out, forget the abandoned ones and send the
+    /// queued ones there is room for
+    pub(crate) fn update(
+        &mut self,
+        port: &str,
+        now: Instant,
+        mut send: impl FnMut(Bytes) -> Result<(), SerialError>,
+        results: &mut Vec<TransactionResult>,
+    ) {
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            let pending = &mut self.in_flight[i];
+            if pending.reply_to.is_abandoned() {
+                self.in_flight.remove(i);
+                continue;
+            }
+            let timed_out = pending
+                .sent_at
+                .is_some_and(|sent_at| now >= sent_at + pending.transaction.timeout);
+            if !timed_out {
+                i += 1;
+                continue;
+            }
+            let outcome = if pending.retries_left == 0 {
+                Err(SerialError::TransactionTimeout(port.to_string()))
+            } else {
+                send(pending.transaction.request.clone())
+            };
+            match outcome {
+                Ok(()) => {
+                    pending.retries_left -= 1;
+                    pending.sent_at = None;
+                    i += 1;
+                }
+                Err(err) => {
+                    let pending = self.in_flight.remove(i).unwrap();
+                    pending.reply_to.complete(port, Err(err), results);
+                }
+            }
+        }
+
+        while self.in_flight.len() < self.max_in_flight {
+            let Some(Queued {
+                transaction,
+                reply_to,
+            }) = self.queued.pop_front()
+            else {
+                break;
+            };
+            if reply_to.is_abandoned() {
+                continue;
+            }
+            match send(transaction.request.clone()) {
+                Ok(()) => self.start(transaction, reply_to),
+                Err(err) => reply_to.complete(port, Err(err), results),
+            }
+        }
+    }
+}
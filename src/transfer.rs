@@ -0,0 +1,473 @@
+//! File transfers taking over a port: XMODEM, YMODEM and ZMODEM, and the sessions driving
+//! them and the bootloaders.
+//!
+//! While a session runs, what is read from its port goes to the session instead of being sent
+//! as [`SerialData`](crate::SerialData), and [`SerialResource::send_message`] to the port fails
+//! with [`SerialError::PortBusy`]. Sessions need a port with [`Framing::Raw`](crate::Framing)
+//! and without reliable delivery.
+
+use std::{collections::BTreeMap, time::Instant};
+
+use bevy::prelude::*;
+use bytes::Bytes;
+
+use crate::{
+    xmodem::{XmodemReceiver, XmodemSender, CANCEL},
+    zmodem::{ZmodemReceiver, ZmodemSender},
+    PortControl, SerialError, SerialResource,
+};
+
+/// File transfer protocols
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferProtocol {
+    /// XMODEM with 128-byte blocks and 8-bit checksums
+    Xmodem,
+    /// XMODEM with 128-byte blocks and CRC-16
+    XmodemCrc,
+    /// XMODEM with 1024-byte blocks and CRC-16, falling back to 128-byte blocks and checksums
+    /// if the receiver asks for them
+    Xmodem1k,
+    /// Batches of named files in 1024-byte blocks
+    Ymodem,
+    /// Batches of named files streamed with CRC-32 when the receiver supports it, resuming
+    /// from where the receiver asks after errors. See [`FileTransfers::resume`]
+    Zmodem,
+}
+
+/// A file sent or received
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferFile {
+    /// Empty for XMODEM, which doesn't transfer names
+    pub name: String,
+    /// Received over XMODEM, the data is padded to the block size with `0x1A`
+    pub data: Bytes,
+}
+
+impl TransferFile {
+    pub fn new(name: impl ToString, data: impl Into<Bytes>) -> Self {
+        Self {
+            name: name.to_string(),
+            data: data.into(),
+        }
+    }
+}
+
+/// Bytes of the current file transferred so far, after each block
+#[derive(Debug, Clone, Event)]
+pub struct TransferProgress {
+    pub port: String,
+    /// The ID the transfer was started with
+    pub id: u64,
+    pub file: String,
+    pub transferred: u64,
+    /// Size of the file, when known
+    pub total: Option<u64>,
+}
+
+/// Progress of a bootloader session, after each block erased, written, read or verified
+#[cfg(any(feature = "stm32", feature = "esp"))]
+#[derive(Debug, Clone, Event)]
+pub struct BootloaderProgress {
+    pub port: String,
+    /// The ID the session was started with
+    pub id: u64,
+    /// What is being done: `erase`, `write`, `read` or `verify`
+    pub stage: String,
+    pub done: u64,
+    pub total: Option<u64>,
+}
+
+/// Outcome of a transfer started with [`FileTransfers`]
+#[derive(Debug, Event)]
+pub struct TransferResult {
+    pub port: String,
+    /// The ID the transfer was started with
+    pub id: u64,
+    /// The files received, none when sending
+    pub result: Result<Vec<TransferFile>, SerialError>,
+    /// When receiving failed, the files and the part of a file received before
+    pub partial: Vec<TransferFile>,
+}
+
+/// Label, bytes done and total of a progress event
+pub(crate) type Progress = (String, u64, Option<u64>);
+
+/// What a protocol state machine asks of its port
+pub(crate) struct TransferIo<T = Vec<TransferFile>> {
+    pub(crate) out: Vec<Bytes>,
+    /// Applied right away, before `out` is queued
+    pub(crate) control: Vec<PortControl>,
+    pub(crate) progress: Vec<Progress>,
+    /// Set once the session ended, with its result or why it failed
+    pub(crate) outcome: Option<Result<T, String>>,
+}
+
+impl<T> Default for TransferIo<T> {
+    fn default() -> Self {
+        Self {
+            out: Vec::new(),
+            control: Vec::new(),
+            progress: Vec::new(),
+            outcome: None,
+        }
+    }
+}
+
+impl<T> TransferIo<T> {
+    pub(crate) fn send(&mut self, bytes: impl Into<Bytes>) {
+        self.out.push(bytes.into());
+    }
+
+    pub(crate) fn progress(&mut self, label: &str, done: u64, total: Option<u64>) {
+        self.progress.push((label.to_string(), done, total));
+    }
+
+    pub(crate) fn finish(&mut self, result: T) {
+        self.outcome = Some(Ok(result));
+    }
+
+    pub(crate) fn fail(&mut self, reason: impl ToString) {
+        self.outcome = Some(Err(reason.to_string()));
+    }
+}
+
+/// One side of a protocol taking over a port, fed what is read from it
+pub(crate) trait Transfer<T = Vec<TransferFile>>: Send + Sync {
+    fn start(&mut self, now: Instant, io: &mut TransferIo<T>);
+
+    fn receive(&mut self, data: &[u8], now: Instant, io: &mut TransferIo<T>);
+
+    /// Handle timeouts
+    fn update(&mut self, now: Instant, io: &mut TransferIo<T>);
+
+    /// Abort the session, which ends once an outcome is set
+    fn cancel(&mut self, _now: Instant, io: &mut TransferIo<T>) {
+        io.send(CANCEL);
+        io.fail("cancelled");
+    }
+
+    /// What was received so far, once the session failed
+    fn partial(&mut self) -> Option<T> {
+        None
+    }
+}
+
+type Build<T> = Box<dyn FnOnce() -> Result<Box<dyn Transfer<T>>, String> + Send + Sync>;
+
+struct Request<T> {
+    port: String,
+    id: u64,
+    build: Build<T>,
+}
+
+struct Running<T> {
+    id: u64,
+    transfer: Box<dyn Transfer<T>>,
+    /// output not yet handled, of starting the session
+    io: TransferIo<T>,
+    cancel: bool,
+    /// the session was told to abort and is winding down
+    cancelling: bool,
+}
+
+/// How a session ended
+pub(crate) struct Ended<T> {
+    pub(crate) port: String,
+    pub(crate) id: u64,
+    pub(crate) result: Result<T, SerialError>,
+    /// what was received before the session failed
+    pub(crate) partial: Option<T>,
+}
+
+/// Sessions to start and running, one per port
+pub(crate) struct Sessions<T> {
+    requests: Vec<Request<T>>,
+    running: BTreeMap<String, Running<T>>,
+    /// requests cancelled before they started
+    cancelled: Vec<(String, u64)>,
+    /// error of a session that failed or was cancelled
+    failed: fn(String, String) -> SerialError,
+    cancelled_error: fn(String) -> SerialError,
+}
+
+impl<T> Sessions<T> {
+    pub(crate) fn new(
+        failed: fn(String, String) -> SerialError,
+        cancelled: fn(String) -> SerialError,
+    ) -> Self {
+        Self {
+            requests: Vec::new(),
+            running: BTreeMap::new(),
+            cancelled: Vec::new(),
+            failed,
+            cancelled_error: cancelled,
+        }
+    }
+
+    /// Start a session on `port` once [`SerialSet::Receive`](crate::SerialSet) next runs
+    pub(crate) fn start(
+        &mut self,
+        port: String,
+        id: u64,
+        build: impl FnOnce() -> Result<Box<dyn Transfer<T>>, String> + Send + Sync + 'static,
+    ) {
+        self.requests.push(Request {
+            port,
+            id,
+            build: Box::new(build),
+        });
+    }
+
+    pub(crate) fn cancel(&mut self, port: &str) -> bool {
+        if let Some(running) = self.running.get_mut(port) {
+            running.cancel = true;
+            return true;
+        }
+        let Some(i) = self.requests.iter().position(|r| r.port == port) else {
+            return false;
+        };
+        let request = self.requests.remove(i);
+        self.cancelled.push((request.port, request.id));
+        true
+    }
+
+    pub(crate) fn is_active(&self, port: &str) -> bool {
+        self.running.contains_key(port) || self.requests.iter().any(|r| r.port == port)
+    }
+
+    /// Start the requested sessions, feed the running ones what their ports read and handle
+    /// what they ask for
+    pub(crate) fn drive(
+        &mut self,
+        serial_res: &mut SerialResource,
+        mut progress: impl FnMut(&str, u64, Progress),
+        mut ended: impl FnMut(Ended<T>),
+    ) {
+        let now = Instant::now();
+        let (failed, cancelled) = (self.failed, self.cancelled_error);
+
+        for (port, id) in self.cancelled.drain(..) {
+            ended(Ended {
+                result: Err(cancelled(port.clone())),
+                port,
+                id,
+                partial: None,
+            });
+        }
+
+        for Request { port, id, build } in std::mem::take(&mut self.requests) {
+            let started = match serial_res.ports.get_mut(&port) {
+                None => Err(SerialError::PortNotFound(port.clone())),
+                Some(port_wrap) if port_wrap.is_claimed() || self.running.contains_key(&port) => {
+                    Err(SerialError::PortBusy(port.clone()))
+                }
+                Some(port_wrap) => match port_wrap.unclaimable() {
+                    Some(reason) => Err(failed(port.clone(), reason.to_string())),
+                    None => build()
+                        .map_err(|reason| failed(port.clone(), reason))
+                        .inspect(|_| port_wrap.claim()),
+                },
+            };
+            match started {
+                Ok(mut transfer) => {
+                    let mut io = TransferIo::default();
+                    transfer.start(now, &mut io);
+                    self.running.insert(
+                        port,
+                        Running {
+                            id,
+                            transfer,
+                            io,
+                            cancel: false,
+                            cancelling: false,
+                        },
+                    );
+                }
+                Err(err) => ended(Ended {
+                    port,
+                    id,
+                    result: Err(err),
+                    partial: None,
+                }),
+            }
+        }
+
+        self.running.retain(|port, running| {
+            let Some(port_wrap) = serial_res.ports.get_mut(port) else {
+                ended(Ended {
+                    port: port.clone(),
+                    id: running.id,
+                    result: Err(SerialError::PortClosed(port.clone())),
+                    partial: running.transfer.partial(),
+                });
+                return false;
+            };
+
+            let mut io = std::mem::take(&mut running.io);
+            if running.cancel && !running.cancelling {
+                running.cancelling = true;
+                running.transfer.cancel(now, &mut io);
+            } else {
+                for data in port_wrap.take_claimed() {
+                    if io.outcome.is_some() {
+                        break;
+                    }
+                    running.transfer.receive(&data, now, &mut io);
+                }
+                if io.outcome.is_none() {
+                    running.transfer.update(now, &mut io);
+                }
+            }
+
+            let mut outcome = io.outcome.map(|outcome| match running.cancelling {
+                true => Err(cancelled(port.clone())),
+                false => outcome.map_err(|reason| failed(port.clone(), reason)),
+            });
+            let controlled = io
+                .control
+                .into_iter()
+                .try_for_each(|control| port_wrap.control(control));
+            let sent = controlled.and_then(|_| {
+                io.out
+                    .into_iter()
+                    .try_for_each(|bytes| port_wrap.send_raw(bytes))
+            });
+            if let Err(err) = sent {
+                outcome = Some(Err(err));
+            }
+            for done in io.progress {
+                progress(port, running.id, done);
+            }
+
+            match outcome {
+                Some(result) => {
+                    port_wrap.release();
+                    let partial = match result {
+                        Ok(_) => None,
+                        Err(_) => running.transfer.partial(),
+                    };
+                    ended(Ended {
+                        port: port.clone(),
+                        id: running.id,
+                        result,
+                        partial,
+                    });
+                    false
+                }
+                None => true,
+            }
+        });
+    }
+}
+
+enum Direction {
+    Send(Vec<TransferFile>),
+    /// with files received before, to resume
+    Receive(Vec<TransferFile>),
+}
+
+/// Transfers to start and running, one per port. Each ends with a [`TransferResult`] event
+#[derive(Resource)]
+pub struct FileTransfers {
+    sessions: Sessions<Vec<TransferFile>>,
+}
+
+impl Default for FileTransfers {
+    fn default() -> Self {
+        Self {
+            sessions: Sessions::new(SerialError::TransferFailed, SerialError::TransferCancelled),
+        }
+    }
+}
+
+impl FileTransfers {
+    /// Send files to `port` once [`SerialSet::Receive`](crate::SerialSet) next runs.
+    ///
+    /// XMODEM sends exactly one file.
+    pub fn send(
+        &mut self,
+        port: impl ToString,
+        id: u64,
+        protocol: TransferProtocol,
+        files: Vec<TransferFile>,
+    ) {
+        self.start(port, id, protocol, Direction::Send(files));
+    }
+
+    /// Receive files from `port` once [`SerialSet::Receive`](crate::SerialSet) next runs
+    pub fn receive(&mut self, port: impl ToString, id: u64, protocol: TransferProtocol) {
+        self.start(port, id, protocol, Direction::Receive(Vec::new()));
+    }
+
+    /// Receive files from `port` over ZMODEM, continuing those in `partial` from where they
+    /// end, as in the [`TransferResult::partial`] of a transfer that failed
+    pub fn resume(&mut self, port: impl ToString, id: u64, partial: Vec<TransferFile>) {
+        self.start(
+            port,
+            id,
+            TransferProtocol::Zmodem,
+            Direction::Receive(partial),
+        );
+    }
+
+    fn start(
+        &mut self,
+        port: impl ToString,
+        id: u64,
+        protocol: TransferProtocol,
+        direction: Direction,
+    ) {
+        self.sessions
+            .start(port.to_string(), id, move || build(protocol, direction));
+    }
+
+    /// Abort the transfer on `port`, ending it with [`SerialError::TransferCancelled`].
+    /// `false` if there was none
+    pub fn cancel(&mut self, port: &str) -> bool {
+        self.sessions.cancel(port)
+    }
+
+    /// Whether a transfer runs or is about to start on `port`
+    pub fn is_active(&self, port: &str) -> bool {
+        self.sessions.is_active(port)
+    }
+}
+
+fn build(protocol: TransferProtocol, direction: Direction) -> Result<Box<dyn Transfer>, String> {
+    Ok(match (protocol, direction) {
+        (TransferProtocol::Zmodem, Direction::Send(files)) => Box::new(ZmodemSender::new(files)),
+        (TransferProtocol::Zmodem, Direction::Receive(partial)) => {
+            Box::new(ZmodemReceiver::new(partial))
+        }
+        (_, Direction::Send(files)) => Box::new(XmodemSender::new(protocol, files)?),
+        (_, Direction::Receive(_)) => Box::new(XmodemReceiver::new(protocol)),
+    })
+}
+
+pub(crate) fn drive_transfers(
+    mut transfers: ResMut<FileTransfers>,
+    mut serial_res: ResMut<SerialResource>,
+    mut progress_ev: EventWriter<TransferProgress>,
+    mut result_ev: EventWriter<TransferResult>,
+) {
+    transfers.sessions.drive(
+        &mut serial_res,
+        |port, id, (file, transferred, total)| {
+            progress_ev.send(TransferProgress {
+                port: port.to_string(),
+                id,
+                file,
+                transferred,
+                total,
+            });
+        },
+        |ended| {
+            result_ev.send(TransferResult {
+                port: ended.port,
+                id: ended.id,
+                result: ended.result,
+                partial: ended.partial.unwrap_or_default(),
+            });
+        },
+    );
+}
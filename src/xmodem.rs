@@ -0,0 +1,604 @@
+//! XMODEM and YMODEM senders and receivers.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::{
+    crc::crc16_xmodem,
+    transfer::{Transfer, TransferFile, TransferIo, TransferProtocol},
+};
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1A;
+/// Sent by a receiver instead of NAK to ask for CRC-16
+const CRC: u8 = b'C';
+
+/// Aborts a transfer, more than the two CAN needed in case some get lost
+pub(crate) const CANCEL: &[u8] = &[CAN; 8];
+
+/// How long to wait for the reply to a block before sending it again
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How often a receiver asks the sender to start
+const START_INTERVAL: Duration = Duration::from_secs(3);
+/// How long a sender waits for the receiver to start
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_RETRIES: u32 = 10;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// A block of `size` bytes, `data` padded with `pad`
+fn block(number: u8, data: &[u8], size: usize, pad: u8, crc: bool) -> Bytes {
+    let mut block = BytesMut::with_capacity(size + 5);
+    block.put_u8(if size == 1024 { STX } else { SOH });
+    block.put_u8(number);
+    block.put_u8(!number);
+    block.put_slice(data);
+    block.put_bytes(pad, size - data.len());
+    if crc {
+        let crc = crc16_xmodem(&block[3..]);
+        block.put_u16(crc);
+    } else {
+        let sum = checksum(&block[3..]);
+        block.put_u8(sum);
+    }
+    block.freeze()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendState {
+    /// for the receiver to ask for the next file, or the end of the batch
+    Start,
+    /// for the YMODEM header to be acknowledged, `true` for the empty one ending the batch
+    HeaderAck(bool),
+    /// for the receiver to ask for the data after the YMODEM header
+    DataStart,
+    BlockAck,
+    EotAck,
+}
+
+pub(crate) struct XmodemSender {
+    ymodem: bool,
+    one_k: bool,
+    crc: bool,
+    files: VecDeque<TransferFile>,
+    file: TransferFile,
+    offset: usize,
+    /// data bytes in the block awaiting acknowledgement
+    in_flight: usize,
+    number: u8,
+    state: SendState,
+    /// what is sent again on NAK or timeout
+    last: Bytes,
+    deadline: Instant,
+    retries: u32,
+    cancels: u8,
+}
+
+impl XmodemSender {
+    pub(crate) fn new(
+        protocol: TransferProtocol,
+        files: Vec<TransferFile>,
+    ) -> Result<Self, String> {
+        let ymodem = protocol == TransferProtocol::Ymodem;
+        if !ymodem && files.len() != 1 {
+            return Err(format!("XMODEM sends one file, not {}", files.len()));
+        }
+        Ok(Self {
+            ymodem,
+            one_k: matches!(
+                protocol,
+                TransferProtocol::Xmodem1k | TransferProtocol::Ymodem
+            ),
+            crc: protocol != TransferProtocol::Xmodem,
+            files: files.into(),
+            file: TransferFile::new("", Bytes::new()),
+            offset: 0,
+            in_flight: 0,
+            number: 0,
+            state: SendState::Start,
+            last: Bytes::new(),
+            deadline: Instant::now(),
+            retries: 0,
+            cancels: 0,
+        })
+    }
+
+    fn transmit(&mut self, bytes: Bytes, state: SendState, now: Instant, io: &mut TransferIo) {
+        io.send(bytes.clone());
+        self.last = bytes;
+        self.state = state;
+        self.deadline = now + TIMEOUT;
+        self.retries = 0;
+    }
+
+    fn retransmit(&mut self, now: Instant, io: &mut TransferIo) {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            io.send(CANCEL);
+            io.fail("too many retries");
+            return;
+        }
+        io.send(self.last.clone());
+        self.deadline = now + TIMEOUT;
+    }
+
+    fn wait(&mut self, state: SendState, now: Instant) {
+        self.state = state;
+        self.deadline = now + START_TIMEOUT;
+    }
+
+    fn send_header(&mut self, now: Instant, io: &mut TransferIo) {
+        let Some(file) = self.files.pop_front() else {
+            let end = block(0, &[], 128, 0, true);
+            self.transmit(end, SendState::HeaderAck(true), now, io);
+            return;
+        };
+        let mut header = file.name.clone().into_bytes();
+        header.push(0);
+        header.extend_from_slice(file.data.len().to_string().as_bytes());
+        header.push(0);
+        let size = if header.len() > 128 { 1024 } else { 128 };
+        header.truncate(size);
+        self.file = file;
+        self.transmit(
+            block(0, &header, size, 0, true),
+            SendState::HeaderAck(false),
+            now,
+            io,
+        );
+    }
+
+    fn start_data(&mut self, now: Instant, io: &mut TransferIo) {
+        self.offset = 0;
+        self.number = 1;
+        self.send_block(now, io);
+    }
+
+    fn send_block(&mut self, now: Instant, io: &mut TransferIo) {
+        let remaining = self.file.data.len() - self.offset;
+        if remaining == 0 {
+            self.transmit(Bytes::from_static(&[EOT]), SendState::EotAck, now, io);
+            return;
+        }
+        let size = if self.one_k && self.crc && remaining >= 1024 {
+            1024
+        } else {
+            128
+        };
+        self.in_flight = remaining.min(size);
+        let data = &self.file.data[self.offset..self.offset + self.in_flight];
+        let block = block(self.number, data, size, SUB, self.crc);
+        self.transmit(block, SendState::BlockAck, now, io);
+    }
+
+    fn on_byte(&mut self, byte: u8, now: Instant, io: &mut TransferIo) {
+        if byte == CAN {
+            self.cancels += 1;
+            if self.cancels >= 2 {
+                io.fail("cancelled by the receiver");
+            }
+            return;
+        }
+        self.cancels = 0;
+        match (self.state, byte) {
+            (SendState::Start, CRC | NAK) => {
+                self.crc = byte == CRC;
+                if self.ymodem {
+                    self.send_header(now, io);
+                } else {
+                    self.file = self.files.pop_front().expect("checked in new");
+                    self.start_data(now, io);
+                }
+            }
+            (SendState::HeaderAck(true), ACK) => io.finish(Vec::new()),
+            (SendState::HeaderAck(false), ACK) => self.wait(SendState::DataStart, now),
+            (SendState::DataStart, CRC) => self.start_data(now, io),
+            (SendState::BlockAck, ACK) => {
+                self.offset += self.in_flight;
+                self.number = self.number.wrapping_add(1);
+                io.progress(
+                    &self.file.name,
+                    self.offset as u64,
+                    Some(self.file.data.len() as u64),
+                );
+                self.send_block(now, io);
+            }
+            (SendState::EotAck, ACK) => {
+                if self.ymodem {
+                    self.wait(SendState::Start, now);
+                } else {
+                    io.finish(Vec::new());
+                }
+            }
+            (SendState::HeaderAck(_) | SendState::BlockAck | SendState::EotAck, NAK) => {
+                self.retransmit(now, io)
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Transfer for XmodemSender {
+    fn start(&mut self, now: Instant, _io: &mut TransferIo) {
+        self.wait(SendState::Start, now);
+    }
+
+    fn receive(&mut self, data: &[u8], now: Instant, io: &mut TransferIo) {
+        for &byte in data {
+            self.on_byte(byte, now, io);
+            if io.outcome.is_some() {
+                return;
+            }
+        }
+    }
+
+    fn update(&mut self, now: Instant, io: &mut TransferIo) {
+        if now < self.deadline {
+            return;
+        }
+        match self.state {
+            SendState::Start | SendState::DataStart => {
+                io.send(CANCEL);
+                io.fail("the receiver did not start");
+            }
+            _ => self.retransmit(now, io),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecvState {
+    /// for a YMODEM header
+    Header,
+    Data,
+}
+
+pub(crate) struct XmodemReceiver {
+    ymodem: bool,
+    crc: bool,
+    buf: BytesMut,
+    state: RecvState,
+    expected: u8,
+    /// the last block accepted, sent again when its acknowledgement got lost
+    last: Option<u8>,
+    /// still asking the sender to start, rather than waiting for a block
+    starting: bool,
+    files: Vec<TransferFile>,
+    name: String,
+    size: Option<u64>,
+    data: BytesMut,
+    deadline: Instant,
+    retries: u32,
+}
+
+impl XmodemReceiver {
+    pub(crate) fn new(protocol: TransferProtocol) -> Self {
+        let ymodem = protocol == TransferProtocol::Ymodem;
+        Self {
+            ymodem,
+            crc: protocol != TransferProtocol::Xmodem,
+            buf: BytesMut::new(),
+            state: if ymodem {
+                RecvState::Header
+            } else {
+                RecvState::Data
+            },
+            expected: if ymodem { 0 } else { 1 },
+            last: None,
+            starting: true,
+            files: Vec::new(),
+            name: String::new(),
+            size: None,
+            data: BytesMut::new(),
+            deadline: Instant::now(),
+            retries: 0,
+        }
+    }
+
+    fn start_char(&self) -> u8 {
+        if self.crc {
+            CRC
+        } else {
+            NAK
+        }
+    }
+
+    /// Ask for the next block, or for the sender to start
+    fn ask(&mut self, now: Instant, io: &mut TransferIo) {
+        self.starting = true;
+        self.deadline = now + START_INTERVAL;
+        io.send(vec![self.start_char()]);
+    }
+
+    fn nak(&mut self, now: Instant, io: &mut TransferIo) {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            io.send(CANCEL);
+            io.fail("too many bad blocks");
+            return;
+        }
+        self.buf.clear();
+        self.deadline = now + TIMEOUT;
+        io.send(vec![NAK]);
+    }
+
+    fn on_block(&mut self, block: &[u8], size: usize, now: Instant, io: &mut TransferIo) {
+        let (number, inverse, payload) = (block[1], block[2], &block[3..3 + size]);
+        let check = &block[3 + size..];
+        let intact = if self.crc {
+            crc16_xmodem(payload) == u16::from_be_bytes([check[0], check[1]])
+        } else {
+            checksum(payload) == check[0]
+        };
+        if number != !inverse || !intact {
+            self.nak(now, io);
+            return;
+        }
+        if Some(number) == self.last && number != self.expected {
+            // our acknowledgement got lost, a repeated YMODEM header also needs asking again
+            io.send(vec![ACK]);
+            if number == 0 && self.ymodem && self.data.is_empty() {
+                io.send(vec![CRC]);
+            }
+            return;
+        }
+        if number != self.expected {
+            io.send(CANCEL);
+            io.fail(format!("expected block {}, got {}", self.expected, number));
+            return;
+        }
+
+        io.send(vec![ACK]);
+        self.last = Some(number);
+        self.expected = number.wrapping_add(1);
+        self.starting = false;
+        self.retries = 0;
+        self.deadline = now + TIMEOUT;
+        match self.state {
+            RecvState::Header => {
+                let mut fields = payload.split(|&b| b == 0);
+                let name = String::from_utf8_lossy(fields.next().unwrap_or_default());
+                if name.is_empty() {
+                    io.finish(std::mem::take(&mut self.files));
+                    return;
+                }
+                self.name = name.into_owned();
+                self.size = fields
+                    .next()
+                    .and_then(|info| info.split(|&b| b == b' ').next())
+                    .and_then(|size| std::str::from_utf8(size).ok()?.parse().ok());
+                self.data.clear();
+                self.state = RecvState::Data;
+                self.ask(now, io);
+            }
+            RecvState::Data => {
+                self.data.extend_from_slice(payload);
+                let transferred = match self.size {
+                    Some(size) => (self.data.len() as u64).min(size),
+                    None => self.data.len() as u64,
+                };
+                io.progress(&self.name, transferred, self.size);
+            }
+        }
+    }
+
+    fn on_eot(&mut self, now: Instant, io: &mut TransferIo) {
+        io.send(vec![ACK]);
+        if self.state != RecvState::Data {
+            return;
+        }
+        let mut data = std::mem::take(&mut self.data);
+        if let Some(size) = self.size {
+            data.truncate(size as usize);
+        }
+        self.files.push(TransferFile {
+            name: std::mem::take(&mut self.name),
+            data: data.freeze(),
+        });
+        self.size = None;
+        if self.ymodem {
+            self.state = RecvState::Header;
+            self.expected = 0;
+            self.last = None;
+            self.ask(now, io);
+        } else {
+            io.finish(std::mem::take(&mut self.files));
+        }
+    }
+}
+
+impl Transfer for XmodemReceiver {
+    fn start(&mut self, now: Instant, io: &mut TransferIo) {
+        self.ask(now, io);
+    }
+
+    fn receive(&mut self, data: &[u8], now: Instant, io: &mut TransferIo) {
+        self.buf.extend_from_slice(data);
+        while let Some(&first) = self.buf.first() {
+            match first {
+                SOH | STX => {
+                    let size = if first == SOH { 128 } else { 1024 };
+                    let len = 3 + size + if self.crc { 2 } else { 1 };
+                    if self.buf.len() < len {
+                        return;
+                    }
+                    let block = self.buf.split_to(len);
+                    self.on_block(&block, size, now, io);
+                }
+                EOT => {
+                    self.buf.advance(1);
+                    self.on_eot(now, io);
+                }
+                CAN => {
+                    if self.buf.len() < 2 {
+                        return;
+                    }
+                    if self.buf[1] == CAN {
+                        io.fail("cancelled by the sender");
+                    }
+                    self.buf.advance(1);
+                }
+                _ => self.buf.advance(1),
+            }
+            if io.outcome.is_some() {
+                return;
+            }
+        }
+    }
+
+    fn update(&mut self, now: Instant, io: &mut TransferIo) {
+        if now < self.deadline {
+            return;
+        }
+        if self.starting {
+            self.retries += 1;
+            if self.retries > MAX_RETRIES {
+                io.send(CANCEL);
+                io.fail("the sender did not start");
+                return;
+            }
+            self.deadline = now + START_INTERVAL;
+            io.send(vec![self.start_char()]);
+        } else {
+            self.nak(now, io);
+        }
+    }
+
+    fn partial(&mut self) -> Option<Vec<TransferFile>> {
+        let mut files = std::mem::take(&mut self.files);
+        if !self.data.is_empty() {
+            files.push(TransferFile {
+                name: std::mem::take(&mut self.name),
+                data: std::mem::take(&mut self.data).freeze(),
+            });
+        }
+        Some(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::Progress;
+
+    /// Run a sender and a receiver against each other, `corrupt` damaging what the sender
+    /// writes, and return the files received and the receiver's progress
+    fn transfer(
+        protocol: TransferProtocol,
+        files: Vec<TransferFile>,
+        mut corrupt: impl FnMut(usize, &mut Vec<u8>),
+    ) -> (Vec<TransferFile>, Vec<Progress>) {
+        let mut now = Instant::now();
+        let mut sender = XmodemSender::new(protocol, files).unwrap();
+        let mut receiver = XmodemReceiver::new(protocol);
+        let (mut to_receiver, mut to_sender) = (TransferIo::default(), TransferIo::default());
+        sender.start(now, &mut to_receiver);
+        receiver.start(now, &mut to_sender);
+        let mut writes = 0;
+        for _ in 0..1000 {
+            if to_receiver.out.is_empty() && to_sender.out.is_empty() {
+                // nothing on the line, let the timeouts fire
+                now += TIMEOUT;
+                if to_receiver.outcome.is_none() {
+                    sender.update(now, &mut to_receiver);
+                }
+                if to_sender.outcome.is_none() {
+                    receiver.update(now, &mut to_sender);
+                }
+            }
+            for bytes in std::mem::take(&mut to_sender.out) {
+                if to_receiver.outcome.is_none() {
+                    sender.receive(&bytes, now, &mut to_receiver);
+                }
+            }
+            for bytes in std::mem::take(&mut to_receiver.out) {
+                let mut bytes = bytes.to_vec();
+                corrupt(writes, &mut bytes);
+                writes += 1;
+                if to_sender.outcome.is_none() {
+                    receiver.receive(&bytes, now, &mut to_sender);
+                }
+            }
+            if let (Some(sent), Some(received)) = (&to_receiver.outcome, &to_sender.outcome) {
+                assert_eq!(sent, &Ok(Vec::new()));
+                return (received.clone().unwrap(), to_sender.progress);
+            }
+        }
+        panic!("the transfer did not end");
+    }
+
+    #[test]
+    fn xmodem_variants() {
+        let data: Bytes = (0..3000u32)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>()
+            .into();
+        for protocol in [
+            TransferProtocol::Xmodem,
+            TransferProtocol::XmodemCrc,
+            TransferProtocol::Xmodem1k,
+        ] {
+            let (files, progress) = transfer(
+                protocol,
+                vec![TransferFile::new("", data.clone())],
+                |_, _| {},
+            );
+            assert_eq!(files.len(), 1);
+            // padded to the block size
+            assert_eq!(files[0].data.len(), 3072);
+            assert_eq!(files[0].data[..3000], data[..]);
+            assert!(files[0].data[3000..].iter().all(|&b| b == SUB));
+            assert_eq!(progress.last().unwrap().1, 3072);
+        }
+    }
+
+    #[test]
+    fn ymodem_batch_with_errors() {
+        let files = vec![
+            TransferFile::new("firmware.bin", vec![0xA5; 2500]),
+            TransferFile::new("empty.cfg", Bytes::new()),
+            TransferFile::new("config.json", &b"{\"rate\": 10}"[..]),
+        ];
+        // flip a bit in the third write and the last byte of the seventh
+        let (received, progress) =
+            transfer(
+                TransferProtocol::Ymodem,
+                files.clone(),
+                |i, bytes| match i {
+                    2 => bytes[10] ^= 0x40,
+                    6 => *bytes.last_mut().unwrap() ^= 1,
+                    _ => {}
+                },
+            );
+        assert_eq!(received, files);
+        assert_eq!(
+            progress.last(),
+            Some(&("config.json".to_string(), 12, Some(12)))
+        );
+    }
+
+    #[test]
+    fn cancelled_by_peer() {
+        let now = Instant::now();
+        let mut receiver = XmodemReceiver::new(TransferProtocol::XmodemCrc);
+        let mut io = TransferIo::default();
+        receiver.start(now, &mut io);
+        receiver.receive(&[CAN], now, &mut io);
+        assert!(io.outcome.is_none());
+        receiver.receive(&[CAN], now, &mut io);
+        assert_eq!(io.outcome, Some(Err("cancelled by the sender".to_string())));
+
+        assert!(XmodemSender::new(TransferProtocol::Xmodem, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,955 @@
+//! ZMODEM sender and receiver: streaming with CRC-32 when the receiver offers it, and resuming
+//! from the offset the receiver asks for with ZRPOS, both after a corrupted packet and for a file
+//! partly received by an earlier transfer.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::{
+    crc::{crc16_xmodem, crc32},
+    transfer::{Transfer, TransferFile, TransferIo},
+};
+
+const ZPAD: u8 = b'*';
+const ZDLE: u8 = 0x18;
+const ZBIN: u8 = b'A';
+const ZHEX: u8 = b'B';
+const ZBIN32: u8 = b'C';
+
+const ZRQINIT: u8 = 0;
+const ZRINIT: u8 = 1;
+const ZSINIT: u8 = 2;
+const ZACK: u8 = 3;
+const ZFILE: u8 = 4;
+const ZSKIP: u8 = 5;
+const ZNAK: u8 = 6;
+const ZABORT: u8 = 7;
+const ZFIN: u8 = 8;
+const ZRPOS: u8 = 9;
+const ZDATA: u8 = 10;
+const ZEOF: u8 = 11;
+const ZFERR: u8 = 12;
+const ZCAN: u8 = 16;
+
+/// Ends a data subpacket and its frame, a header follows
+const ZCRCE: u8 = b'h';
+/// Ends a data subpacket, the frame continues
+const ZCRCG: u8 = b'i';
+/// Ends a data subpacket, the frame continues and the receiver sends ZACK
+const ZCRCQ: u8 = b'j';
+/// Ends a data subpacket and its frame, the receiver sends ZACK
+const ZCRCW: u8 = b'k';
+const ZRUB0: u8 = b'l';
+const ZRUB1: u8 = b'm';
+
+/// ZRINIT flags: full duplex, receiving while writing, CRC-32
+const CANFDX: u8 = 0x01;
+const CANOVIO: u8 = 0x02;
+const CANFC32: u8 = 0x20;
+/// ZFILE option: resume a partly received file
+const ZCRESUM: u8 = 3;
+
+const XON: u8 = 0x11;
+/// Aborts a transfer: five or more ZDLE, then backspaces over them on a terminal
+const CANCEL: &[u8] = &[
+    ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8,
+];
+
+const SUBPACKET: usize = 1024;
+/// Largest subpacket accepted, other implementations send up to 8 KiB
+const MAX_SUBPACKET: usize = 8192;
+/// Bytes streamed ahead of the last ZACK
+const WINDOW: usize = 16 * 1024;
+/// Subpackets between asking for a ZACK while streaming
+const ACK_EVERY: usize = 4;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a receiver waits for the sender's closing "OO"
+const FINISH_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRIES: u32 = 10;
+
+/// Type and four data bytes of a header. Positions are little-endian, flags are in reverse
+/// with ZF0 last
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    kind: u8,
+    data: [u8; 4],
+}
+
+impl Header {
+    fn position(kind: u8, position: usize) -> Self {
+        Self {
+            kind,
+            data: (position as u32).to_le_bytes(),
+        }
+    }
+
+    fn flags(kind: u8, zf0: u8) -> Self {
+        Self {
+            kind,
+            data: [0, 0, 0, zf0],
+        }
+    }
+
+    fn offset(&self) -> usize {
+        u32::from_le_bytes(self.data) as usize
+    }
+
+    fn zf0(&self) -> u8 {
+        self.data[3]
+    }
+
+    fn bytes(&self) -> [u8; 5] {
+        let [p0, p1, p2, p3] = self.data;
+        [self.kind, p0, p1, p2, p3]
+    }
+
+    /// Header in hex, used by receivers and for session start and end
+    fn hex(&self) -> Bytes {
+        let body = self.bytes();
+        let crc = crc16_xmodem(&body);
+        let mut out = vec![ZPAD, ZPAD, ZDLE, ZHEX];
+        for byte in body.into_iter().chain(crc.to_be_bytes()) {
+            out.extend_from_slice(format!("{byte:02x}").as_bytes());
+        }
+        out.extend_from_slice(&[b'\r', b'\n' | 0x80]);
+        if self.kind != ZACK && self.kind != ZFIN {
+            out.push(XON);
+        }
+        out.into()
+    }
+
+    /// Header in binary, used by senders
+    fn binary(&self, crc32: bool) -> Vec<u8> {
+        let body = self.bytes();
+        let mut out = vec![ZPAD, ZDLE];
+        if crc32 {
+            out.push(ZBIN32);
+            escape(&body, &mut out);
+            escape(&self::crc32(&body).to_le_bytes(), &mut out);
+        } else {
+            out.push(ZBIN);
+            escape(&body, &mut out);
+            escape(&crc16_xmodem(&body).to_be_bytes(), &mut out);
+        }
+        out
+    }
+}
+
+fn escape(data: &[u8], out: &mut Vec<u8>) {
+    for &byte in data {
+        match byte {
+            ZDLE | 0x10 | 0x90 | 0x11 | 0x91 | 0x13 | 0x93 | 0x0D | 0x8D => {
+                out.extend_from_slice(&[ZDLE, byte ^ 0x40]);
+            }
+            _ => out.push(byte),
+        }
+    }
+}
+
+/// A data subpacket ending with `end`
+fn subpacket(data: &[u8], end: u8, crc32: bool, out: &mut Vec<u8>) {
+    escape(data, out);
+    out.extend_from_slice(&[ZDLE, end]);
+    let mut covered = data.to_vec();
+    covered.push(end);
+    if crc32 {
+        escape(&self::crc32(&covered).to_le_bytes(), out);
+    } else {
+        escape(&crc16_xmodem(&covered).to_be_bytes(), out);
+    }
+}
+
+enum Unit {
+    Byte(u8),
+    /// end of a data subpacket
+    End(u8),
+    /// flow control
+    Skip,
+    Invalid,
+}
+
+/// The first unit of `buf` and its length, `None` if incomplete
+fn unescape(buf: &[u8]) -> Option<(Unit, usize)> {
+    let &byte = buf.first()?;
+    match byte {
+        ZDLE => {
+            let &next = buf.get(1)?;
+            let unit = match next {
+                ZCRCE | ZCRCG | ZCRCQ | ZCRCW => Unit::End(next),
+                ZRUB0 => Unit::Byte(0x7F),
+                ZRUB1 => Unit::Byte(0xFF),
+                _ if next & 0x60 == 0x40 => Unit::Byte(next ^ 0x40),
+                _ => Unit::Invalid,
+            };
+            Some((unit, 2))
+        }
+        0x11 | 0x13 | 0x91 | 0x93 => Some((Unit::Skip, 1)),
+        _ => Some((Unit::Byte(byte), 1)),
+    }
+}
+
+enum Parsed<T> {
+    /// and the bytes it took
+    Done(T, usize),
+    More,
+    /// bytes to drop
+    Bad(usize),
+}
+
+fn read_escaped(buf: &[u8], len: usize) -> Parsed<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while out.len() < len {
+        match unescape(&buf[i..]) {
+            None => return Parsed::More,
+            Some((Unit::Byte(byte), n)) => {
+                out.push(byte);
+                i += n;
+            }
+            Some((Unit::Skip, n)) => i += n,
+            Some((_, n)) => return Parsed::Bad(i + n),
+        }
+    }
+    Parsed::Done(out, i)
+}
+
+/// The next header in `buf` and whether it uses CRC-32, skipping anything before it
+fn parse_header(buf: &[u8]) -> Parsed<(Header, bool)> {
+    match buf.iter().position(|&b| b == ZPAD) {
+        None if buf.is_empty() => return Parsed::More,
+        None => return Parsed::Bad(buf.len()),
+        Some(0) => {}
+        Some(start) => return Parsed::Bad(start),
+    }
+    let mut i = 0;
+    while buf.get(i) == Some(&ZPAD) {
+        i += 1;
+    }
+    let (Some(&zdle), Some(&format)) = (buf.get(i), buf.get(i + 1)) else {
+        return Parsed::More;
+    };
+    if zdle != ZDLE {
+        return Parsed::Bad(i);
+    }
+    i += 2;
+    let (body, crc_ok) = match format {
+        ZHEX => {
+            let Some(hex) = buf.get(i..i + 14) else {
+                return Parsed::More;
+            };
+            let Some(bytes) = hex
+                .chunks(2)
+                .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+                .collect::<Option<Vec<u8>>>()
+            else {
+                return Parsed::Bad(i);
+            };
+            i += 14;
+            let crc_ok = crc16_xmodem(&bytes[..5]) == u16::from_be_bytes([bytes[5], bytes[6]]);
+            (bytes, crc_ok)
+        }
+        ZBIN | ZBIN32 => {
+            let crc_len = if format == ZBIN32 { 4 } else { 2 };
+            let bytes = match read_escaped(&buf[i..], 5 + crc_len) {
+                Parsed::Done(bytes, n) => {
+                    i += n;
+                    bytes
+                }
+                Parsed::More => return Parsed::More,
+                Parsed::Bad(_) => return Parsed::Bad(i),
+            };
+            let crc_ok = if format == ZBIN32 {
+                crc32(&bytes[..5]).to_le_bytes()[..] == bytes[5..]
+            } else {
+                crc16_xmodem(&bytes[..5]).to_be_bytes()[..] == bytes[5..]
+            };
+            (bytes, crc_ok)
+        }
+        _ => return Parsed::Bad(i),
+    };
+    if !crc_ok {
+        return Parsed::Bad(i);
+    }
+    let header = Header {
+        kind: body[0],
+        data: [body[1], body[2], body[3], body[4]],
+    };
+    Parsed::Done((header, format == ZBIN32), i)
+}
+
+/// The next data subpacket in `buf` and how it ends
+fn parse_subpacket(buf: &[u8], crc32: bool) -> Parsed<(Vec<u8>, u8)> {
+    let mut data = Vec::new();
+    let mut i = 0;
+    loop {
+        match unescape(&buf[i..]) {
+            None => return Parsed::More,
+            Some((Unit::Byte(byte), n)) => {
+                data.push(byte);
+                i += n;
+                if data.len() > MAX_SUBPACKET {
+                    return Parsed::Bad(i);
+                }
+            }
+            Some((Unit::Skip, n)) => i += n,
+            Some((Unit::Invalid, n)) => return Parsed::Bad(i + n),
+            Some((Unit::End(end), n)) => {
+                i += n;
+                let crc = match read_escaped(&buf[i..], if crc32 { 4 } else { 2 }) {
+                    Parsed::Done(crc, n) => {
+                        i += n;
+                        crc
+                    }
+                    Parsed::More => return Parsed::More,
+                    Parsed::Bad(n) => return Parsed::Bad(i + n),
+                };
+                data.push(end);
+                let crc_ok = if crc32 {
+                    self::crc32(&data).to_le_bytes()[..] == crc[..]
+                } else {
+                    crc16_xmodem(&data).to_be_bytes()[..] == crc[..]
+                };
+                data.pop();
+                return if crc_ok {
+                    Parsed::Done((data, end), i)
+                } else {
+                    Parsed::Bad(i)
+                };
+            }
+        }
+    }
+}
+
+/// Counts consecutive ZDLE, which never come in pairs otherwise. `true` once cancelled
+fn cancelled(cans: &mut usize, data: &[u8]) -> bool {
+    for &byte in data {
+        *cans = if byte == ZDLE { *cans + 1 } else { 0 };
+        if *cans >= 5 {
+            return true;
+        }
+    }
+    false
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendState {
+    /// for ZRINIT
+    Init,
+    /// for ZRPOS or ZSKIP after ZFILE
+    FileOffer,
+    Sending,
+    /// for ZRINIT after ZEOF
+    Eof,
+    /// for ZFIN
+    Fin,
+}
+
+pub(crate) struct ZmodemSender {
+    buf: BytesMut,
+    cans: usize,
+    state: SendState,
+    files: VecDeque<TransferFile>,
+    file: TransferFile,
+    /// next byte to send
+    offset: usize,
+    /// bytes the receiver confirmed
+    acked: usize,
+    /// stream subpackets rather than wait for each to be acknowledged
+    streaming: bool,
+    crc32: bool,
+    /// a subpacket awaits its ZACK, when not streaming
+    awaiting_ack: bool,
+    since_ack_request: usize,
+    deadline: Instant,
+    retries: u32,
+}
+
+impl ZmodemSender {
+    pub(crate) fn new(files: Vec<TransferFile>) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            cans: 0,
+            state: SendState::Init,
+            files: files.into(),
+            file: TransferFile::new("", Bytes::new()),
+            offset: 0,
+            acked: 0,
+            streaming: false,
+            crc32: false,
+            awaiting_ack: false,
+            since_ack_request: 0,
+            deadline: Instant::now(),
+            retries: 0,
+        }
+    }
+
+    fn wait(&mut self, state: SendState, now: Instant) {
+        self.state = state;
+        self.deadline = now + TIMEOUT;
+    }
+
+    fn offer_file(&mut self, now: Instant, io: &mut TransferIo) {
+        let Some(file) = self.files.pop_front() else {
+            io.send(Header::flags(ZFIN, 0).hex());
+            self.wait(SendState::Fin, now);
+            return;
+        };
+        self.file = file;
+        self.send_offer(io);
+        self.wait(SendState::FileOffer, now);
+    }
+
+    fn send_offer(&self, io: &mut TransferIo) {
+        let mut info = self.file.name.clone().into_bytes();
+        info.push(0);
+        info.extend_from_slice(self.file.data.len().to_string().as_bytes());
+        info.push(0);
+        let mut out = Header::flags(ZFILE, ZCRESUM).binary(self.crc32);
+        subpacket(&info, ZCRCW, self.crc32, &mut out);
+        io.send(out);
+    }
+
+    /// Continue the file from `offset`
+    fn seek(&mut self, offset: usize, now: Instant, io: &mut TransferIo) {
+        self.offset = offset.min(self.file.data.len());
+        self.acked = self.offset;
+        self.awaiting_ack = false;
+        self.since_ack_request = 0;
+        self.wait(SendState::Sending, now);
+        if self.streaming {
+            io.send(Header::position(ZDATA, self.offset).binary(self.crc32));
+        }
+        self.pump(now, io);
+    }
+
+    fn pump(&mut self, now: Instant, io: &mut TransferIo) {
+        if self.state != SendState::Sending {
+            return;
+        }
+        let size = self.file.data.len();
+        let mut out = Vec::new();
+        if self.streaming {
+            while self.offset - self.acked < WINDOW {
+                let len = (size - self.offset).min(SUBPACKET);
+                let last = self.offset + len == size;
+                self.since_ack_request += 1;
+                let end = if last {
+                    ZCRCE
+                } else if self.since_ack_request >= ACK_EVERY {
+                    self.since_ack_request = 0;
+                    ZCRCQ
+                } else {
+                    ZCRCG
+                };
+                let data = &self.file.data[self.offset..self.offset + len];
+                subpacket(data, end, self.crc32, &mut out);
+                self.offset += len;
+                io.progress(&self.file.name, self.offset as u64, Some(size as u64));
+                if last {
+                    out.extend(Header::position(ZEOF, size).binary(self.crc32));
+                    self.state = SendState::Eof;
+                    break;
+                }
+            }
+        } else if !self.awaiting_ack {
+            if self.offset == size {
+                out = Header::position(ZEOF, size).binary(self.crc32);
+                self.state = SendState::Eof;
+            } else {
+                let len = (size - self.offset).min(SUBPACKET);
+                out = Header::position(ZDATA, self.offset).binary(self.crc32);
+                let data = &self.file.data[self.offset..self.offset + len];
+                subpacket(data, ZCRCW, self.crc32, &mut out);
+                self.offset += len;
+                self.awaiting_ack = true;
+                io.progress(&self.file.name, self.offset as u64, Some(size as u64));
+            }
+        }
+        if !out.is_empty() {
+            io.send(out);
+            self.deadline = now + TIMEOUT;
+        }
+    }
+
+    fn on_header(&mut self, header: Header, now: Instant, io: &mut TransferIo) {
+        self.retries = 0;
+        match (self.state, header.kind) {
+            (SendState::Init, ZRINIT) => {
+                let flags = header.zf0();
+                let buffer = u16::from_le_bytes([header.data[0], header.data[1]]);
+                self.streaming = flags & (CANFDX | CANOVIO) == CANFDX | CANOVIO && buffer == 0;
+                self.crc32 = flags & CANFC32 != 0;
+                self.offer_file(now, io);
+            }
+            (SendState::Eof, ZRINIT) | (SendState::FileOffer, ZSKIP) => self.offer_file(now, io),
+            (SendState::FileOffer | SendState::Sending | SendState::Eof, ZRPOS) => {
+                self.seek(header.offset(), now, io)
+            }
+            (SendState::Sending, ZACK) => {
+                let offset = header.offset();
+                if self.streaming {
+                    self.acked = self.acked.max(offset.min(self.offset));
+                } else if offset == self.offset {
+                    self.acked = offset;
+                    self.awaiting_ack = false;
+                }
+                self.deadline = now + TIMEOUT;
+                self.pump(now, io);
+            }
+            (SendState::FileOffer, ZNAK) => self.send_offer(io),
+            (SendState::Fin, ZFIN) => {
+                io.send(&b"OO"[..]);
+                io.finish(Vec::new());
+            }
+            (_, ZCAN | ZABORT | ZFERR) => io.fail("aborted by the receiver"),
+            _ => {}
+        }
+    }
+}
+
+impl Transfer for ZmodemSender {
+    fn start(&mut self, now: Instant, io: &mut TransferIo) {
+        let mut init = b"rz\r".to_vec();
+        init.extend_from_slice(&Header::flags(ZRQINIT, 0).hex());
+        io.send(init);
+        self.wait(SendState::Init, now);
+    }
+
+    fn receive(&mut self, data: &[u8], now: Instant, io: &mut TransferIo) {
+        if cancelled(&mut self.cans, data) {
+            io.fail("cancelled by the receiver");
+            return;
+        }
+        self.buf.extend_from_slice(data);
+        loop {
+            match parse_header(&self.buf) {
+                Parsed::Done((header, _), n) => {
+                    self.buf.advance(n);
+                    self.on_header(header, now, io);
+                }
+                Parsed::Bad(n) => self.buf.advance(n),
+                Parsed::More => return,
+            }
+            if io.outcome.is_some() {
+                return;
+            }
+        }
+    }
+
+    fn update(&mut self, now: Instant, io: &mut TransferIo) {
+        if now < self.deadline {
+            return;
+        }
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            io.send(CANCEL);
+            io.fail("too many retries");
+            return;
+        }
+        self.deadline = now + TIMEOUT;
+        match self.state {
+            SendState::Init => io.send(Header::flags(ZRQINIT, 0).hex()),
+            SendState::FileOffer => self.send_offer(io),
+            // the receiver answers with ZRPOS if it has more than was acknowledged
+            SendState::Sending => self.seek(self.acked, now, io),
+            SendState::Eof => {
+                io.send(Header::position(ZEOF, self.file.data.len()).binary(self.crc32))
+            }
+            SendState::Fin => io.send(Header::flags(ZFIN, 0).hex()),
+        }
+    }
+
+    fn cancel(&mut self, _now: Instant, io: &mut TransferIo) {
+        io.send(CANCEL);
+        io.fail("cancelled");
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecvState {
+    Header,
+    /// the subpacket after ZSINIT
+    Attention,
+    FileInfo,
+    Data,
+    /// for the sender's "OO" after ZFIN
+    Finishing,
+}
+
+/// A file being received
+struct Incoming {
+    name: String,
+    size: Option<u64>,
+    data: BytesMut,
+}
+
+pub(crate) struct ZmodemReceiver {
+    buf: BytesMut,
+    cans: usize,
+    state: RecvState,
+    /// whether the subpackets that follow use CRC-32
+    crc32: bool,
+    /// files received before, to resume
+    resume: Vec<TransferFile>,
+    files: Vec<TransferFile>,
+    file: Option<Incoming>,
+    deadline: Instant,
+    retries: u32,
+}
+
+impl ZmodemReceiver {
+    /// Files in `resume` that are offered again continue from where they end
+    pub(crate) fn new(resume: Vec<TransferFile>) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            cans: 0,
+            state: RecvState::Header,
+            crc32: false,
+            resume,
+            files: Vec::new(),
+            file: None,
+            deadline: Instant::now(),
+            retries: 0,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.file.as_ref().map_or(0, |file| file.data.len())
+    }
+
+    fn init(&self, io: &mut TransferIo) {
+        io.send(Header::flags(ZRINIT, CANFDX | CANOVIO | CANFC32).hex());
+    }
+
+    /// Ask the sender to continue from what was received intact
+    fn reposition(&mut self, io: &mut TransferIo) {
+        io.send(Header::position(ZRPOS, self.offset()).hex());
+        self.state = RecvState::Header;
+    }
+
+    fn on_header(&mut self, header: Header, crc32: bool, now: Instant, io: &mut TransferIo) {
+        match header.kind {
+            ZRQINIT => self.init(io),
+            ZSINIT => self.state = RecvState::Attention,
+            ZFILE => self.state = RecvState::FileInfo,
+            ZDATA if self.file.is_some() => {
+                if header.offset() == self.offset() {
+                    self.state = RecvState::Data;
+                } else {
+                    self.reposition(io);
+                }
+            }
+            ZEOF if self.file.is_some() && header.offset() == self.offset() => {
+                let file = self.file.take().expect("checked above");
+                self.files.push(TransferFile {
+                    name: file.name,
+                    data: file.data.freeze(),
+                });
+                self.init(io);
+            }
+            ZFIN => {
+                io.send(Header::flags(ZFIN, 0).hex());
+                self.state = RecvState::Finishing;
+                self.deadline = now + FINISH_TIMEOUT;
+                return;
+            }
+            ZCAN | ZABORT => {
+                io.fail("aborted by the sender");
+                return;
+            }
+            _ => return,
+        }
+        self.crc32 = crc32;
+        self.retries = 0;
+        self.deadline = now + TIMEOUT;
+    }
+
+    fn on_file_info(&mut self, info: &[u8], io: &mut TransferIo) {
+        let mut fields = info.split(|&b| b == 0);
+        let name = String::from_utf8_lossy(fields.next().unwrap_or_default()).into_owned();
+        let size = fields
+            .next()
+            .and_then(|info| info.split(|&b| b == b' ').next())
+            .and_then(|size| std::str::from_utf8(size).ok()?.parse::<u64>().ok());
+        let earlier = self
+            .resume
+            .iter()
+            .position(|file| file.name == name)
+            .map(|i| self.resume.remove(i).data);
+        self.state = RecvState::Header;
+        match (earlier, size) {
+            (Some(data), Some(size)) if data.len() as u64 >= size => {
+                self.files.push(TransferFile { name, data });
+                io.send(Header::position(ZSKIP, 0).hex());
+            }
+            (earlier, size) => {
+                let data = BytesMut::from(&earlier.unwrap_or_default()[..]);
+                if !data.is_empty() {
+                    io.progress(&name, data.len() as u64, size);
+                }
+                self.file = Some(Incoming { name, size, data });
+                self.reposition(io);
+            }
+        }
+    }
+
+    fn on_data(&mut self, data: &[u8], end: u8, io: &mut TransferIo) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        file.data.extend_from_slice(data);
+        io.progress(&file.name, file.data.len() as u64, file.size);
+        if matches!(end, ZCRCQ | ZCRCW) {
+            io.send(Header::position(ZACK, file.data.len()).hex());
+        }
+        if matches!(end, ZCRCE | ZCRCW) {
+            self.state = RecvState::Header;
+        }
+    }
+
+    fn finish(&mut self, io: &mut TransferIo) {
+        let mut files = std::mem::take(&mut self.files);
+        // files resumed that the sender didn't offer again
+        files.append(&mut self.resume);
+        io.finish(files);
+    }
+}
+
+impl Transfer for ZmodemReceiver {
+    fn start(&mut self, now: Instant, io: &mut TransferIo) {
+        self.init(io);
+        self.deadline = now + TIMEOUT;
+    }
+
+    fn receive(&mut self, data: &[u8], now: Instant, io: &mut TransferIo) {
+        if cancelled(&mut self.cans, data) {
+            io.fail("cancelled by the sender");
+            return;
+        }
+        self.buf.extend_from_slice(data);
+        while io.outcome.is_none() {
+            match self.state {
+                RecvState::Header => match parse_header(&self.buf) {
+                    Parsed::Done((header, crc32), n) => {
+                        self.buf.advance(n);
+                        self.on_header(header, crc32, now, io);
+                    }
+                    Parsed::Bad(n) => self.buf.advance(n),
+                    Parsed::More => return,
+                },
+                RecvState::Finishing => {
+                    if self.buf.windows(2).any(|pair| pair == b"OO") {
+                        self.finish(io);
+                    }
+                    return;
+                }
+                state => match parse_subpacket(&self.buf, self.crc32) {
+                    Parsed::Done((data, end), n) => {
+                        self.buf.advance(n);
+                        self.retries = 0;
+                        self.deadline = now + TIMEOUT;
+                        match state {
+                            RecvState::Attention => {
+                                io.send(Header::position(ZACK, 0).hex());
+                                self.state = RecvState::Header;
+                            }
+                            RecvState::FileInfo => self.on_file_info(&data, io),
+                            _ => self.on_data(&data, end, io),
+                        }
+                    }
+                    Parsed::Bad(n) => {
+                        self.buf.advance(n);
+                        if state == RecvState::Data {
+                            self.reposition(io);
+                        } else {
+                            io.send(Header::flags(ZNAK, 0).hex());
+                            self.state = RecvState::Header;
+                        }
+                    }
+                    Parsed::More => return,
+                },
+            }
+        }
+    }
+
+    fn update(&mut self, now: Instant, io: &mut TransferIo) {
+        if now < self.deadline {
+            return;
+        }
+        if self.state == RecvState::Finishing {
+            self.finish(io);
+            return;
+        }
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            io.send(CANCEL);
+            io.fail("too many retries");
+            return;
+        }
+        self.deadline = now + TIMEOUT;
+        self.buf.clear();
+        if self.file.is_some() {
+            self.reposition(io);
+        } else {
+            self.state = RecvState::Header;
+            self.init(io);
+        }
+    }
+
+    fn cancel(&mut self, _now: Instant, io: &mut TransferIo) {
+        io.send(CANCEL);
+        io.fail("cancelled");
+    }
+
+    fn partial(&mut self) -> Option<Vec<TransferFile>> {
+        let mut files = std::mem::take(&mut self.files);
+        if let Some(file) = self.file.take() {
+            files.push(TransferFile {
+                name: file.name,
+                data: file.data.freeze(),
+            });
+        }
+        Some(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::Progress;
+
+    /// Run a sender and a receiver against each other, `corrupt` damaging what the sender
+    /// writes, until `stop` says to cut the line. Returns the receiver's outcome and progress
+    fn transfer(
+        files: Vec<TransferFile>,
+        receiver: &mut ZmodemReceiver,
+        mut corrupt: impl FnMut(usize, &mut Vec<u8>),
+        mut stop: impl FnMut(usize) -> bool,
+    ) -> (Option<Result<Vec<TransferFile>, String>>, Vec<Progress>) {
+        let mut now = Instant::now();
+        let mut sender = ZmodemSender::new(files);
+        let (mut to_receiver, mut to_sender) = (TransferIo::default(), TransferIo::default());
+        sender.start(now, &mut to_receiver);
+        receiver.start(now, &mut to_sender);
+        let mut writes = 0;
+        for _ in 0..1000 {
+            if to_receiver.out.is_empty() && to_sender.out.is_empty() {
+                now += TIMEOUT;
+                if to_receiver.outcome.is_none() {
+                    sender.update(now, &mut to_receiver);
+                }
+                if to_sender.outcome.is_none() {
+                    receiver.update(now, &mut to_sender);
+                }
+            }
+            for bytes in std::mem::take(&mut to_sender.out) {
+                if to_receiver.outcome.is_none() {
+                    sender.receive(&bytes, now, &mut to_receiver);
+                }
+            }
+            for bytes in std::mem::take(&mut to_receiver.out) {
+                if stop(writes) {
+                    return (None, to_sender.progress);
+                }
+                let mut bytes = bytes.to_vec();
+                corrupt(writes, &mut bytes);
+                writes += 1;
+                if to_sender.outcome.is_none() {
+                    receiver.receive(&bytes, now, &mut to_sender);
+                }
+            }
+            if let (Some(sent), Some(received)) = (&to_receiver.outcome, &to_sender.outcome) {
+                assert_eq!(sent, &Ok(Vec::new()));
+                return (Some(received.clone()), to_sender.progress);
+            }
+        }
+        panic!("the transfer did not end");
+    }
+
+    fn log(len: usize) -> Bytes {
+        (0..len)
+            .map(|i| (i * 7 % 256) as u8)
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    #[test]
+    fn streams_with_errors() {
+        let files = vec![
+            TransferFile::new("dmesg.log", log(50_000)),
+            TransferFile::new("empty", Bytes::new()),
+            TransferFile::new("boot.log", log(3000)),
+        ];
+        let mut receiver = ZmodemReceiver::new(Vec::new());
+        // damage a data subpacket, then the end of a later write
+        let (received, progress) = transfer(
+            files.clone(),
+            &mut receiver,
+            |i, bytes| match i {
+                3 => bytes[500] ^= 0x04,
+                6 => *bytes.last_mut().unwrap() ^= 1,
+                _ => {}
+            },
+            |_| false,
+        );
+        assert_eq!(received, Some(Ok(files)));
+        assert_eq!(
+            progress.last(),
+            Some(&("boot.log".to_string(), 3000, Some(3000)))
+        );
+    }
+
+    #[test]
+    fn resumes_after_interruption() {
+        let files = vec![
+            TransferFile::new("kern.log", log(2000)),
+            TransferFile::new("syslog", log(40_000)),
+        ];
+        let mut receiver = ZmodemReceiver::new(Vec::new());
+        let (received, _) = transfer(files.clone(), &mut receiver, |_, _| {}, |i| i == 7);
+        assert_eq!(received, None);
+        let partial = receiver.partial().unwrap();
+        assert_eq!(partial.len(), 2);
+        assert_eq!(partial[0], files[0]);
+        let resumed_from = partial[1].data.len();
+        assert!(resumed_from > 0 && resumed_from < 40_000);
+
+        let mut receiver = ZmodemReceiver::new(partial);
+        let (received, progress) = transfer(files.clone(), &mut receiver, |_, _| {}, |_| false);
+        assert_eq!(received, Some(Ok(files)));
+        // kern.log was skipped, syslog picked up where it stopped
+        assert!(progress.iter().all(|(file, _, _)| file == "syslog"));
+        assert_eq!(progress[0].1, resumed_from as u64);
+    }
+
+    #[test]
+    fn escaping_round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        for crc32 in [false, true] {
+            let mut out = Header::position(ZDATA, 0x0102_1113).binary(crc32);
+            subpacket(&data, ZCRCW, crc32, &mut out);
+            assert!(!out[2..].contains(&XON));
+            let Parsed::Done((header, is_crc32), n) = parse_header(&out) else {
+                panic!("header not parsed");
+            };
+            assert_eq!(
+                (header.kind, header.offset(), is_crc32),
+                (ZDATA, 0x0102_1113, crc32)
+            );
+            let Parsed::Done((parsed, end), _) = parse_subpacket(&out[n..], crc32) else {
+                panic!("subpacket not parsed");
+            };
+            assert_eq!((parsed, end), (data.clone(), ZCRCW));
+        }
+        let hex = Header::position(ZRPOS, 1234).hex();
+        assert!(matches!(
+            parse_header(&hex),
+            Parsed::Done((Header { kind: ZRPOS, .. }, false), _)
+        ));
+    }
+}
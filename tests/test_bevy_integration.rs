@@ -1,36 +1,97 @@
 //! A single integration test that we can send and receive serial data through the bevy plugin.
-// Right now, this test can only run where we can reliably create files as placeholder ports, since
-// we need a fixed targets of 2 ports for bidirectional communication. Many CI systems don't let
-// you just connect to any random COM port.
+// Right now, this test can only run where we can create linked pseudo-terminals as placeholder
+// ports, since we need a fixed targets of 2 ports for bidirectional communication. Many CI systems
+// don't let you just connect to any random COM port.
 #[cfg(target_os = "linux")]
 #[test]
 fn test_receive_bytes_send_through_serial_port_from_bevy_app() -> Result<(), String> {
-    use bevy::prelude::{App, MinimalPlugins, PostStartup, Startup, Update};
+    use bevy::{
+        app::ScheduleRunnerPlugin,
+        prelude::{App, MinimalPlugins, PluginGroup, PostStartup, Startup, Update},
+    };
     use bevy_serialport::SerialPortPlugin;
     use internal_nonsense::{run_in_background_with_deadline, with_local_serial_connected_ports};
     use receive_or_panic_bevy_app_impl::{
         poll_serial_messages_10_times_exit_app_if_found_else_panic, send_test_data, setup_receiver,
         setup_sender, TestPTTYPortNames,
     };
+    use std::time::Duration;
     // If there is a timeout, we probably aren't receiving the data and the app will hang.
-    run_in_background_with_deadline(std::time::Duration::from_millis(2000), || {
+    run_in_background_with_deadline(Duration::from_millis(2000), || {
         with_local_serial_connected_ports(|serial_port_name, serial_port_name2: String| {
             let mut app = App::new();
             // The app should only update for 10 ticks before exiting gracefully or panicing.
             // Typically calling .run() will never return, which is bad for a test and
             // CI.
-            app.add_plugins((MinimalPlugins, SerialPortPlugin))
-                .insert_resource(TestPTTYPortNames {
-                    sender: String::from(serial_port_name),
-                    receiver: String::from(serial_port_name2),
-                })
-                .add_systems(Startup, (setup_receiver, setup_sender))
-                .add_systems(PostStartup, send_test_data)
+            // Pace the ticks so the data has time to cross the ptys within the 10 polls.
+            app.add_plugins((
+                MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_millis(10))),
+                SerialPortPlugin::default(),
+            ))
+            .insert_resource(TestPTTYPortNames {
+                sender: serial_port_name,
+                receiver: serial_port_name2,
+            })
+            .add_systems(Startup, (setup_receiver, setup_sender))
+            .add_systems(PostStartup, send_test_data)
+            .add_systems(
+                Update,
+                poll_serial_messages_10_times_exit_app_if_found_else_panic,
+            );
+            app.run(); // This should shut down if we received a serial signal.
+        })
+    })
+}
+
+/// The last message queued before the app exits is still written to the port.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_queued_message_written_on_app_exit() -> Result<(), String> {
+    use bevy::{
+        app::AppExit,
+        prelude::{App, EventWriter, MinimalPlugins, Res, ResMut, Startup, Update},
+    };
+    use bevy_serialport::{SerialPortPlugin, SerialPortRuntime, SerialResource};
+    use bytes::Bytes;
+    use internal_nonsense::{run_in_background_with_deadline, with_local_serial_connected_ports};
+    use std::{io::Read, time::Duration};
+
+    run_in_background_with_deadline(Duration::from_millis(4000), || {
+        with_local_serial_connected_ports(|sender, receiver: String| {
+            let mut device = serialport::new(&receiver, 115_200)
+                .timeout(Duration::from_millis(1000))
+                .open()
+                .expect("Error opening the receiving port");
+
+            let mut app = App::new();
+            app.add_plugins((MinimalPlugins, SerialPortPlugin::default()))
+                .add_systems(
+                    Startup,
+                    move |mut serial_res: ResMut<SerialResource>, rt: Res<SerialPortRuntime>| {
+                        serial_res
+                            .open(rt.clone(), &sender, 115_200)
+                            .expect("Error opening the sending port");
+                    },
+                )
                 .add_systems(
                     Update,
-                    poll_serial_messages_10_times_exit_app_if_found_else_panic,
+                    |mut serial_res: ResMut<SerialResource>, mut exit: EventWriter<AppExit>| {
+                        let port = serial_res.ports.keys().next().unwrap().clone();
+                        serial_res
+                            .send_message(&port, Bytes::from_static(b"motors off"))
+                            .expect("Error sending the last message");
+                        exit.send(AppExit::Success);
+                    },
                 );
-            app.run(); // This should shut down if we received a serial signal.
+            app.run();
+            // nothing of the plugin is left running to write late data
+            drop(app);
+
+            let mut received = [0; 10];
+            device
+                .read_exact(&mut received)
+                .expect("The last message never arrived");
+            assert_eq!(&received, b"motors off");
         })
     })
 }
@@ -79,7 +140,9 @@ mod receive_or_panic_bevy_app_impl {
         mut serial_res: ResMut<SerialResource>,
         port_name: Res<TestPTTYPortNames>,
     ) {
-        serial_res.send_message(&port_name.sender, Bytes::from(&b"123457"[..]))
+        serial_res
+            .send_message(&port_name.sender, Bytes::from(&b"123457"[..]))
+            .expect("Error sending test data");
     }
     pub(super) fn setup_receiver(
         ports: Res<TestPTTYPortNames>,
@@ -88,11 +151,13 @@ mod receive_or_panic_bevy_app_impl {
     ) {
         serial_res
             .open(rt.clone(), &ports.receiver, 115_200)
-            .expect(&format!(
-                "Error opening serial port. {:?}. Available ports: {:?}",
-                &ports,
-                &serial_res.ports.keys()
-            ));
+            .unwrap_or_else(|_| {
+                panic!(
+                    "Error opening serial port. {:?}. Available ports: {:?}",
+                    &ports,
+                    &serial_res.ports.keys()
+                )
+            });
     }
     pub(super) fn setup_sender(
         ports: Res<TestPTTYPortNames>,
@@ -107,21 +172,24 @@ mod receive_or_panic_bevy_app_impl {
             parity: Parity::None,
             stop_bits: StopBits::One,
             timeout: Default::default(),
+            ..Default::default()
         };
         serial_res
             .open_with_setting(rt.clone(), serial_setting)
-            .expect(&format!(
-                "Error opening serial port. {:?}. Available ports: {:?}",
-                &ports,
-                &serial_res.ports.keys()
-            ));
+            .unwrap_or_else(|_| {
+                panic!(
+                    "Error opening serial port. {:?}. Available ports: {:?}",
+                    &ports,
+                    &serial_res.ports.keys()
+                )
+            });
     }
 }
 
 #[cfg(target_os = "linux")] // Whatever linux-specific hacks I need to get tests to work
 mod internal_nonsense {
-    use std::{panic::UnwindSafe, sync::mpsc, thread, time::Duration};
-    use tempdir::TempDir;
+    use bevy_serialport::VirtualPortPair;
+    use std::{sync::mpsc, thread, time::Duration};
     pub(super) type TimeoutError = String;
     pub(super) type TimeoutResult<T> = Result<T, TimeoutError>;
     // https://github.com/rust-lang/rfcs/issues/2798#issuecomment-552949300
@@ -148,72 +216,19 @@ mod internal_nonsense {
         {
             Ok(_) => match handle.join() {
                 Ok(h) => h,
-                Err(_) => Err(format!("Uncaught exception")),
+                Err(_) => Err("Uncaught exception".to_string()),
             },
             Err(e) => Err(e),
         }
     }
 
     /// Execute a function that accepts names of 2 bidirectional ptys (serial data) (software
-    /// approximation of hardware). This connection is destroyed even if the invoked function
-    /// panics, to prevent a zombie process.
-    pub(super) fn with_local_serial_connected_ports<F, T>(test_impl: F)
+    /// approximation of hardware). The connection is cut once the function returns.
+    pub(super) fn with_local_serial_connected_ports<F, T>(test_impl: F) -> T
     where
-        T: Send + 'static,
-        F: FnOnce(String, String) -> T + UnwindSafe,
-        F: Send + 'static,
+        F: FnOnce(String, String) -> T,
     {
-        let (port1, port2, mut child) = ephemeral_serially_linked_file_ptys();
-        // Analog of try-catch/python context manager to close the "mocked" pty to prevent a zombie
-        // process
-        // For some reason, this doesn't work when the test fn is run in another thread. idk.
-        let maybe_res = std::panic::catch_unwind(move || test_impl(port1, port2));
-        child.kill().expect("Failed to kill the socat process");
-        maybe_res.expect("Thread panicked");
-    }
-    /// Returns 2 file names that are linked ptys. Very linux specific
-    /// (https://linux.die.net/man/1/socat).
-    fn ephemeral_serially_linked_file_ptys() -> (String, String, std::process::Child) {
-        let tmp_path = TempDir::new("bevy_serialport_test")
-            .expect("Could not find a temporary directory for faked serial port communication")
-            .into_path();
-        let fname1 = tmp_path.join("file1");
-        let fname2 = tmp_path.join("file2");
-        std::fs::write(&fname1, []).expect("Failed to write an empty temp file");
-        std::fs::write(&fname2, []).expect("Failed to write an empty temp file");
-        let abs_fname1 = fname1
-            .canonicalize()
-            .expect("Could not find file for serial communication")
-            .into_os_string()
-            .into_string()
-            .unwrap();
-        let abs_fname2 = fname2
-            .canonicalize()
-            .expect("Could not find file for serial communication")
-            .into_os_string()
-            .into_string()
-            .unwrap();
-
-        // https://stackoverflow.com/questions/52187/virtual-serial-port-for-linux
-        // socat -d -d pty,raw,echo=0,link=file1 pty,raw,echo=0,link=file2
-        let mut socat_child = std::process::Command::new("socat")
-            .args([
-                "-d",
-                "-d",
-                &format!("pty,raw,echo=0,link={abs_fname1}"),
-                &format!("pty,raw,echo=0,link={abs_fname2}"),
-            ])
-            .spawn()
-            .expect("Failed to start a fake serial port");
-
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-        match socat_child
-            .try_wait()
-            .expect("Failed to run the socat command to emulate serial comms")
-        {
-            None => {}
-            Some(e) => panic!("socat process should not have exited. {:?}", e),
-        }
-        (abs_fname1, abs_fname2, socat_child)
+        let pair = VirtualPortPair::new().expect("Failed to create a virtual null-modem pair");
+        test_impl(pair.a().to_string(), pair.b().to_string())
     }
 }